@@ -12,28 +12,110 @@ typedef enum SupportedFileTypes {
   Unkn,
 } SupportedFileTypes;
 
+typedef struct CCSWarning {
+  uint32_t code;
+  const char *message;
+} CCSWarning;
+
 typedef struct CCSResult {
   bool success;
   uint32_t code;
   const char *error_message;
+  struct CCSWarning *warnings;
+  uintptr_t warnings_length;
 } CCSResult;
 
+typedef struct CCSJpegScan {
+  uint32_t components[4];
+  uint32_t component_count;
+  uint32_t spectral_start;
+  uint32_t spectral_end;
+  uint32_t approx_high;
+  uint32_t approx_low;
+} CCSJpegScan;
+
 typedef struct CCSParameters {
   bool keep_metadata;
+  bool convert_to_srgb;
+  bool embed_srgb_profile;
+  bool keep_dpi;
+  uint32_t dpi_x;
+  uint32_t dpi_y;
+  bool to_grayscale;
+  bool detect_grayscale;
+  uint32_t grayscale_tolerance;
   uint32_t jpeg_quality;
+  uint32_t jpeg_chroma_quality;
   uint32_t jpeg_chroma_subsampling;
   bool jpeg_progressive;
+  bool jpeg_optimize_scans;
+  uint32_t jpeg_dc_scan_mode;
+  const struct CCSJpegScan *jpeg_scan_script;
+  uintptr_t jpeg_scan_script_length;
   bool jpeg_optimize;
   bool jpeg_preserve_icc;
+  bool jpeg_cmyk_to_rgb;
+  uint32_t jpeg_source_quality_policy;
+  bool jpeg_recover_corrupted;
+  bool jpeg_keep_gain_map;
+  bool jpeg_requantize;
+  uint32_t jpeg_profile;
+  uint32_t jpeg_quant_table;
+  bool jpeg_trellis;
+  uint32_t jpeg_tune;
+  bool jpeg_overshoot_deringing;
+  uint32_t jpeg_smoothing;
+  uint32_t jpeg_restart_interval;
+  bool jpeg_arithmetic_coding;
   uint32_t png_quality;
   uint32_t png_optimization_level;
   bool png_force_zopfli;
   bool png_optimize;
+  uint32_t png_max_colors;
+  float png_dithering_level;
+  uint32_t png_speed;
+  uint32_t png_min_quality;
+  uint32_t png_posterization;
+  bool png_keep_16_bit;
+  bool png_dither_16_bit;
+  const uint32_t *png_filters;
+  uintptr_t png_filters_length;
+  uint32_t png_interlace;
+  bool png_optimize_alpha;
+  bool png_bit_depth_reduction;
+  bool png_color_type_reduction;
+  bool png_palette_reduction;
+  const uint8_t (*png_strip_chunks)[4];
+  uintptr_t png_strip_chunks_length;
+  const uint8_t (*png_keep_chunks)[4];
+  uintptr_t png_keep_chunks_length;
+  uint32_t png_deflater;
+  uint32_t png_deflate_effort;
+  uint32_t png_timeout;
   uint32_t gif_quality;
   uint32_t webp_quality;
   bool webp_lossless;
+  uint32_t webp_method;
+  uint32_t webp_preset;
+  int32_t webp_sns_strength;
+  int32_t webp_filter_strength;
+  int32_t webp_filter_sharpness;
+  uint32_t webp_segments;
+  uint32_t webp_pass;
+  uint32_t webp_alpha_quality;
+  uint32_t webp_alpha_filtering;
+  uint32_t webp_near_lossless;
+  bool webp_exact;
+  bool webp_use_sharp_yuv;
+  float webp_target_psnr;
+  uint32_t webp_target_size;
+  bool webp_allow_mixed;
+  int32_t webp_kmin;
+  int32_t webp_kmax;
   uint32_t tiff_compression;
   uint32_t tiff_deflate_level;
+  uint32_t tiff_first_page;
+  uint32_t tiff_last_page;
   uint32_t width;
   uint32_t height;
 } CCSParameters;
@@ -65,4 +147,6 @@ struct CCSResult c_convert(const char *input_path,
 
 void c_free_byte_array(struct CByteArray byte_array);
 
+void c_free_warnings(struct CCSWarning *warnings, uintptr_t length);
+
 void c_free_string(char *ptr);
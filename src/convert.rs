@@ -1,20 +1,65 @@
 use std::io::Cursor;
 
 use bytes::Bytes;
-use image::{ColorType, DynamicImage, ImageFormat, ImageReader};
+use image::{ColorType, DynamicImage, ImageDecoder, ImageFormat, ImageReader};
 use img_parts::{DynImage, ImageEXIF, ImageICC};
 
+use crate::density::{generated_exif_dpi, output_dpi};
 use crate::error::CaesiumError;
+use crate::icc::{convert_image_to_srgb, converted_profile, is_cmyk_profile, needs_srgb_conversion};
 use crate::utils::{get_filetype_from_memory, get_jpeg_orientation};
-use crate::{compress_in_memory, CSParameters, SupportedFileTypes};
+use crate::{
+    compress_in_memory_with_warnings, compress_to_size_in_memory_with_warnings, CSParameters, SupportedFileTypes,
+};
 
 pub fn convert_in_memory(
     in_file: Vec<u8>,
     format: SupportedFileTypes,
     parameters: &CSParameters,
-) -> Result<Vec<u8>, CaesiumError> {
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    convert_with(in_file, format, parameters, |image, parameters, _| {
+        compress_in_memory_with_warnings(image, parameters)
+    })
+}
+
+/// Converts like `convert_in_memory`, compressing the converted image up to `max_output_size` with the size of the
+/// metadata copied from the source taken off.
+pub fn convert_to_size_in_memory(
+    in_file: Vec<u8>,
+    format: SupportedFileTypes,
+    parameters: &CSParameters,
+    max_output_size: usize,
+    return_smallest: bool,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    convert_with(in_file, format, parameters, |image, parameters, metadata_size| {
+        compress_to_size_in_memory_with_warnings(
+            image,
+            &mut parameters.clone(),
+            max_output_size.saturating_sub(metadata_size),
+            return_smallest,
+        )
+    })
+}
+
+/// Decodes the source and encodes it in `format`, compressing the result with `compress` before copying back the
+/// metadata, whose size is passed to it.
+fn convert_with(
+    in_file: Vec<u8>,
+    format: SupportedFileTypes,
+    parameters: &CSParameters,
+    compress: impl FnOnce(Vec<u8>, &CSParameters, usize) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError>,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
     let mut iccp = None;
     let mut exif = None;
+    //The intermediate encoding below would lose the source resolution
+    let dpi = match format {
+        SupportedFileTypes::WebP => generated_exif_dpi(&in_file, parameters),
+        _ => output_dpi(&in_file, parameters),
+    };
+    let parameters = &CSParameters {
+        dpi,
+        ..parameters.clone()
+    };
 
     let output_format = map_image_format(format)?;
     let original_file_type = get_filetype_from_memory(&in_file);
@@ -25,19 +70,30 @@ pub fn convert_in_memory(
             code: 10407,
         });
     }
+    let (in_file, mut warnings) = recover_corrupted(in_file, original_file_type, parameters)?;
 
     let i = in_file.as_slice();
-    let mut original_image = ImageReader::new(Cursor::new(i))
+    let mut decoder = ImageReader::new(Cursor::new(i))
         .with_guessed_format()
         .map_err(|e| CaesiumError {
             message: e.to_string(),
             code: 10402,
         })?
-        .decode()
+        .into_decoder()
         .map_err(|e| CaesiumError {
             message: e.to_string(),
             code: 10403,
         })?;
+    let source_icc = decoder.icc_profile().ok().flatten();
+    let mut original_image = DynamicImage::from_decoder(decoder).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10403,
+    })?;
+    let cmyk_image = decode_cmyk_to_rgb(i, original_file_type, parameters)?;
+    let cmyk_converted = cmyk_image.is_some();
+    if let Some(image) = cmyk_image {
+        original_image = image;
+    }
 
     if parameters.keep_metadata {
         if original_file_type == SupportedFileTypes::Jpeg {
@@ -62,6 +118,20 @@ pub fn convert_in_memory(
             .map_or((None, None), |dimg| (dimg.icc_profile(), dimg.exif()));
     }
 
+    //The output is never CMYK, so a CMYK profile cannot be carried over
+    if source_icc.as_deref().is_some_and(is_cmyk_profile) {
+        iccp = if cmyk_converted {
+            converted_profile(parameters)
+        } else {
+            None
+        };
+    }
+
+    if let Some(icc) = source_icc.filter(|i| parameters.convert_to_srgb && needs_srgb_conversion(Some(i))) {
+        original_image = convert_image_to_srgb(original_image, &icc)?;
+        iccp = converted_profile(parameters);
+    }
+
     if format == SupportedFileTypes::Jpeg {
         original_image = match original_image.color() {
             ColorType::Rgba8 => DynamicImage::from(original_image.to_rgb8()),
@@ -79,12 +149,15 @@ pub fn convert_in_memory(
             code: 10404,
         })?;
 
-    let compressed_converted_image = compress_in_memory(output_image, parameters).map_err(|e| CaesiumError {
-        message: e.to_string(),
-        code: 10405,
-    })?;
+    let metadata_size = iccp.as_ref().map_or(0, Bytes::len) + exif.as_ref().map_or(0, Bytes::len);
+    let (compressed_converted_image, compression_warnings) = compress(output_image, parameters, metadata_size)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10405,
+        })?;
+    warnings.extend(compression_warnings);
 
-    if parameters.keep_metadata {
+    if iccp.is_some() || exif.is_some() {
         let dyn_image =
             DynImage::from_bytes(Bytes::from(compressed_converted_image.clone())).map_err(|e| CaesiumError {
                 message: e.to_string(),
@@ -107,15 +180,72 @@ pub fn convert_in_memory(
                         code: 10409,
                     })?;
 
-                Ok(output_image_with_metadata)
+                Ok((output_image_with_metadata, warnings))
             }
-            None => Ok(compressed_converted_image),
+            None => Ok((compressed_converted_image, warnings)),
         }
     } else {
-        Ok(compressed_converted_image)
+        Ok((compressed_converted_image, warnings))
     }
 }
 
+/// Drops the corrupted or missing parts of a JPEG with `jpeg.recover_corrupted`, which the decoder of the conversion
+/// cannot skip, returning the warnings about them. The lossless optimization used for it keeps the metadata.
+#[cfg(feature = "jpg")]
+fn recover_corrupted(
+    in_file: Vec<u8>,
+    file_type: SupportedFileTypes,
+    parameters: &CSParameters,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    if !parameters.jpeg.recover_corrupted || file_type != SupportedFileTypes::Jpeg {
+        return Ok((in_file, vec![]));
+    }
+
+    let mut lossless = CSParameters {
+        keep_metadata: true,
+        width: 0,
+        height: 0,
+        ..parameters.clone()
+    };
+    //Plain Huffman coding, which the decoder of the conversion always supports
+    lossless.jpeg.optimize = true;
+    lossless.jpeg.progressive = false;
+    lossless.jpeg.scan_script = None;
+    lossless.jpeg.arithmetic_coding = false;
+    crate::jpeg::compress_in_memory_with_warnings(&in_file, &lossless)
+}
+
+#[cfg(not(feature = "jpg"))]
+fn recover_corrupted(
+    in_file: Vec<u8>,
+    _file_type: SupportedFileTypes,
+    _parameters: &CSParameters,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    Ok((in_file, vec![]))
+}
+
+#[cfg(feature = "jpg")]
+fn decode_cmyk_to_rgb(
+    in_file: &[u8],
+    file_type: SupportedFileTypes,
+    parameters: &CSParameters,
+) -> Result<Option<DynamicImage>, CaesiumError> {
+    if parameters.jpeg.cmyk_to_rgb && file_type == SupportedFileTypes::Jpeg {
+        crate::jpeg::decode_cmyk_to_rgb(in_file)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(not(feature = "jpg"))]
+fn decode_cmyk_to_rgb(
+    _in_file: &[u8],
+    _file_type: SupportedFileTypes,
+    _parameters: &CSParameters,
+) -> Result<Option<DynamicImage>, CaesiumError> {
+    Ok(None)
+}
+
 fn map_image_format(format: SupportedFileTypes) -> Result<ImageFormat, CaesiumError> {
     let image_format = match format {
         SupportedFileTypes::Jpeg => ImageFormat::Jpeg,
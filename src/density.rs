@@ -0,0 +1,163 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+use img_parts::jpeg::Jpeg as PartsJpeg;
+use img_parts::png::Png as PartsPng;
+#[cfg(feature = "png")]
+use img_parts::png::PngChunk;
+
+#[cfg(feature = "png")]
+use crate::error::CaesiumError;
+use crate::parameters::Dpi;
+use crate::utils::get_filetype_from_memory;
+use crate::{CSParameters, SupportedFileTypes};
+
+const INCHES_PER_CM: f64 = 1.0 / 2.54;
+const INCHES_PER_METER: f64 = 1.0 / 0.0254;
+/// Resolution assumed by most software when none is stored.
+const DEFAULT_DPI: Dpi = Dpi { x: 72, y: 72 };
+
+/// Resolution to write in the output: the requested one, or the source one when it has to be kept.
+pub fn output_dpi(in_file: &[u8], parameters: &CSParameters) -> Option<Dpi> {
+    parameters
+        .dpi
+        .or_else(|| parameters.keep_dpi.then(|| read_dpi(in_file)).flatten())
+}
+
+/// Resolution to store in a generated EXIF, for formats without a field of their own: the requested one, or the source
+/// one when it has to be kept and differs from the default.
+pub fn generated_exif_dpi(in_file: &[u8], parameters: &CSParameters) -> Option<Dpi> {
+    output_dpi(in_file, parameters).filter(|dpi| parameters.dpi.is_some() || *dpi != DEFAULT_DPI)
+}
+
+/// Reads the resolution from the JFIF header of JPEGs and the pHYs chunk of PNGs, falling back to the EXIF data.
+pub fn read_dpi(in_file: &[u8]) -> Option<Dpi> {
+    let dpi = match get_filetype_from_memory(in_file) {
+        SupportedFileTypes::Jpeg => jfif_dpi(in_file),
+        SupportedFileTypes::Png => png_dpi(in_file),
+        _ => None,
+    };
+
+    dpi.or_else(|| exif_dpi(in_file))
+}
+
+fn jfif_dpi(in_file: &[u8]) -> Option<Dpi> {
+    let jpeg = PartsJpeg::from_bytes(Bytes::copy_from_slice(in_file)).ok()?;
+    let jfif = jpeg.segments().iter().find(|s| s.marker() == 0xE0)?.contents();
+    if jfif.len() < 12 || !jfif.starts_with(b"JFIF\0") {
+        return None;
+    }
+
+    let x = u16::from_be_bytes([jfif[8], jfif[9]]) as f64;
+    let y = u16::from_be_bytes([jfif[10], jfif[11]]) as f64;
+    match jfif[7] {
+        1 => to_dpi(x, y, 1.0),
+        2 => to_dpi(x, y, INCHES_PER_CM),
+        _ => None,
+    }
+}
+
+fn png_dpi(in_file: &[u8]) -> Option<Dpi> {
+    let png = PartsPng::from_bytes(Bytes::copy_from_slice(in_file)).ok()?;
+    let phys = png.chunk_by_type(*b"pHYs")?.contents();
+    if phys.len() < 9 || phys[8] != 1 {
+        return None;
+    }
+
+    let x = u32::from_be_bytes([phys[0], phys[1], phys[2], phys[3]]) as f64;
+    let y = u32::from_be_bytes([phys[4], phys[5], phys[6], phys[7]]) as f64;
+    to_dpi(x, y, INCHES_PER_METER)
+}
+
+fn exif_dpi(in_file: &[u8]) -> Option<Dpi> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(in_file))
+        .ok()?;
+    let resolution = |tag| match &exif.get_field(tag, exif::In::PRIMARY)?.value {
+        exif::Value::Rational(v) => v.first().map(|r| r.to_f64()),
+        _ => None,
+    };
+    let unit = exif
+        .get_field(exif::Tag::ResolutionUnit, exif::In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        .unwrap_or(2);
+
+    let (x, y) = (resolution(exif::Tag::XResolution)?, resolution(exif::Tag::YResolution)?);
+    match unit {
+        2 => to_dpi(x, y, 1.0),
+        3 => to_dpi(x, y, INCHES_PER_CM),
+        _ => None,
+    }
+}
+
+fn to_dpi(x: f64, y: f64, scale: f64) -> Option<Dpi> {
+    let (x, y) = ((x / scale).round(), (y / scale).round());
+    if x < 1.0 || y < 1.0 {
+        return None;
+    }
+
+    //Kept in the range accepted for the requested resolution, which conversions set from this one
+    Some(Dpi {
+        x: x.min(u16::MAX as f64) as u32,
+        y: y.min(u16::MAX as f64) as u32,
+    })
+}
+
+/// Replaces the pHYs chunk of a PNG with one for the given resolution.
+#[cfg(feature = "png")]
+pub fn set_png_dpi(png: Vec<u8>, dpi: Dpi) -> Result<Vec<u8>, CaesiumError> {
+    let mut png = PartsPng::from_bytes(Bytes::from(png)).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10800,
+    })?;
+
+    let mut phys = Vec::with_capacity(9);
+    phys.extend_from_slice(&pixels_per_meter(dpi.x).to_be_bytes());
+    phys.extend_from_slice(&pixels_per_meter(dpi.y).to_be_bytes());
+    phys.push(1);
+    png.remove_chunks_by_type(*b"pHYs");
+    //Must come before the image data, right after IHDR is always fine
+    png.chunks_mut().insert(1, PngChunk::new(*b"pHYs", Bytes::from(phys)));
+
+    let mut output = vec![];
+    png.encoder().write_to(&mut output).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10801,
+    })?;
+    Ok(output)
+}
+
+#[cfg(feature = "png")]
+fn pixels_per_meter(dpi: u32) -> u32 {
+    (dpi as f64 * INCHES_PER_METER).round() as u32
+}
+
+/// Minimal little endian EXIF data only holding the resolution, for formats without a dedicated field.
+#[cfg(feature = "webp")]
+pub fn resolution_exif(dpi: Dpi) -> Bytes {
+    const ENTRIES: u16 = 3;
+    let data_offset = 8 + 2 + ENTRIES as u32 * 12 + 4;
+
+    let mut exif = b"II*\0".to_vec();
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&ENTRIES.to_le_bytes());
+    for (tag, offset) in [(0x011Au16, data_offset), (0x011B, data_offset + 8)] {
+        //RATIONAL, one value stored at the offset
+        exif.extend_from_slice(&tag.to_le_bytes());
+        exif.extend_from_slice(&5u16.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+        exif.extend_from_slice(&offset.to_le_bytes());
+    }
+    //ResolutionUnit, SHORT inches stored inline
+    exif.extend_from_slice(&0x0128u16.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&2u32.to_le_bytes());
+    exif.extend_from_slice(&0u32.to_le_bytes());
+    for value in [dpi.x, dpi.y] {
+        exif.extend_from_slice(&value.to_le_bytes());
+        exif.extend_from_slice(&1u32.to_le_bytes());
+    }
+
+    Bytes::from(exif)
+}
@@ -2,6 +2,7 @@ use crate::error::CaesiumError;
 use crate::resize::compute_dimensions;
 use crate::CSParameters;
 use gifski::{progress, Settings};
+use image::RgbaImage;
 use std::fs;
 use std::fs::File;
 use std::io::Write;
@@ -33,6 +34,39 @@ pub fn compress_in_memory(in_file: &Vec<u8>, parameters: &CSParameters) -> Resul
     Ok(compressed)
 }
 
+/// Encodes canvases of the same size as the frames of an endless animation, each shown for `frame_duration_ms`.
+pub fn encode_frames(
+    canvases: Vec<RgbaImage>,
+    frame_duration_ms: u32,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    let (width, height) = canvases.first().map_or((0, 0), |c| c.dimensions());
+    let settings = Settings {
+        quality: parameters.gif.quality as u8,
+        width: Some(width),
+        height: Some(height),
+        ..Default::default()
+    };
+
+    let frames = canvases.into_iter().enumerate().map(move |(i, canvas)| {
+        let pixels = canvas
+            .pixels()
+            .map(|p| gif_dispose::RGBA8 {
+                r: p[0],
+                g: p[1],
+                b: p[2],
+                a: p[3],
+            })
+            .collect();
+        let timestamp_in_s = i as f64 * frame_duration_ms as f64 / 1000.0;
+        Ok((
+            imgref::Img::new(pixels, width as usize, height as usize),
+            timestamp_in_s,
+        ))
+    });
+    encode(settings, frames)
+}
+
 fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
     let mut decoder = gif::DecodeOptions::new();
     decoder.set_color_output(gif::ColorOutput::Indexed);
@@ -56,6 +90,63 @@ fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
     settings.width = Some(new_w);
     settings.height = Some(new_h);
 
+    let mut total_delay_in_s = 0.0;
+    let mut next_frame = move || -> Result<Option<Frame>, CaesiumError> {
+        let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20406,
+        })?
+        else {
+            return Ok(None);
+        };
+        screen.blit_frame(frame).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20407,
+        })?;
+        let mut pixels = screen.pixels_rgba().map_buf(|b| b.to_owned());
+
+        if new_w != old_w || new_h != old_h {
+            let mut raw_buf = Vec::with_capacity((old_w * old_h * 4) as usize);
+            for px in pixels.pixels() {
+                raw_buf.extend_from_slice(&[px.r, px.g, px.b, px.a]);
+            }
+
+            let img = image::RgbaImage::from_raw(old_w, old_h, raw_buf).unwrap();
+            let resized = image::imageops::resize(&img, new_w, new_h, image::imageops::FilterType::Lanczos3);
+
+            let mut new_buf = Vec::with_capacity((new_w * new_h) as usize);
+            for chunk in resized.chunks_exact(4) {
+                new_buf.push(gif_dispose::RGBA8 {
+                    r: chunk[0],
+                    g: chunk[1],
+                    b: chunk[2],
+                    a: chunk[3],
+                });
+            }
+
+            pixels = imgref::Img::new(new_buf, new_w as usize, new_h as usize);
+        }
+
+        let mut delay = frame.delay;
+        if delay <= 1 {
+            delay = 10;
+        }
+        let timestamp_in_s = total_delay_in_s;
+        total_delay_in_s += delay as f64 * 10.0 / 1000.0;
+        Ok(Some((pixels, timestamp_in_s)))
+    };
+
+    encode(settings, std::iter::from_fn(move || next_frame().transpose()))
+}
+
+/// Pixels of a frame and the time it is shown at, in seconds.
+type Frame = (imgref::ImgVec<gif_dispose::RGBA8>, f64);
+
+/// Runs gifski, adding the frames from another thread while the writer compresses them.
+fn encode(
+    settings: Settings,
+    frames: impl Iterator<Item = Result<Frame, CaesiumError>> + Send,
+) -> Result<Vec<u8>, CaesiumError> {
     let (collector, writer) = gifski::new(settings).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20405,
@@ -65,53 +156,14 @@ fn lossy(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
 
     std::thread::scope(|t| -> Result<(), CaesiumError> {
         let frames_thread = t.spawn(move || -> Result<(), CaesiumError> {
-            let mut i = 0;
-            let mut total_delay_in_s = 0.0;
-            while let Some(frame) = decoder.read_next_frame().map_err(|e| CaesiumError {
-                message: e.to_string(),
-                code: 20406,
-            })? {
-                screen.blit_frame(frame).map_err(|e| CaesiumError {
-                    message: e.to_string(),
-                    code: 20407,
-                })?;
-                let mut pixels = screen.pixels_rgba().map_buf(|b| b.to_owned());
-
-                if new_w != old_w || new_h != old_h {
-                    let mut raw_buf = Vec::with_capacity((old_w * old_h * 4) as usize);
-                    for px in pixels.pixels() {
-                        raw_buf.extend_from_slice(&[px.r, px.g, px.b, px.a]);
-                    }
-
-                    let img = image::RgbaImage::from_raw(old_w, old_h, raw_buf).unwrap();
-                    let resized = image::imageops::resize(&img, new_w, new_h, image::imageops::FilterType::Lanczos3);
-
-                    let mut new_buf = Vec::with_capacity((new_w * new_h) as usize);
-                    for chunk in resized.chunks_exact(4) {
-                        new_buf.push(gif_dispose::RGBA8 {
-                            r: chunk[0],
-                            g: chunk[1],
-                            b: chunk[2],
-                            a: chunk[3],
-                        });
-                    }
-
-                    pixels = imgref::Img::new(new_buf, new_w as usize, new_h as usize);
-                }
-
-                let mut delay = frame.delay;
-                if delay <= 1 {
-                    delay = 10;
-                }
-                let delay_in_s = delay as f64 * 10.0 / 1000.0;
+            for (i, frame) in frames.enumerate() {
+                let (pixels, timestamp_in_s) = frame?;
                 collector
-                    .add_frame_rgba(i, pixels, total_delay_in_s)
+                    .add_frame_rgba(i, pixels, timestamp_in_s)
                     .map_err(|e| CaesiumError {
                         message: e.to_string(),
                         code: 20408,
                     })?;
-                i += 1;
-                total_delay_in_s += delay_in_s;
             }
             drop(collector);
             Ok(())
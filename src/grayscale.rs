@@ -0,0 +1,62 @@
+use image::{ColorType, DynamicImage};
+
+use crate::CSParameters;
+
+/// Whether an image has to be encoded in grayscale: always with `to_grayscale`, or with `detect_grayscale` when
+/// `is_grayscale` confirms all the pixels are neutral within the given tolerance. Lossless encoders only accept exactly
+/// neutral pixels.
+pub fn encode_as_grayscale(parameters: &CSParameters, lossless: bool, is_grayscale: impl FnOnce(u8) -> bool) -> bool {
+    let tolerance = if lossless { 0 } else { parameters.grayscale_tolerance };
+    parameters.to_grayscale || (parameters.detect_grayscale && is_grayscale(tolerance))
+}
+
+/// Whether the color channels of interleaved pixels differ at most by `tolerance`. Channels past the third are ignored.
+pub fn is_neutral<T: Copy + Into<u32>>(pixels: &[T], channels: usize, tolerance: u32) -> bool {
+    pixels.chunks_exact(channels).all(|p| {
+        let (r, g, b) = (p[0].into(), p[1].into(), p[2].into());
+        r.max(g).max(b) - r.min(g).min(b) <= tolerance
+    })
+}
+
+/// Whether the image only holds neutral gray pixels, gray color types always do.
+pub fn is_grayscale(image: &DynamicImage, tolerance: u8) -> bool {
+    let tolerance = tolerance as u32;
+    match image {
+        DynamicImage::ImageLuma8(_)
+        | DynamicImage::ImageLumaA8(_)
+        | DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_) => true,
+        DynamicImage::ImageRgb8(i) => is_neutral(i.as_raw(), 3, tolerance),
+        DynamicImage::ImageRgba8(i) => is_neutral(i.as_raw(), 4, tolerance),
+        DynamicImage::ImageRgb16(i) => is_neutral(i.as_raw(), 3, tolerance * 257),
+        DynamicImage::ImageRgba16(i) => is_neutral(i.as_raw(), 4, tolerance * 257),
+        _ => false,
+    }
+}
+
+/// BT.601 luma, as used by JPEG.
+pub fn luma(r: u8, g: u8, b: u8) -> u8 {
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114 + 500) / 1000) as u8
+}
+
+/// Whether all the pixels are opaque, images without alpha always are.
+pub fn is_opaque(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageRgba8(i) => i.pixels().all(|p| p[3] == u8::MAX),
+        DynamicImage::ImageLumaA8(i) => i.pixels().all(|p| p[1] == u8::MAX),
+        DynamicImage::ImageRgba16(i) => i.pixels().all(|p| p[3] == u16::MAX),
+        DynamicImage::ImageLumaA16(i) => i.pixels().all(|p| p[1] == u16::MAX),
+        _ => !image.color().has_alpha(),
+    }
+}
+
+/// Converts the image to gray keeping its bit depth. The alpha channel is dropped when all the pixels are opaque.
+pub fn to_grayscale(image: DynamicImage) -> DynamicImage {
+    let is_opaque = is_opaque(&image);
+    let gray = image.grayscale();
+    match gray.color() {
+        ColorType::La8 if is_opaque => DynamicImage::ImageLuma8(gray.to_luma8()),
+        ColorType::La16 if is_opaque => DynamicImage::ImageLuma16(gray.to_luma16()),
+        _ => gray,
+    }
+}
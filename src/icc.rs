@@ -0,0 +1,164 @@
+use bytes::Bytes;
+use image::{DynamicImage, ImageBuffer};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+use crate::error::CaesiumError;
+use crate::CSParameters;
+
+/// Returns the parsed profile if pixels tagged with it must be converted to be displayed as sRGB.
+fn parse_convertible_profile(icc: &[u8]) -> Option<ColorProfile> {
+    let profile = ColorProfile::new_from_slice(icc).ok()?;
+    if profile.color_space != DataColorSpace::Rgb {
+        return None;
+    }
+
+    let srgb = ColorProfile::new_srgb();
+    let same_colorant = |a: moxcms::Xyzd, b: moxcms::Xyzd| {
+        (a.x - b.x).abs() < 0.002 && (a.y - b.y).abs() < 0.002 && (a.z - b.z).abs() < 0.002
+    };
+    let is_srgb = same_colorant(profile.red_colorant, srgb.red_colorant)
+        && same_colorant(profile.green_colorant, srgb.green_colorant)
+        && same_colorant(profile.blue_colorant, srgb.blue_colorant)
+        && profile.lut_a_to_b_perceptual.is_none();
+
+    if is_srgb {
+        None
+    } else {
+        Some(profile)
+    }
+}
+
+pub fn needs_srgb_conversion(icc: Option<&[u8]>) -> bool {
+    icc.is_some_and(|icc| parse_convertible_profile(icc).is_some())
+}
+
+pub fn is_cmyk_profile(icc: &[u8]) -> bool {
+    ColorProfile::new_from_slice(icc).is_ok_and(|p| p.color_space == DataColorSpace::Cmyk)
+}
+
+/// Converts 8-bit RGB or RGBA pixels tagged with `icc` to sRGB, in place.
+pub fn convert_buffer_to_srgb(buffer: &mut [u8], has_alpha: bool, icc: &[u8]) -> Result<(), CaesiumError> {
+    let Some(profile) = parse_convertible_profile(icc) else {
+        return Ok(());
+    };
+    let layout = if has_alpha { Layout::Rgba } else { Layout::Rgb };
+    let transform = profile
+        .create_transform_8bit(layout, &ColorProfile::new_srgb(), layout, TransformOptions::default())
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10600,
+        })?;
+
+    let source = buffer.to_vec();
+    transform.transform(&source, buffer).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10601,
+    })
+}
+
+/// Converts 8-bit CMYK pixels to sRGB through `icc`, or with a plain formula when there is no CMYK profile.
+/// `inverted` marks Adobe style data, where 255 means no ink.
+#[cfg(any(feature = "jpg", feature = "tiff"))]
+pub fn convert_cmyk_to_srgb(cmyk: &[u8], inverted: bool, icc: Option<&[u8]>) -> Result<Vec<u8>, CaesiumError> {
+    let profile = icc
+        .filter(|icc| is_cmyk_profile(icc))
+        .and_then(|icc| ColorProfile::new_from_slice(icc).ok());
+    let mut rgb = vec![0u8; cmyk.len() / 4 * 3];
+
+    let Some(profile) = profile else {
+        for (src, dst) in cmyk.chunks_exact(4).zip(rgb.chunks_exact_mut(3)) {
+            let ink = |v: u8| if inverted { v as u32 } else { 255 - v as u32 };
+            for c in 0..3 {
+                dst[c] = (ink(src[c]) * ink(src[3]) / 255) as u8;
+            }
+        }
+        return Ok(rgb);
+    };
+
+    let source: Vec<u8> = if inverted {
+        cmyk.iter().map(|v| 255 - v).collect()
+    } else {
+        cmyk.to_vec()
+    };
+    let transform = profile
+        .create_transform_8bit(
+            Layout::Rgba,
+            &ColorProfile::new_srgb(),
+            Layout::Rgb,
+            TransformOptions::default(),
+        )
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10600,
+        })?;
+    transform.transform(&source, &mut rgb).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 10601,
+    })?;
+
+    Ok(rgb)
+}
+
+/// Converts an image tagged with `icc` to sRGB. 16-bit images keep their precision.
+pub fn convert_image_to_srgb(image: DynamicImage, icc: &[u8]) -> Result<DynamicImage, CaesiumError> {
+    let Some(profile) = parse_convertible_profile(icc) else {
+        return Ok(image);
+    };
+    let srgb = ColorProfile::new_srgb();
+    let (width, height) = (image.width(), image.height());
+
+    macro_rules! transform_16bit {
+        ($buffer:expr, $layout:expr) => {{
+            let transform = profile
+                .create_transform_16bit($layout, &srgb, $layout, TransformOptions::default())
+                .map_err(|e| CaesiumError {
+                    message: e.to_string(),
+                    code: 10600,
+                })?;
+            let source = $buffer.into_raw();
+            let mut converted = vec![0u16; source.len()];
+            transform.transform(&source, &mut converted).map_err(|e| CaesiumError {
+                message: e.to_string(),
+                code: 10601,
+            })?;
+            ImageBuffer::from_raw(width, height, converted).ok_or(CaesiumError {
+                message: "Cannot create image buffer after color conversion".into(),
+                code: 10602,
+            })?
+        }};
+    }
+
+    let converted = match image {
+        DynamicImage::ImageRgb16(buffer) => DynamicImage::ImageRgb16(transform_16bit!(buffer, Layout::Rgb)),
+        DynamicImage::ImageRgba16(buffer) => DynamicImage::ImageRgba16(transform_16bit!(buffer, Layout::Rgba)),
+        i if i.color().has_alpha() => {
+            let mut buffer = i.to_rgba8();
+            convert_buffer_to_srgb(&mut buffer, true, icc)?;
+            DynamicImage::ImageRgba8(buffer)
+        }
+        i => {
+            let mut buffer = i.to_rgb8();
+            convert_buffer_to_srgb(&mut buffer, false, icc)?;
+            DynamicImage::ImageRgb8(buffer)
+        }
+    };
+
+    Ok(converted)
+}
+
+/// Returns the profile to attach to pixels that have been converted to sRGB, if any.
+pub fn converted_profile(parameters: &CSParameters) -> Option<Bytes> {
+    if parameters.embed_srgb_profile {
+        srgb_profile().map(Bytes::from)
+    } else {
+        None
+    }
+}
+
+fn srgb_profile() -> Option<Vec<u8>> {
+    let mut profile = ColorProfile::new_srgb();
+    profile.copyright = None;
+    profile.device_manufacturer = None;
+    profile.device_model = None;
+    profile.encode().ok()
+}
@@ -0,0 +1,602 @@
+use std::io::Cursor;
+
+use bytes::Bytes;
+use image::{ExtendedColorType, ImageDecoder, ImageReader};
+use img_parts::{DynImage, ImageICC};
+
+use crate::error::CaesiumError;
+use crate::parameters::ChromaSubsampling;
+use crate::utils::{get_filetype_from_memory, get_jpeg_orientation};
+use crate::SupportedFileTypes;
+
+/// Enum representing the color model of the image data as stored in the file.
+///
+/// - `Gray`: Grayscale
+/// - `GrayAlpha`: Grayscale with alpha channel
+/// - `Rgb`: RGB
+/// - `Rgba`: RGB with alpha channel
+/// - `Indexed`: Palette based
+/// - `YCbCr`: YCbCr (JPEG only)
+/// - `Cmyk`: CMYK
+/// - `Ycck`: YCbCr + K (JPEG only)
+/// - `Unknown`: Color model could not be determined
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ColorModel {
+    Gray,
+    GrayAlpha,
+    Rgb,
+    Rgba,
+    Indexed,
+    YCbCr,
+    Cmyk,
+    Ycck,
+    Unknown,
+}
+
+/// Struct representing the EXIF information relevant to compression.
+///
+/// Fields:
+/// - `orientation`: EXIF orientation (1-8)
+/// - `capture_date`: Original capture date, if present
+/// - `has_gps`: Whether the EXIF data contains GPS information
+#[derive(Clone, Debug)]
+pub struct ExifInfo {
+    pub orientation: u32,
+    pub capture_date: Option<String>,
+    pub has_gps: bool,
+}
+
+/// Struct representing JPEG specific information.
+///
+/// Fields:
+/// - `chroma_subsampling`: Chroma subsampling of the source, `None` for grayscale or non-standard factors
+/// - `progressive`: Whether the image is progressive
+/// - `estimated_quality`: Quality (1-100) estimated from the quantization tables
+#[derive(Copy, Clone, Debug)]
+pub struct JpegInfo {
+    pub chroma_subsampling: Option<ChromaSubsampling>,
+    pub progressive: bool,
+    pub estimated_quality: Option<u32>,
+}
+
+/// Struct representing the information extracted from an image.
+///
+/// Fields:
+/// - `format`: Detected file type
+/// - `width`: Width of the image (canvas width for animations)
+/// - `height`: Height of the image (canvas height for animations)
+/// - `color_model`: Color model of the stored data
+/// - `bit_depth`: Bits per channel
+/// - `frame_count`: Number of frames (pages for TIFF)
+/// - `duration_ms`: Total duration of the animation in milliseconds, 0 for still images
+/// - `has_alpha`: Whether the image has an alpha channel or transparency
+/// - `icc_description`: Description of the embedded ICC profile, if any
+/// - `exif`: Parsed EXIF information, if any
+/// - `jpeg`: JPEG specific information, only for JPEG files
+#[derive(Clone, Debug)]
+pub struct ImageInfo {
+    pub format: SupportedFileTypes,
+    pub width: u32,
+    pub height: u32,
+    pub color_model: ColorModel,
+    pub bit_depth: u8,
+    pub frame_count: u32,
+    pub duration_ms: u32,
+    pub has_alpha: bool,
+    pub icc_description: Option<String>,
+    pub exif: Option<ExifInfo>,
+    pub jpeg: Option<JpegInfo>,
+}
+
+// IJG reference tables (ITU T.81 Annex K), used to estimate the quality of a JPEG
+const STD_LUMINANCE_QUANT_TBL: [u16; 64] = [
+    16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51,
+    87, 80, 62, 18, 22, 37, 56, 68, 109, 103, 77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103, 99,
+];
+const STD_CHROMINANCE_QUANT_TBL: [u16; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99,
+];
+
+pub fn inspect(in_file: &[u8]) -> Result<ImageInfo, CaesiumError> {
+    let format = get_filetype_from_memory(in_file);
+
+    let mut info = match format {
+        SupportedFileTypes::Jpeg => inspect_jpeg(in_file),
+        SupportedFileTypes::Png => inspect_png(in_file),
+        SupportedFileTypes::Gif => inspect_gif(in_file),
+        SupportedFileTypes::WebP => inspect_webp(in_file),
+        SupportedFileTypes::Tiff => inspect_tiff(in_file),
+        SupportedFileTypes::Unkn => None,
+    }
+    .ok_or(CaesiumError {
+        message: "Unknown file type or malformed image".into(),
+        code: 10500,
+    })?;
+
+    info.icc_description = extract_icc(in_file, format).and_then(|icc| icc_description(&icc));
+    info.exif = extract_exif(in_file);
+
+    Ok(info)
+}
+
+fn new_info(format: SupportedFileTypes, width: u32, height: u32) -> ImageInfo {
+    ImageInfo {
+        format,
+        width,
+        height,
+        color_model: ColorModel::Unknown,
+        bit_depth: 8,
+        frame_count: 1,
+        duration_ms: 0,
+        has_alpha: false,
+        icc_description: None,
+        exif: None,
+        jpeg: None,
+    }
+}
+
+pub(crate) fn jpeg_segments(in_file: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = vec![];
+    if in_file.len() < 4 || in_file[0] != 0xFF || in_file[1] != 0xD8 {
+        return segments;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= in_file.len() {
+        if in_file[pos] != 0xFF {
+            break;
+        }
+        let marker = in_file[pos + 1];
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        if (0xD0..=0xD8).contains(&marker) || marker == 0x01 {
+            pos += 2;
+            continue;
+        }
+        let length = u16::from_be_bytes([in_file[pos + 2], in_file[pos + 3]]) as usize;
+        if length < 2 || pos + 2 + length > in_file.len() {
+            break;
+        }
+        segments.push((marker, &in_file[pos + 4..pos + 2 + length]));
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        pos += 2 + length;
+    }
+
+    segments
+}
+
+pub(crate) fn estimate_jpeg_quality(in_file: &[u8]) -> Option<u32> {
+    let mut tables: [Option<[u16; 64]>; 4] = [None; 4];
+    for (marker, data) in jpeg_segments(in_file) {
+        if marker != 0xDB {
+            continue;
+        }
+        let mut pos = 0;
+        while pos < data.len() {
+            let precision = data[pos] >> 4;
+            let id = (data[pos] & 0x0F) as usize;
+            let entry_size = if precision == 0 { 1 } else { 2 };
+            if id > 3 || pos + 1 + 64 * entry_size > data.len() {
+                break;
+            }
+            let mut table = [0u16; 64];
+            for (i, v) in table.iter_mut().enumerate() {
+                let offset = pos + 1 + i * entry_size;
+                *v = if precision == 0 {
+                    data[offset] as u16
+                } else {
+                    u16::from_be_bytes([data[offset], data[offset + 1]])
+                };
+            }
+            tables[id] = Some(table);
+            pos += 1 + 64 * entry_size;
+        }
+    }
+
+    // The order of the coefficients does not matter when comparing the sums of the tables
+    let mut quant_sum = 0u32;
+    let mut std_sum = 0u32;
+    for (table, std_table) in [
+        (tables[0], STD_LUMINANCE_QUANT_TBL),
+        (tables[1], STD_CHROMINANCE_QUANT_TBL),
+    ] {
+        if let Some(t) = table {
+            quant_sum += t.iter().map(|&v| v as u32).sum::<u32>();
+            std_sum += std_table.iter().map(|&v| v as u32).sum::<u32>();
+        }
+    }
+    if std_sum == 0 {
+        return None;
+    }
+
+    let scale = quant_sum as f64 * 100.0 / std_sum as f64;
+    let quality = if scale <= 100.0 {
+        (200.0 - scale) / 2.0
+    } else {
+        5000.0 / scale
+    };
+
+    Some(quality.round().clamp(1.0, 100.0) as u32)
+}
+
+fn inspect_jpeg(in_file: &[u8]) -> Option<ImageInfo> {
+    let segments = jpeg_segments(in_file);
+    let (sof_marker, sof) = segments
+        .iter()
+        .find(|(m, _)| matches!(m, 0xC0..=0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF))?;
+    if sof.len() < 6 {
+        return None;
+    }
+
+    let bit_depth = sof[0];
+    let height = u16::from_be_bytes([sof[1], sof[2]]) as u32;
+    let width = u16::from_be_bytes([sof[3], sof[4]]) as u32;
+    let components = sof[5] as usize;
+    if sof.len() < 6 + components * 3 {
+        return None;
+    }
+    let sampling = |c: usize| (sof[7 + c * 3] >> 4, sof[7 + c * 3] & 0x0F);
+
+    let adobe_transform = segments
+        .iter()
+        .find(|(m, d)| *m == 0xEE && d.len() >= 12 && d.starts_with(b"Adobe"))
+        .map(|(_, d)| d[11]);
+
+    let color_model = match components {
+        1 => ColorModel::Gray,
+        3 => match adobe_transform {
+            Some(0) => ColorModel::Rgb,
+            _ => ColorModel::YCbCr,
+        },
+        4 => match adobe_transform {
+            Some(2) => ColorModel::Ycck,
+            _ => ColorModel::Cmyk,
+        },
+        _ => ColorModel::Unknown,
+    };
+
+    let chroma_subsampling = if components >= 3 && sampling(1) == (1, 1) && sampling(2) == (1, 1) {
+        match sampling(0) {
+            (1, 1) => Some(ChromaSubsampling::CS444),
+            (2, 1) => Some(ChromaSubsampling::CS422),
+            (2, 2) => Some(ChromaSubsampling::CS420),
+            (4, 1) => Some(ChromaSubsampling::CS411),
+            _ => None,
+        }
+    } else {
+        None
+    };
+
+    let mut info = new_info(SupportedFileTypes::Jpeg, width, height);
+    info.color_model = color_model;
+    info.bit_depth = bit_depth;
+    info.jpeg = Some(JpegInfo {
+        chroma_subsampling,
+        progressive: matches!(sof_marker, 0xC2 | 0xC6 | 0xCA | 0xCE),
+        estimated_quality: estimate_jpeg_quality(in_file),
+    });
+
+    Some(info)
+}
+
+fn inspect_png(in_file: &[u8]) -> Option<ImageInfo> {
+    if in_file.len() < 33 || &in_file[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(in_file[16..20].try_into().ok()?);
+    let height = u32::from_be_bytes(in_file[20..24].try_into().ok()?);
+
+    let mut info = new_info(SupportedFileTypes::Png, width, height);
+    info.bit_depth = in_file[24];
+    info.color_model = match in_file[25] {
+        0 => ColorModel::Gray,
+        2 => ColorModel::Rgb,
+        3 => ColorModel::Indexed,
+        4 => ColorModel::GrayAlpha,
+        6 => ColorModel::Rgba,
+        _ => ColorModel::Unknown,
+    };
+    info.has_alpha = matches!(info.color_model, ColorModel::GrayAlpha | ColorModel::Rgba);
+
+    let mut pos = 8;
+    while pos + 12 <= in_file.len() {
+        let length = u32::from_be_bytes(in_file[pos..pos + 4].try_into().ok()?) as usize;
+        let kind = &in_file[pos + 4..pos + 8];
+        let data = in_file.get(pos + 8..pos + 8 + length)?;
+        match kind {
+            b"tRNS" => info.has_alpha = true,
+            b"acTL" if length >= 8 => {
+                info.frame_count = u32::from_be_bytes(data[0..4].try_into().ok()?);
+            }
+            b"fcTL" if length >= 26 => {
+                let numerator = u16::from_be_bytes([data[20], data[21]]) as u32;
+                let denominator = match u16::from_be_bytes([data[22], data[23]]) {
+                    0 => 100,
+                    d => d as u32,
+                };
+                info.duration_ms = info
+                    .duration_ms
+                    .saturating_add(numerator.saturating_mul(1000) / denominator);
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + length;
+    }
+
+    if info.frame_count <= 1 {
+        info.duration_ms = 0;
+    }
+
+    Some(info)
+}
+
+fn inspect_gif(in_file: &[u8]) -> Option<ImageInfo> {
+    if in_file.len() < 13 {
+        return None;
+    }
+    let width = u16::from_le_bytes([in_file[6], in_file[7]]) as u32;
+    let height = u16::from_le_bytes([in_file[8], in_file[9]]) as u32;
+    let packed = in_file[10];
+
+    let mut info = new_info(SupportedFileTypes::Gif, width, height);
+    info.color_model = ColorModel::Indexed;
+    info.frame_count = 0;
+
+    let mut pos = 13;
+    if packed & 0x80 != 0 {
+        pos += 3 << ((packed & 0x07) + 1);
+    }
+
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let size = *in_file.get(pos)? as usize;
+            pos += 1;
+            if size == 0 {
+                return Some(pos);
+            }
+            pos += size;
+        }
+    };
+
+    while let Some(&block) = in_file.get(pos) {
+        match block {
+            0x21 => {
+                let label = *in_file.get(pos + 1)?;
+                if label == 0xF9 && in_file.len() > pos + 7 {
+                    if in_file[pos + 3] & 0x01 != 0 {
+                        info.has_alpha = true;
+                    }
+                    let delay = u16::from_le_bytes([in_file[pos + 4], in_file[pos + 5]]) as u32;
+                    info.duration_ms = info.duration_ms.saturating_add(delay.saturating_mul(10));
+                }
+                pos = skip_sub_blocks(pos + 2)?;
+            }
+            0x2C => {
+                info.frame_count += 1;
+                let local_packed = *in_file.get(pos + 9)?;
+                pos += 10;
+                if local_packed & 0x80 != 0 {
+                    pos += 3 << ((local_packed & 0x07) + 1);
+                }
+                pos = skip_sub_blocks(pos + 1)?;
+            }
+            _ => break,
+        }
+    }
+
+    if info.frame_count <= 1 {
+        info.frame_count = 1;
+        info.duration_ms = 0;
+    }
+
+    Some(info)
+}
+
+fn inspect_webp(in_file: &[u8]) -> Option<ImageInfo> {
+    if in_file.len() < 20 || &in_file[8..12] != b"WEBP" {
+        return None;
+    }
+
+    let mut info = new_info(SupportedFileTypes::WebP, 0, 0);
+    info.color_model = ColorModel::Rgb;
+    let mut frame_count = 0;
+
+    let mut pos = 12;
+    while pos + 8 <= in_file.len() {
+        let kind = &in_file[pos..pos + 4];
+        let length = u32::from_le_bytes(in_file[pos + 4..pos + 8].try_into().ok()?) as usize;
+        let data = in_file.get(pos + 8..pos + 8 + length)?;
+        let read_u24 = |d: &[u8]| d[0] as u32 | (d[1] as u32) << 8 | (d[2] as u32) << 16;
+        match kind {
+            b"VP8X" if length >= 10 => {
+                info.has_alpha = data[0] & 0x10 != 0;
+                info.width = read_u24(&data[4..7]) + 1;
+                info.height = read_u24(&data[7..10]) + 1;
+            }
+            b"VP8 " if length >= 10 && info.width == 0 => {
+                info.width = (u16::from_le_bytes([data[6], data[7]]) & 0x3FFF) as u32;
+                info.height = (u16::from_le_bytes([data[8], data[9]]) & 0x3FFF) as u32;
+            }
+            b"VP8L" if length >= 5 && info.width == 0 => {
+                let bits = u32::from_le_bytes(data[1..5].try_into().ok()?);
+                info.width = (bits & 0x3FFF) + 1;
+                info.height = ((bits >> 14) & 0x3FFF) + 1;
+                info.has_alpha = (bits >> 28) & 0x01 != 0;
+            }
+            b"ALPH" => info.has_alpha = true,
+            b"ANMF" if length >= 16 => {
+                frame_count += 1;
+                info.duration_ms = info.duration_ms.saturating_add(read_u24(&data[12..15]));
+            }
+            _ => {}
+        }
+        pos += 8 + length + (length & 1);
+    }
+
+    if info.has_alpha {
+        info.color_model = ColorModel::Rgba;
+    }
+    if frame_count > 1 {
+        info.frame_count = frame_count;
+    } else {
+        info.duration_ms = 0;
+    }
+
+    Some(info)
+}
+
+fn inspect_tiff(in_file: &[u8]) -> Option<ImageInfo> {
+    let decoder = ImageReader::new(Cursor::new(in_file))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let (width, height) = decoder.dimensions();
+
+    let mut info = new_info(SupportedFileTypes::Tiff, width, height);
+    (info.color_model, info.bit_depth) = match decoder.original_color_type() {
+        ExtendedColorType::L1 => (ColorModel::Gray, 1),
+        ExtendedColorType::L2 => (ColorModel::Gray, 2),
+        ExtendedColorType::L4 => (ColorModel::Gray, 4),
+        ExtendedColorType::L8 => (ColorModel::Gray, 8),
+        ExtendedColorType::L16 => (ColorModel::Gray, 16),
+        ExtendedColorType::La8 => (ColorModel::GrayAlpha, 8),
+        ExtendedColorType::La16 => (ColorModel::GrayAlpha, 16),
+        ExtendedColorType::Rgb8 | ExtendedColorType::Bgr8 => (ColorModel::Rgb, 8),
+        ExtendedColorType::Rgb16 => (ColorModel::Rgb, 16),
+        ExtendedColorType::Rgb32F => (ColorModel::Rgb, 32),
+        ExtendedColorType::Rgba8 | ExtendedColorType::Bgra8 => (ColorModel::Rgba, 8),
+        ExtendedColorType::Rgba16 => (ColorModel::Rgba, 16),
+        ExtendedColorType::Rgba32F => (ColorModel::Rgba, 32),
+        ExtendedColorType::Cmyk8 => (ColorModel::Cmyk, 8),
+        c => (
+            ColorModel::Unknown,
+            (c.bits_per_pixel() / c.channel_count().max(1) as u16) as u8,
+        ),
+    };
+    info.has_alpha = matches!(info.color_model, ColorModel::GrayAlpha | ColorModel::Rgba);
+    info.frame_count = count_tiff_directories(in_file).max(1);
+
+    Some(info)
+}
+
+fn count_tiff_directories(in_file: &[u8]) -> u32 {
+    if in_file.len() < 8 {
+        return 0;
+    }
+    let little_endian = &in_file[0..2] == b"II";
+    let read_u16 = |p: usize| -> Option<u16> {
+        let b: [u8; 2] = in_file.get(p..p + 2)?.try_into().ok()?;
+        Some(if little_endian {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    };
+    let read_u32 = |p: usize| -> Option<u32> {
+        let b: [u8; 4] = in_file.get(p..p + 4)?.try_into().ok()?;
+        Some(if little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    };
+
+    // BigTIFF is not walked, the first directory is always there
+    if read_u16(2) != Some(42) {
+        return 1;
+    }
+
+    let mut count = 0;
+    let mut visited = vec![];
+    let mut offset = read_u32(4).unwrap_or(0) as usize;
+    while offset != 0 && !visited.contains(&offset) {
+        let Some(entries) = read_u16(offset) else {
+            break;
+        };
+        visited.push(offset);
+        count += 1;
+        offset = read_u32(offset + 2 + entries as usize * 12).unwrap_or(0) as usize;
+    }
+
+    count
+}
+
+fn extract_icc(in_file: &[u8], format: SupportedFileTypes) -> Option<Vec<u8>> {
+    if format == SupportedFileTypes::Tiff {
+        let mut decoder = ImageReader::new(Cursor::new(in_file))
+            .with_guessed_format()
+            .ok()?
+            .into_decoder()
+            .ok()?;
+        return decoder.icc_profile().ok().flatten();
+    }
+
+    DynImage::from_bytes(Bytes::copy_from_slice(in_file))
+        .ok()
+        .flatten()
+        .and_then(|d| d.icc_profile())
+        .map(|icc| icc.to_vec())
+}
+
+fn icc_description(icc: &[u8]) -> Option<String> {
+    let read_u32 =
+        |p: usize| -> Option<usize> { Some(u32::from_be_bytes(icc.get(p..p + 4)?.try_into().ok()?) as usize) };
+
+    let tag_count = read_u32(128)?;
+    for i in 0..tag_count {
+        let entry = 132 + i * 12;
+        if icc.get(entry..entry + 4)? != b"desc" {
+            continue;
+        }
+        let offset = read_u32(entry + 4)?;
+        let tag = icc.get(offset..offset + read_u32(entry + 8)?)?;
+        let description = match tag.get(0..4)? {
+            b"desc" => {
+                let length = u32::from_be_bytes(tag.get(8..12)?.try_into().ok()?) as usize;
+                let ascii = tag.get(12..12 + length)?;
+                String::from_utf8_lossy(ascii).trim_end_matches('\0').to_string()
+            }
+            b"mluc" => {
+                let length = u32::from_be_bytes(tag.get(20..24)?.try_into().ok()?) as usize;
+                let string_offset = u32::from_be_bytes(tag.get(24..28)?.try_into().ok()?) as usize;
+                let utf16: Vec<u16> = tag
+                    .get(string_offset..string_offset + length)?
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&utf16).trim_end_matches('\0').to_string()
+            }
+            _ => return None,
+        };
+        return Some(description);
+    }
+
+    None
+}
+
+fn extract_exif(in_file: &[u8]) -> Option<ExifInfo> {
+    let exif_data = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(in_file))
+        .ok()?;
+
+    let capture_date = exif_data
+        .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        .or_else(|| exif_data.get_field(exif::Tag::DateTime, exif::In::PRIMARY))
+        .map(|f| f.display_value().to_string());
+    let has_gps = exif_data.fields().any(|f| f.tag.context() == exif::Context::Gps);
+
+    Some(ExifInfo {
+        orientation: get_jpeg_orientation(in_file),
+        capture_date,
+        has_gps,
+    })
+}
@@ -1,10 +1,15 @@
 use std::ffi::{CStr, CString};
 use std::os::raw::c_char;
+use std::time::Duration;
 
-use crate::parameters::ChromaSubsampling;
 use crate::parameters::TiffCompression::{Deflate, Lzw, Packbits, Uncompressed};
+use crate::parameters::{
+    ChromaSubsampling, Dpi, JpegDcScanMode, JpegProfile, JpegQuantTable, JpegScan, JpegSourceQualityPolicy, JpegTune,
+    PngDeflater, PngFilter, PngInterlace, WebPAlphaFiltering, WebPPreset,
+};
 use crate::{
-    compress, compress_in_memory, compress_to_size, convert, error, CSParameters, SupportedFileTypes, TiffDeflateLevel,
+    compress_in_memory_with_warnings, compress_to_size_with_warnings, compress_with_warnings, convert_with_warnings,
+    error, CSParameters, SupportedFileTypes, TiffDeflateLevel,
 };
 
 #[repr(C)]
@@ -13,32 +18,116 @@ pub struct CByteArray {
     pub length: usize,
 }
 
+#[repr(C)]
+pub struct CCSJpegScan {
+    pub components: [u32; 4],
+    pub component_count: u32,
+    pub spectral_start: u32,
+    pub spectral_end: u32,
+    pub approx_high: u32,
+    pub approx_low: u32,
+}
+
 #[repr(C)]
 pub struct CCSParameters {
     pub keep_metadata: bool,
+    pub convert_to_srgb: bool,
+    pub embed_srgb_profile: bool,
+    pub keep_dpi: bool,
+    pub dpi_x: u32,
+    pub dpi_y: u32,
+    pub to_grayscale: bool,
+    pub detect_grayscale: bool,
+    pub grayscale_tolerance: u32,
     pub jpeg_quality: u32,
+    pub jpeg_chroma_quality: u32,
     pub jpeg_chroma_subsampling: u32,
     pub jpeg_progressive: bool,
+    pub jpeg_optimize_scans: bool,
+    pub jpeg_dc_scan_mode: u32,
+    pub jpeg_scan_script: *const CCSJpegScan,
+    pub jpeg_scan_script_length: usize,
     pub jpeg_optimize: bool,
     pub jpeg_preserve_icc: bool,
+    pub jpeg_cmyk_to_rgb: bool,
+    pub jpeg_source_quality_policy: u32,
+    pub jpeg_recover_corrupted: bool,
+    pub jpeg_keep_gain_map: bool,
+    pub jpeg_requantize: bool,
+    pub jpeg_profile: u32,
+    pub jpeg_quant_table: u32,
+    pub jpeg_trellis: bool,
+    pub jpeg_tune: u32,
+    pub jpeg_overshoot_deringing: bool,
+    pub jpeg_smoothing: u32,
+    pub jpeg_restart_interval: u32,
+    pub jpeg_arithmetic_coding: bool,
     pub png_quality: u32,
     pub png_optimization_level: u32,
     pub png_force_zopfli: bool,
     pub png_optimize: bool,
+    pub png_max_colors: u32,
+    pub png_dithering_level: f32,
+    pub png_speed: u32,
+    pub png_min_quality: u32,
+    pub png_posterization: u32,
+    pub png_keep_16_bit: bool,
+    pub png_dither_16_bit: bool,
+    pub png_filters: *const u32,
+    pub png_filters_length: usize,
+    pub png_interlace: u32,
+    pub png_optimize_alpha: bool,
+    pub png_bit_depth_reduction: bool,
+    pub png_color_type_reduction: bool,
+    pub png_palette_reduction: bool,
+    pub png_strip_chunks: *const [u8; 4],
+    pub png_strip_chunks_length: usize,
+    pub png_keep_chunks: *const [u8; 4],
+    pub png_keep_chunks_length: usize,
+    pub png_deflater: u32,
+    pub png_deflate_effort: u32,
+    pub png_timeout: u32,
     pub gif_quality: u32,
     pub webp_quality: u32,
     pub webp_lossless: bool,
+    pub webp_method: u32,
+    pub webp_preset: u32,
+    pub webp_sns_strength: i32,
+    pub webp_filter_strength: i32,
+    pub webp_filter_sharpness: i32,
+    pub webp_segments: u32,
+    pub webp_pass: u32,
+    pub webp_alpha_quality: u32,
+    pub webp_alpha_filtering: u32,
+    pub webp_near_lossless: u32,
+    pub webp_exact: bool,
+    pub webp_use_sharp_yuv: bool,
+    pub webp_target_psnr: f32,
+    pub webp_target_size: u32,
+    pub webp_allow_mixed: bool,
+    pub webp_kmin: i32,
+    pub webp_kmax: i32,
     pub tiff_compression: u32,
     pub tiff_deflate_level: u32,
+    pub tiff_first_page: u32,
+    pub tiff_last_page: u32,
     pub width: u32,
     pub height: u32,
 }
 
+#[repr(C)]
+pub struct CCSWarning {
+    pub code: u32,
+    pub message: *const c_char,
+}
+
 #[repr(C)]
 pub struct CCSResult {
     pub success: bool,
     pub code: u32,
     pub error_message: *const c_char,
+    pub warnings: *mut CCSWarning,
+    pub warnings_length: usize,
 }
 
 #[no_mangle]
@@ -50,7 +139,7 @@ pub unsafe extern "C" fn c_compress(
 ) -> CCSResult {
     let parameters = c_set_parameters(params);
 
-    c_return_result(compress(
+    c_return_result(compress_with_warnings(
         CStr::from_ptr(input_path).to_str().unwrap().to_string(),
         CStr::from_ptr(output_path).to_str().unwrap().to_string(),
         &parameters,
@@ -70,6 +159,8 @@ pub unsafe extern "C" fn c_compress_in_memory(
             success: false,
             code: 1001,
             error_message: CString::new("Null pointer provided").unwrap().into_raw(),
+            warnings: std::ptr::null_mut(),
+            warnings_length: 0,
         };
     }
 
@@ -77,8 +168,8 @@ pub unsafe extern "C" fn c_compress_in_memory(
 
     let parameters = c_set_parameters(params);
 
-    match compress_in_memory(input_vec, &parameters) {
-        Ok(compressed_data) => {
+    match compress_in_memory_with_warnings(input_vec, &parameters) {
+        Ok((compressed_data, warnings)) => {
             let output_length = compressed_data.len();
             let output_data = libc::malloc(output_length) as *mut u8;
 
@@ -87,6 +178,8 @@ pub unsafe extern "C" fn c_compress_in_memory(
                     success: false,
                     code: 1002,
                     error_message: CString::new("Memory allocation failed").unwrap().into_raw(),
+                    warnings: std::ptr::null_mut(),
+                    warnings_length: 0,
                 };
             }
 
@@ -95,21 +188,13 @@ pub unsafe extern "C" fn c_compress_in_memory(
             (*output).data = output_data;
             (*output).length = output_length;
 
-            CCSResult {
-                success: true,
-                code: 0,
-                error_message: CString::new("").unwrap().into_raw(),
-            }
+            c_return_result(Ok(warnings))
         }
         Err(e) => {
             (*output).data = std::ptr::null_mut();
             (*output).length = 0;
 
-            CCSResult {
-                success: false,
-                code: e.code,
-                error_message: CString::new(e.to_string()).unwrap().into_raw(),
-            }
+            c_return_result(Err(e))
         }
     }
 }
@@ -125,7 +210,7 @@ pub unsafe extern "C" fn c_compress_to_size(
 ) -> CCSResult {
     let mut parameters = c_set_parameters(params);
 
-    c_return_result(compress_to_size(
+    c_return_result(compress_to_size_with_warnings(
         CStr::from_ptr(input_path).to_str().unwrap().to_string(),
         CStr::from_ptr(output_path).to_str().unwrap().to_string(),
         &mut parameters,
@@ -144,7 +229,7 @@ pub unsafe extern "C" fn c_convert(
 ) -> CCSResult {
     let parameters = c_set_parameters(params);
 
-    c_return_result(convert(
+    c_return_result(convert_with_warnings(
         CStr::from_ptr(input_path).to_str().unwrap().to_string(),
         CStr::from_ptr(output_path).to_str().unwrap().to_string(),
         &parameters,
@@ -152,21 +237,44 @@ pub unsafe extern "C" fn c_convert(
     ))
 }
 
-fn c_return_result(result: error::Result<()>) -> CCSResult {
+fn c_return_result(result: error::Result<Vec<error::CaesiumError>>) -> CCSResult {
     match result {
-        Ok(_) => CCSResult {
-            success: true,
-            code: 0,
-            error_message: CString::new("").unwrap().into_raw(),
-        },
+        Ok(warnings) => {
+            let (warnings, warnings_length) = c_warnings(warnings);
+            CCSResult {
+                success: true,
+                code: 0,
+                error_message: CString::new("").unwrap().into_raw(),
+                warnings,
+                warnings_length,
+            }
+        }
         Err(e) => CCSResult {
             success: false,
             code: e.code,
             error_message: CString::new(e.to_string()).unwrap().into_raw(),
+            warnings: std::ptr::null_mut(),
+            warnings_length: 0,
         },
     }
 }
 
+fn c_warnings(warnings: Vec<error::CaesiumError>) -> (*mut CCSWarning, usize) {
+    if warnings.is_empty() {
+        return (std::ptr::null_mut(), 0);
+    }
+
+    let warnings: Box<[CCSWarning]> = warnings
+        .into_iter()
+        .map(|w| CCSWarning {
+            code: w.code,
+            message: CString::new(w.to_string()).unwrap().into_raw(),
+        })
+        .collect();
+    let length = warnings.len();
+    (Box::into_raw(warnings) as *mut CCSWarning, length)
+}
+
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
 pub unsafe extern "C" fn c_free_byte_array(byte_array: CByteArray) {
@@ -175,6 +283,18 @@ pub unsafe extern "C" fn c_free_byte_array(byte_array: CByteArray) {
     }
 }
 
+// Helper function to free the warnings of a result
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn c_free_warnings(warnings: *mut CCSWarning, length: usize) {
+    if !warnings.is_null() {
+        let warnings = Box::from_raw(std::ptr::slice_from_raw_parts_mut(warnings, length));
+        for warning in warnings.iter() {
+            drop(CString::from_raw(warning.message as *mut c_char));
+        }
+    }
+}
+
 // Helper function to free error message strings
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
@@ -184,21 +304,84 @@ pub unsafe extern "C" fn c_free_string(ptr: *mut c_char) {
     }
 }
 
-fn c_set_parameters(params: CCSParameters) -> CSParameters {
+unsafe fn c_set_parameters(params: CCSParameters) -> CSParameters {
     let mut parameters = CSParameters::new();
 
     parameters.jpeg.quality = params.jpeg_quality;
+    parameters.jpeg.chroma_quality = match params.jpeg_chroma_quality {
+        0 => None,
+        q => Some(q),
+    };
     parameters.jpeg.progressive = params.jpeg_progressive;
+    parameters.jpeg.optimize_scans = params.jpeg_optimize_scans;
     parameters.jpeg.optimize = params.jpeg_optimize;
     parameters.jpeg.preserve_icc = params.jpeg_preserve_icc;
+    parameters.jpeg.cmyk_to_rgb = params.jpeg_cmyk_to_rgb;
+    parameters.jpeg.recover_corrupted = params.jpeg_recover_corrupted;
+    parameters.jpeg.keep_gain_map = params.jpeg_keep_gain_map;
+    parameters.jpeg.requantize = params.jpeg_requantize;
+    parameters.jpeg.trellis = params.jpeg_trellis;
+    parameters.jpeg.overshoot_deringing = params.jpeg_overshoot_deringing;
+    parameters.jpeg.smoothing = params.jpeg_smoothing;
+    parameters.jpeg.restart_interval = params.jpeg_restart_interval;
+    parameters.jpeg.arithmetic_coding = params.jpeg_arithmetic_coding;
     parameters.png.quality = params.png_quality;
     parameters.png.optimize = params.png_optimize;
+    parameters.png.max_colors = match params.png_max_colors {
+        0 => 256,
+        colors => colors,
+    };
+    parameters.png.dithering_level = params.png_dithering_level;
+    parameters.png.speed = params.png_speed.min(u8::MAX as u32) as u8;
+    parameters.png.min_quality = params.png_min_quality;
+    parameters.png.posterization = params.png_posterization.min(u8::MAX as u32) as u8;
+    parameters.png.keep_16_bit = params.png_keep_16_bit;
+    parameters.png.dither_16_bit = params.png_dither_16_bit;
+    parameters.png.optimize_alpha = params.png_optimize_alpha;
+    parameters.png.bit_depth_reduction = params.png_bit_depth_reduction;
+    parameters.png.color_type_reduction = params.png_color_type_reduction;
+    parameters.png.palette_reduction = params.png_palette_reduction;
+    parameters.png.timeout = match params.png_timeout {
+        0 => None,
+        timeout => Some(Duration::from_millis(timeout as u64)),
+    };
     parameters.keep_metadata = params.keep_metadata;
+    parameters.convert_to_srgb = params.convert_to_srgb;
+    parameters.embed_srgb_profile = params.embed_srgb_profile;
+    parameters.keep_dpi = params.keep_dpi;
+    parameters.dpi = match (params.dpi_x, params.dpi_y) {
+        (0, 0) => None,
+        (x, y) => Some(Dpi { x, y }),
+    };
+    parameters.to_grayscale = params.to_grayscale;
+    parameters.detect_grayscale = params.detect_grayscale;
+    parameters.grayscale_tolerance = params.grayscale_tolerance.min(u8::MAX as u32) as u8;
     parameters.png.optimization_level = params.png_optimization_level as u8;
     parameters.png.force_zopfli = params.png_force_zopfli;
     parameters.gif.quality = params.gif_quality;
     parameters.webp.quality = params.webp_quality;
     parameters.webp.lossless = params.webp_lossless;
+    parameters.webp.method = params.webp_method.min(u8::MAX as u32) as u8;
+    parameters.webp.sns_strength = u8::try_from(params.webp_sns_strength).ok();
+    parameters.webp.filter_strength = u8::try_from(params.webp_filter_strength).ok();
+    parameters.webp.filter_sharpness = u8::try_from(params.webp_filter_sharpness).ok();
+    parameters.webp.segments = match params.webp_segments {
+        0 => 4,
+        segments => segments.min(u8::MAX as u32) as u8,
+    };
+    parameters.webp.pass = match params.webp_pass {
+        0 => 1,
+        pass => pass.min(u8::MAX as u32) as u8,
+    };
+    parameters.webp.alpha_quality = params.webp_alpha_quality.min(u8::MAX as u32) as u8;
+    parameters.webp.near_lossless = params.webp_near_lossless.min(u8::MAX as u32) as u8;
+    parameters.webp.exact = params.webp_exact;
+    parameters.webp.use_sharp_yuv = params.webp_use_sharp_yuv;
+    parameters.webp.target_psnr = params.webp_target_psnr;
+    parameters.webp.target_size = params.webp_target_size;
+    parameters.webp.allow_mixed = params.webp_allow_mixed;
+    parameters.webp.kmin = u32::try_from(params.webp_kmin).ok();
+    parameters.webp.kmax = u32::try_from(params.webp_kmax).ok();
     parameters.width = params.width;
     parameters.height = params.height;
 
@@ -207,9 +390,101 @@ fn c_set_parameters(params: CCSParameters) -> CSParameters {
         422 => ChromaSubsampling::CS422,
         420 => ChromaSubsampling::CS420,
         411 => ChromaSubsampling::CS411,
+        1 => ChromaSubsampling::Preserve,
         _ => ChromaSubsampling::Auto,
     };
 
+    parameters.jpeg.dc_scan_mode = match params.jpeg_dc_scan_mode {
+        0 => JpegDcScanMode::Interleaved,
+        2 => JpegDcScanMode::LumaSeparate,
+        _ => JpegDcScanMode::PerComponent,
+    };
+
+    if let Some(scan_script) = c_slice(params.jpeg_scan_script, params.jpeg_scan_script_length) {
+        parameters.jpeg.scan_script = Some(
+            scan_script
+                .iter()
+                .map(|scan| JpegScan {
+                    components: scan.components.map(|c| c.min(u8::MAX as u32) as u8),
+                    component_count: scan.component_count.min(u8::MAX as u32) as u8,
+                    spectral_start: scan.spectral_start.min(u8::MAX as u32) as u8,
+                    spectral_end: scan.spectral_end.min(u8::MAX as u32) as u8,
+                    approx_high: scan.approx_high.min(u8::MAX as u32) as u8,
+                    approx_low: scan.approx_low.min(u8::MAX as u32) as u8,
+                })
+                .collect(),
+        );
+    }
+
+    parameters.webp.preset = match params.webp_preset {
+        1 => WebPPreset::Picture,
+        2 => WebPPreset::Photo,
+        3 => WebPPreset::Drawing,
+        4 => WebPPreset::Icon,
+        5 => WebPPreset::Text,
+        _ => WebPPreset::Default,
+    };
+
+    parameters.webp.alpha_filtering = match params.webp_alpha_filtering {
+        0 => WebPAlphaFiltering::None,
+        2 => WebPAlphaFiltering::Best,
+        _ => WebPAlphaFiltering::Fast,
+    };
+
+    parameters.png.interlace = match params.png_interlace {
+        1 => PngInterlace::Adam7,
+        2 => PngInterlace::Keep,
+        _ => PngInterlace::None,
+    };
+
+    parameters.png.filters = c_slice(params.png_filters, params.png_filters_length)
+        .map(|filters| filters.iter().filter_map(|f| png_filter(*f)).collect());
+    parameters.png.strip_chunks = c_slice(params.png_strip_chunks, params.png_strip_chunks_length).map(<[_]>::to_vec);
+    if let Some(keep_chunks) = c_slice(params.png_keep_chunks, params.png_keep_chunks_length) {
+        parameters.png.keep_chunks = keep_chunks.to_vec();
+    }
+
+    let png_deflate_effort = params.png_deflate_effort.min(u8::MAX as u32) as u8;
+    parameters.png.deflater = match params.png_deflater {
+        1 => Some(PngDeflater::Libdeflater {
+            level: png_deflate_effort,
+        }),
+        2 => Some(PngDeflater::Zopfli {
+            iterations: png_deflate_effort,
+        }),
+        _ => None,
+    };
+
+    parameters.jpeg.source_quality_policy = match params.jpeg_source_quality_policy {
+        1 => JpegSourceQualityPolicy::Cap,
+        2 => JpegSourceQualityPolicy::Lossless,
+        _ => JpegSourceQualityPolicy::Ignore,
+    };
+
+    parameters.jpeg.profile = match params.jpeg_profile {
+        1 => JpegProfile::Fastest,
+        _ => JpegProfile::MaxCompression,
+    };
+
+    parameters.jpeg.quant_table = match params.jpeg_quant_table {
+        0 => JpegQuantTable::AnnexK,
+        1 => JpegQuantTable::Flat,
+        2 => JpegQuantTable::MssimTuned,
+        4 => JpegQuantTable::PsnrHvsTuned,
+        5 => JpegQuantTable::Klein,
+        6 => JpegQuantTable::Watson,
+        7 => JpegQuantTable::Ahumada,
+        8 => JpegQuantTable::Peterson,
+        _ => JpegQuantTable::ImageMagick,
+    };
+
+    parameters.jpeg.tune = match params.jpeg_tune {
+        1 => JpegTune::Psnr,
+        2 => JpegTune::Ssim,
+        3 => JpegTune::MsSsim,
+        _ => JpegTune::PsnrHvs,
+    };
+
     parameters.tiff.algorithm = match params.tiff_compression {
         1 => Lzw,
         2 => Deflate,
@@ -222,6 +497,36 @@ fn c_set_parameters(params: CCSParameters) -> CSParameters {
         6 => TiffDeflateLevel::Balanced,
         _ => TiffDeflateLevel::Best,
     };
+    //The last page counts from 1, so that zero-initialised parameters keep all the pages
+    if params.tiff_first_page > 0 || params.tiff_last_page > 0 {
+        let last_page = params.tiff_last_page.checked_sub(1).unwrap_or(u32::MAX);
+        parameters.tiff.pages = Some((params.tiff_first_page, last_page));
+    }
 
     parameters
 }
+
+/// Borrows an array passed from C, `None` when the pointer is null.
+unsafe fn c_slice<'a, T>(data: *const T, length: usize) -> Option<&'a [T]> {
+    if data.is_null() {
+        None
+    } else {
+        Some(std::slice::from_raw_parts(data, length))
+    }
+}
+
+fn png_filter(filter: u32) -> Option<PngFilter> {
+    match filter {
+        0 => Some(PngFilter::None),
+        1 => Some(PngFilter::Sub),
+        2 => Some(PngFilter::Up),
+        3 => Some(PngFilter::Average),
+        4 => Some(PngFilter::Paeth),
+        5 => Some(PngFilter::MinSum),
+        6 => Some(PngFilter::Entropy),
+        7 => Some(PngFilter::Bigrams),
+        8 => Some(PngFilter::BigEnt),
+        9 => Some(PngFilter::Brute),
+        _ => None,
+    }
+}
@@ -1,13 +1,25 @@
+use crate::density::output_dpi;
 use crate::error::CaesiumError;
-use crate::parameters::ChromaSubsampling;
-use crate::resize::resize;
+use crate::grayscale::{encode_as_grayscale, is_neutral, luma};
+use crate::icc::{convert_buffer_to_srgb, convert_cmyk_to_srgb, converted_profile, needs_srgb_conversion};
+use crate::info::{estimate_jpeg_quality, inspect};
+use crate::mpf;
+use crate::mpf::MultiPicture;
+use crate::parameters::{
+    ChromaSubsampling, Dpi, JpegProfile, JpegScan, JpegSourceQualityPolicy, JpegTransform, JpegTransformation, JpegTune,
+};
+use crate::resize::{resize, resize_decoded};
+use crate::utils::get_jpeg_orientation;
 use crate::CSParameters;
 use bytes::Bytes;
 use image::ImageFormat::Jpeg;
+use image::{DynamicImage, RgbImage};
 use img_parts::jpeg::Jpeg as PartsJpeg;
 use img_parts::{ImageEXIF, ImageICC};
 use libc::free;
 use mozjpeg_sys::*;
+use std::cell::RefCell;
+use std::ffi::CStr;
 use std::fs::File;
 use std::io::Write;
 use std::mem;
@@ -17,14 +29,21 @@ use std::sync::atomic::{AtomicI32, Ordering};
 use std::{fs, ptr};
 
 static JPEG_ERROR: AtomicI32 = AtomicI32::new(0);
+thread_local! {
+    static JPEG_WARNINGS: RefCell<Vec<CaesiumError>> = const { RefCell::new(Vec::new()) };
+}
 
-pub fn compress(input_path: String, output_path: String, parameters: &CSParameters) -> Result<(), CaesiumError> {
+pub fn compress(
+    input_path: String,
+    output_path: String,
+    parameters: &CSParameters,
+) -> Result<Vec<CaesiumError>, CaesiumError> {
     let in_file = fs::read(input_path).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20100,
     })?;
 
-    let out_buffer = compress_in_memory(&in_file, parameters)?;
+    let (out_buffer, warnings) = compress_in_memory_with_warnings(&in_file, parameters)?;
     let mut out_file = File::create(output_path).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20101,
@@ -33,22 +52,154 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
         message: e.to_string(),
         code: 20102,
     })?;
-    Ok(())
+    Ok(warnings)
+}
+
+/// Compresses a JPEG in memory, also returning the issues found in the input that did not stop the compression.
+pub fn compress_in_memory_with_warnings(
+    in_file: &[u8],
+    parameters: &CSParameters,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    match mpf::split(in_file) {
+        Some(picture) => compress_multi_picture(picture, parameters),
+        None => compress_image(in_file, parameters, true),
+    }
 }
 
-pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+/// Compresses the primary image and, unless dropped, the secondary ones like gain maps with the same settings. Other
+/// secondary images are only kept with the metadata.
+fn compress_multi_picture(
+    mut picture: MultiPicture,
+    parameters: &CSParameters,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    if !parameters.keep_metadata {
+        picture.retain_gain_maps();
+    }
+    if !parameters.jpeg.keep_gain_map || picture.secondary.is_empty() {
+        let (primary, warnings) = compress_image(picture.primary, parameters, true)?;
+        return Ok((mpf::strip(primary)?, warnings));
+    }
+
+    //Gain maps are scaled to the primary image when rendering, but they must share its orientation,
+    //so either all the images are rotated or none is
+    let mut oriented = None;
+    if let Some(orientation) =
+        orientation_transform(get_jpeg_orientation(picture.primary)).filter(|_| !parameters.keep_metadata)
+    {
+        let secondary: Option<Vec<Vec<u8>>> = picture
+            .secondary
+            .iter()
+            .map(|image| perfect_transform(image, &orientation))
+            .collect::<Result<_, _>>()?;
+        if let Some(secondary) = secondary {
+            oriented = auto_orient(picture.primary)?.map(|primary| (primary, secondary));
+        }
+    }
+    let (primary, mut warnings) = compress_image(
+        oriented.as_ref().map_or(picture.primary, |(primary, _)| primary),
+        parameters,
+        false,
+    )?;
+    //The metadata of a gain map holds how to apply it
+    let secondary_parameters = CSParameters {
+        keep_metadata: true,
+        width: 0,
+        height: 0,
+        ..parameters.clone()
+    };
+    let mut secondary = Vec::with_capacity(picture.secondary.len());
+    for (i, image) in picture.secondary.iter().enumerate() {
+        let oriented = oriented.as_ref().map(|(_, secondary)| secondary[i].as_slice());
+        let (compressed, image_warnings) = compress_image(oriented.unwrap_or(image), &secondary_parameters, false)?;
+        warnings.extend(image_warnings);
+        secondary.push(if compressed.len() < image.len() || oriented.is_some() {
+            compressed
+        } else {
+            image.to_vec()
+        });
+    }
+
+    Ok((mpf::join(primary, &picture, secondary)?, warnings))
+}
+
+/// `orient` bakes the EXIF orientation into the pixels when the metadata is stripped, otherwise only the orientation
+/// is kept.
+fn compress_image(
+    in_file: &[u8],
+    parameters: &CSParameters,
+    orient: bool,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    JPEG_WARNINGS.with_borrow_mut(Vec::clear);
+    let mut warnings = vec![];
+    let mut in_file = in_file;
+    if parameters.jpeg.recover_corrupted {
+        if let Some(offset) = find_corruption(in_file) {
+            warnings.push(CaesiumError {
+                message: format!(
+                    "Unexpected marker 0x{:02X} at offset {}, the data after it was dropped",
+                    in_file[offset + 1],
+                    offset
+                ),
+                code: 20118,
+            });
+            in_file = &in_file[..offset];
+        }
+    }
+
+    let output = recompress(in_file, parameters, orient)?;
+    warnings.extend(JPEG_WARNINGS.take());
+    Ok((output, warnings))
+}
+
+fn recompress(in_file: &[u8], parameters: &CSParameters, orient: bool) -> Result<Vec<u8>, CaesiumError> {
+    //Read before the orientation drops the EXIF data, which may be the only place holding the resolution
+    let mut parameters = parameters.clone();
+    parameters.dpi = output_dpi(in_file, &parameters);
+    let parameters = &parameters;
+
+    //Without EXIF the orientation would be lost, so it gets applied to the pixels when no edge has to be trimmed
+    let oriented = if parameters.keep_metadata || !orient {
+        None
+    } else {
+        auto_orient(in_file)?
+    };
+    let output = encode(oriented.as_deref().unwrap_or(in_file), parameters)?;
+    if parameters.keep_metadata || oriented.is_some() {
+        return Ok(output);
+    }
+
+    keep_orientation(output, get_jpeg_orientation(in_file))
+}
+
+fn encode(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let parameters = &apply_source_quality_policy(in_file, parameters);
+
     if parameters.width > 0 || parameters.height > 0 {
-        let mut input = resize(in_file, parameters.width, parameters.height, Jpeg)?;
-        if parameters.keep_metadata || parameters.jpeg.preserve_icc {
-            let (iccp, exif) = extract_metadata(in_file);
+        //The resized image is encoded again before getting here, so the source sampling factors have to be picked now
+        let mut parameters = parameters.clone();
+        if parameters.jpeg.chroma_subsampling == ChromaSubsampling::Preserve {
+            if let Some(subsampling) = inspect(in_file).ok().and_then(|i| i.jpeg?.chroma_subsampling) {
+                parameters.jpeg.chroma_subsampling = subsampling;
+            }
+        }
+        let parameters = &parameters;
+
+        //Resizing always outputs RGB, so CMYK images go through the same conversion as cmyk_to_rgb
+        let cmyk_image = decode_cmyk_to_rgb(in_file)?;
+        let is_cmyk = cmyk_image.is_some();
+        let mut input = match cmyk_image {
+            Some(image) => resize_decoded(image, in_file, parameters.width, parameters.height, Jpeg)?,
+            None => resize(in_file, parameters.width, parameters.height, Jpeg)?,
+        };
+        let convert_to_srgb = parameters.convert_to_srgb && !parameters.jpeg.optimize;
+        if parameters.keep_metadata || parameters.jpeg.preserve_icc || convert_to_srgb || is_cmyk {
+            let (mut iccp, exif) = extract_metadata(in_file);
+            if is_cmyk {
+                iccp = converted_profile(parameters);
+            }
 
             if iccp.is_some() || exif.is_some() {
-                input = save_metadata(
-                    input,
-                    iccp,
-                    exif,
-                    parameters.jpeg.preserve_icc && !parameters.keep_metadata,
-                )?;
+                input = save_metadata(input, iccp, exif, !parameters.keep_metadata)?;
             }
         }
 
@@ -56,6 +207,8 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
             return catch_unwind(|| {
                 if parameters.jpeg.optimize {
                     lossless(&input, parameters)
+                } else if can_requantize(parameters) {
+                    requantize(&input, parameters)
                 } else {
                     lossy(&input, parameters)
                 }
@@ -73,6 +226,8 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
         catch_unwind(|| {
             if parameters.jpeg.optimize {
                 lossless(in_file, parameters)
+            } else if can_requantize(parameters) {
+                requantize(in_file, parameters)
             } else {
                 lossy(in_file, parameters)
             }
@@ -86,6 +241,333 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
     }
 }
 
+/// Finds where the structure of the JPEG breaks, e.g. where another image was appended to a truncated one.
+/// Everything before that point can be decoded, as libjpeg only warns about the premature end of the data.
+fn find_corruption(in_file: &[u8]) -> Option<usize> {
+    let mut pos = 2;
+    let mut in_scan = false;
+    let mut has_frame = false;
+    while pos + 1 < in_file.len() {
+        //Entropy coded data, or garbage between segments that libjpeg skips with a warning
+        if in_file[pos] != 0xFF {
+            pos += 1;
+            continue;
+        }
+
+        let marker = in_file[pos + 1];
+        match marker {
+            0x00 | 0xD0..=0xD7 if in_scan => {
+                pos += 2;
+                continue;
+            }
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            0xD9 => return None,
+            0xC4 | 0xCC | 0xDA | 0xDB | 0xDD | 0xE0..=0xEF | 0xFE => {}
+            0xC0..=0xCF if !has_frame && marker != 0xC8 => has_frame = true,
+            _ => return Some(pos),
+        }
+
+        if pos + 4 > in_file.len() {
+            return Some(pos);
+        }
+        let length = u16::from_be_bytes([in_file[pos + 2], in_file[pos + 3]]) as usize;
+        if length < 2 || pos + 2 + length > in_file.len() {
+            return Some(pos);
+        }
+        in_scan = marker == 0xDA;
+        pos += 2 + length;
+    }
+
+    None
+}
+
+/// Lowers the target quality to the estimated one of the source, or switches to lossless optimization,
+/// as requested by `source_quality_policy`.
+fn apply_source_quality_policy(in_file: &[u8], parameters: &CSParameters) -> CSParameters {
+    let mut parameters = parameters.clone();
+    let policy = parameters.jpeg.source_quality_policy;
+    if parameters.jpeg.optimize || policy == JpegSourceQualityPolicy::Ignore {
+        return parameters;
+    }
+    let Some(source_quality) = estimate_jpeg_quality(in_file) else {
+        return parameters;
+    };
+
+    //Lossless optimization would skip the resize and the color conversions
+    let is_converting =
+        parameters.width > 0 || parameters.height > 0 || parameters.convert_to_srgb || parameters.jpeg.cmyk_to_rgb;
+    let jpeg = &mut parameters.jpeg;
+    if policy == JpegSourceQualityPolicy::Lossless && !is_converting && source_quality <= jpeg.quality {
+        jpeg.optimize = true;
+    } else {
+        jpeg.quality = jpeg.quality.min(source_quality);
+        jpeg.chroma_quality = jpeg.chroma_quality.map(|q| q.min(source_quality));
+    }
+
+    parameters
+}
+
+pub fn transform(in_file: &[u8], transform: &JpegTransform) -> Result<Vec<u8>, CaesiumError> {
+    unsafe {
+        catch_unwind(|| lossless_transform(in_file, transform)).unwrap_or_else(|_| {
+            Err(CaesiumError {
+                message: format!("Internal JPEG error: {}", JPEG_ERROR.load(Ordering::SeqCst)),
+                code: 20104,
+            })
+        })
+    }
+}
+
+fn orientation_transform(orientation: u32) -> Option<JpegTransform> {
+    let transformation = match orientation {
+        2 => JpegTransformation::FlipHorizontal,
+        3 => JpegTransformation::Rotate180,
+        4 => JpegTransformation::FlipVertical,
+        5 => JpegTransformation::Transpose,
+        6 => JpegTransformation::Rotate90,
+        7 => JpegTransformation::Transverse,
+        8 => JpegTransformation::Rotate270,
+        _ => return None,
+    };
+
+    Some(JpegTransform {
+        transformation,
+        crop: None,
+        perfect: true,
+    })
+}
+
+/// Applies a transform that must not trim the edges. Returns `None` if the image has partial edge blocks.
+fn perfect_transform(in_file: &[u8], orientation: &JpegTransform) -> Result<Option<Vec<u8>>, CaesiumError> {
+    match transform(in_file, orientation) {
+        Ok(transformed) => Ok(Some(transformed)),
+        Err(e) if e.code == 20114 => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Bakes the EXIF orientation into the pixels and drops the EXIF data. Returns `None` if there's nothing to do, or if
+/// the rotation would trim the partial edge blocks.
+fn auto_orient(in_file: &[u8]) -> Result<Option<Vec<u8>>, CaesiumError> {
+    let Some(orientation) = orientation_transform(get_jpeg_orientation(in_file)) else {
+        return Ok(None);
+    };
+    let Some(oriented) = perfect_transform(in_file, &orientation)? else {
+        return Ok(None);
+    };
+
+    let mut oriented = PartsJpeg::from_bytes(Bytes::from(oriented)).map_err(|_| CaesiumError {
+        message: "Failed to parse JPEG after orientation".to_string(),
+        code: 20115,
+    })?;
+    oriented.set_exif(None);
+    let mut result = vec![];
+    oriented.encoder().write_to(&mut result).map_err(|_| CaesiumError {
+        message: "Failed to encode JPEG after orientation".to_string(),
+        code: 20116,
+    })?;
+
+    Ok(Some(result))
+}
+
+/// Writes EXIF data only holding `orientation` to a JPEG whose metadata was stripped.
+fn keep_orientation(jpeg: Vec<u8>, orientation: u32) -> Result<Vec<u8>, CaesiumError> {
+    if orientation_transform(orientation).is_none() {
+        return Ok(jpeg);
+    }
+
+    let mut jpeg = PartsJpeg::from_bytes(Bytes::from(jpeg)).map_err(|_| CaesiumError {
+        message: "Failed to parse JPEG after orientation".to_string(),
+        code: 20115,
+    })?;
+    jpeg.set_exif(Some(orientation_exif(orientation as u16)));
+    let mut result = vec![];
+    jpeg.encoder().write_to(&mut result).map_err(|_| CaesiumError {
+        message: "Failed to encode JPEG after orientation".to_string(),
+        code: 20116,
+    })?;
+
+    Ok(result)
+}
+
+/// Minimal little endian EXIF data only holding the orientation.
+fn orientation_exif(orientation: u16) -> Bytes {
+    let mut exif = b"II*\0".to_vec();
+    exif.extend_from_slice(&8u32.to_le_bytes());
+    exif.extend_from_slice(&1u16.to_le_bytes());
+    //Orientation, SHORT stored inline
+    exif.extend_from_slice(&0x0112u16.to_le_bytes());
+    exif.extend_from_slice(&3u16.to_le_bytes());
+    exif.extend_from_slice(&1u32.to_le_bytes());
+    exif.extend_from_slice(&orientation.to_le_bytes());
+    exif.extend_from_slice(&0u16.to_le_bytes());
+    exif.extend_from_slice(&0u32.to_le_bytes());
+
+    Bytes::from(exif)
+}
+
+/// Decodes a CMYK/YCCK JPEG to sRGB, through its ICC profile when present. Returns `None` for other color spaces.
+pub fn decode_cmyk_to_rgb(in_file: &[u8]) -> Result<Option<DynamicImage>, CaesiumError> {
+    unsafe {
+        catch_unwind(|| decode_cmyk(in_file)).unwrap_or_else(|_| {
+            Err(CaesiumError {
+                message: format!("Internal JPEG error: {}", JPEG_ERROR.load(Ordering::SeqCst)),
+                code: 20104,
+            })
+        })
+    }
+}
+
+unsafe fn decode_cmyk(in_file: &[u8]) -> Result<Option<DynamicImage>, CaesiumError> {
+    let mut src_info: jpeg_decompress_struct = mem::zeroed();
+    let mut src_err = mem::zeroed();
+
+    src_info.common.err = jpeg_std_error(&mut src_err);
+    (*src_info.common.err).error_exit = Some(error_handler);
+    (*src_info.common.err).output_message = Some(error_message_handler);
+    (*src_info.common.err).emit_message = Some(warning_handler);
+
+    jpeg_create_decompress(&mut src_info);
+    jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
+    jpeg_save_markers(&mut src_info, ADOBE_MARKER, 0xFFFF);
+    jpeg_read_header(&mut src_info, true as boolean);
+
+    if !matches!(src_info.jpeg_color_space, JCS_CMYK | JCS_YCCK) {
+        jpeg_destroy_decompress(&mut src_info);
+        return Ok(None);
+    }
+
+    src_info.out_color_space = JCS_CMYK;
+    jpeg_start_decompress(&mut src_info);
+    let width = src_info.output_width;
+    let height = src_info.output_height;
+    let row_stride = width as usize * src_info.output_components as usize;
+    let mut buffer = vec![0u8; row_stride * height as usize];
+
+    while src_info.output_scanline < src_info.output_height {
+        let offset = src_info.output_scanline as usize * row_stride;
+        let mut jsamparray = [buffer[offset..].as_mut_ptr()];
+        jpeg_read_scanlines(&mut src_info, jsamparray.as_mut_ptr(), 1);
+    }
+    let inverted = adobe_marker(&src_info).is_some();
+
+    jpeg_finish_decompress(&mut src_info);
+    jpeg_destroy_decompress(&mut src_info);
+
+    let rgb = convert_cmyk_to_srgb(&buffer, inverted, extract_metadata(in_file).0.as_deref())?;
+    let image = RgbImage::from_raw(width, height, rgb).ok_or(CaesiumError {
+        message: "Cannot create image buffer after CMYK conversion".into(),
+        code: 20112,
+    })?;
+
+    Ok(Some(DynamicImage::ImageRgb8(image)))
+}
+
+unsafe fn lossless_transform(in_file: &[u8], transform: &JpegTransform) -> Result<Vec<u8>, CaesiumError> {
+    let mut src_info: jpeg_decompress_struct = mem::zeroed();
+    let mut src_err = mem::zeroed();
+    let mut dst_info: jpeg_compress_struct = mem::zeroed();
+    let mut dst_err = mem::zeroed();
+
+    src_info.common.err = jpeg_std_error(&mut src_err);
+    (*src_info.common.err).error_exit = Some(error_handler);
+    (*src_info.common.err).output_message = Some(error_message_handler);
+    (*src_info.common.err).emit_message = Some(warning_handler);
+
+    dst_info.common.err = jpeg_std_error(&mut dst_err);
+    (*dst_info.common.err).error_exit = Some(error_handler);
+    (*dst_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_decompress(&mut src_info);
+    jpeg_create_compress(&mut dst_info);
+
+    jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
+    jcopy_markers_setup(&mut src_info, JCOPY_OPTION_JCOPYOPT_ALL);
+    jpeg_read_header(&mut src_info, true as boolean);
+
+    let mut info: jpeg_transform_info = mem::zeroed();
+    info.transform = match transform.transformation {
+        JpegTransformation::None => JXFORM_CODE_JXFORM_NONE,
+        JpegTransformation::FlipHorizontal => JXFORM_CODE_JXFORM_FLIP_H,
+        JpegTransformation::FlipVertical => JXFORM_CODE_JXFORM_FLIP_V,
+        JpegTransformation::Transpose => JXFORM_CODE_JXFORM_TRANSPOSE,
+        JpegTransformation::Transverse => JXFORM_CODE_JXFORM_TRANSVERSE,
+        JpegTransformation::Rotate90 => JXFORM_CODE_JXFORM_ROT_90,
+        JpegTransformation::Rotate180 => JXFORM_CODE_JXFORM_ROT_180,
+        JpegTransformation::Rotate270 => JXFORM_CODE_JXFORM_ROT_270,
+    };
+    info.perfect = transform.perfect as boolean;
+    info.trim = !transform.perfect as boolean;
+
+    if let Some(crop) = transform.crop {
+        let (width, height) = match transform.transformation {
+            JpegTransformation::Transpose
+            | JpegTransformation::Transverse
+            | JpegTransformation::Rotate90
+            | JpegTransformation::Rotate270 => (src_info.image_height, src_info.image_width),
+            _ => (src_info.image_width, src_info.image_height),
+        };
+        if crop.width == 0
+            || crop.height == 0
+            || crop.x.saturating_add(crop.width) > width
+            || crop.y.saturating_add(crop.height) > height
+        {
+            jpeg_destroy_decompress(&mut src_info);
+            jpeg_destroy_compress(&mut dst_info);
+            return Err(CaesiumError {
+                message: "Crop region is outside the image".to_string(),
+                code: 20113,
+            });
+        }
+        info.crop = true as boolean;
+        info.crop_width = crop.width;
+        info.crop_width_set = JCROP_CODE_JCROP_POS;
+        info.crop_height = crop.height;
+        info.crop_height_set = JCROP_CODE_JCROP_POS;
+        info.crop_xoffset = crop.x;
+        info.crop_xoffset_set = JCROP_CODE_JCROP_POS;
+        info.crop_yoffset = crop.y;
+        info.crop_yoffset_set = JCROP_CODE_JCROP_POS;
+    }
+
+    if jtransform_request_workspace(&mut src_info, &mut info) == 0 {
+        jpeg_destroy_decompress(&mut src_info);
+        jpeg_destroy_compress(&mut dst_info);
+        return Err(CaesiumError {
+            message: "Transformation is not perfect, the image has partial edge blocks".to_string(),
+            code: 20114,
+        });
+    }
+
+    let src_coef_arrays = jpeg_read_coefficients(&mut src_info);
+    jpeg_copy_critical_parameters(&src_info, &mut dst_info);
+    let dst_coef_arrays = jtransform_adjust_parameters(&mut src_info, &mut dst_info, src_coef_arrays, &mut info);
+
+    dst_info.optimize_coding = i32::from(true);
+    let mut buf = ptr::null_mut();
+    let mut buf_size = 0;
+    jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
+    jpeg_write_coefficients(&mut dst_info, dst_coef_arrays);
+    jcopy_markers_execute(&mut src_info, &mut dst_info, JCOPY_OPTION_JCOPYOPT_ALL);
+    jtransform_execute_transform(&mut src_info, &mut dst_info, src_coef_arrays, &mut info);
+
+    jpeg_finish_compress(&mut dst_info);
+    jpeg_destroy_compress(&mut dst_info);
+    jpeg_finish_decompress(&mut src_info);
+    jpeg_destroy_decompress(&mut src_info);
+
+    let slice = std::slice::from_raw_parts(buf, buf_size as usize);
+
+    let result = slice.to_vec();
+
+    free(buf as *mut c_void);
+
+    Ok(result)
+}
+
 unsafe fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
     let mut src_info: jpeg_decompress_struct = mem::zeroed();
 
@@ -96,6 +578,7 @@ unsafe fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>,
     src_info.common.err = jpeg_std_error(&mut src_err);
     (*src_info.common.err).error_exit = Some(error_handler);
     (*src_info.common.err).output_message = Some(error_message_handler);
+    (*src_info.common.err).emit_message = Some(warning_handler);
 
     dst_info.common.err = jpeg_std_error(&mut dst_err);
     (*dst_info.common.err).error_exit = Some(error_handler);
@@ -112,19 +595,22 @@ unsafe fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>,
 
     let src_coef_arrays = jpeg_read_coefficients(&mut src_info);
     jpeg_copy_critical_parameters(&src_info, &mut dst_info);
+    set_density(&mut dst_info, parameters.dpi);
+    set_entropy_coding(&mut dst_info, parameters);
     let dst_coef_arrays = src_coef_arrays;
 
-    dst_info.optimize_coding = i32::from(true);
+    //Arithmetic coding is adaptive and has no Huffman tables to optimize
+    dst_info.optimize_coding = i32::from(!parameters.jpeg.arithmetic_coding);
     let mut buf = ptr::null_mut();
     let mut buf_size = 0;
     jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
-    if !parameters.jpeg.progressive {
-        dst_info.scan_info = null();
-    }
+    set_scan_script(&mut dst_info, parameters);
+    let adobe = take_over_adobe_marker(&src_info, &mut dst_info);
     jpeg_write_coefficients(&mut dst_info, dst_coef_arrays);
+    write_adobe_marker(&mut dst_info, adobe);
 
     if parameters.keep_metadata || parameters.jpeg.preserve_icc {
-        write_metadata(&mut src_info, &mut dst_info);
+        write_metadata(&mut src_info, &mut dst_info, false);
     }
 
     jpeg_finish_compress(&mut dst_info);
@@ -141,6 +627,125 @@ unsafe fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>,
     Ok(result)
 }
 
+//Color conversions need the pixels, so they always go through a full re-encoding
+fn can_requantize(parameters: &CSParameters) -> bool {
+    parameters.jpeg.requantize && !parameters.convert_to_srgb && !parameters.jpeg.cmyk_to_rgb
+}
+
+/// Lowers the quality working on the DCT coefficients, scaling them to the quantization tables of the target quality.
+/// Tables are never made finer than the source ones.
+unsafe fn requantize(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let mut src_info: jpeg_decompress_struct = mem::zeroed();
+    let mut src_err = mem::zeroed();
+    let mut dst_info: jpeg_compress_struct = mem::zeroed();
+    let mut dst_err = mem::zeroed();
+
+    src_info.common.err = jpeg_std_error(&mut src_err);
+    (*src_info.common.err).error_exit = Some(error_handler);
+    (*src_info.common.err).output_message = Some(error_message_handler);
+    (*src_info.common.err).emit_message = Some(warning_handler);
+
+    dst_info.common.err = jpeg_std_error(&mut dst_err);
+    (*dst_info.common.err).error_exit = Some(error_handler);
+    (*dst_info.common.err).output_message = Some(error_message_handler);
+
+    jpeg_create_decompress(&mut src_info);
+    jpeg_create_compress(&mut dst_info);
+
+    jpeg_mem_src(&mut src_info, in_file.as_ptr(), in_file.len() as _);
+
+    save_markers(&mut src_info, parameters);
+
+    jpeg_read_header(&mut src_info, true as boolean);
+
+    let coef_arrays = jpeg_read_coefficients(&mut src_info);
+    jpeg_copy_critical_parameters(&src_info, &mut dst_info);
+    set_density(&mut dst_info, parameters.dpi);
+
+    let mut source_tables = [[0u16; 64]; 4];
+    for (slot, table) in source_tables.iter_mut().enumerate() {
+        if !dst_info.quant_tbl_ptrs[slot].is_null() {
+            *table = (*dst_info.quant_tbl_ptrs[slot]).quantval;
+        }
+    }
+
+    set_tuning(&mut dst_info, parameters);
+    //Trellis needs the unquantized DCT output, which is not available when transcoding
+    jpeg_c_set_bool_param(&mut dst_info, JBOOLEAN_TRELLIS_QUANT, false as boolean);
+    jpeg_c_set_bool_param(&mut dst_info, JBOOLEAN_TRELLIS_QUANT_DC, false as boolean);
+    set_entropy_coding(&mut dst_info, parameters);
+    set_quality(&mut dst_info, parameters);
+    let luma_table = (*dst_info.quant_tbl_ptrs[0]).quantval;
+    let chroma_table = (*dst_info.quant_tbl_ptrs[1]).quantval;
+    for (slot, source_table) in source_tables.iter().enumerate() {
+        if dst_info.quant_tbl_ptrs[slot].is_null() || *source_table == [0; 64] {
+            continue;
+        }
+        //Tables past the first chroma one are not touched by jpeg_set_quality
+        let target = if slot == 0 { &luma_table } else { &chroma_table };
+        let quantval = &mut (*dst_info.quant_tbl_ptrs[slot]).quantval;
+        for k in 0..64 {
+            quantval[k] = target[k].max(source_table[k]);
+        }
+    }
+
+    for ci in 0..src_info.num_components as usize {
+        let component = &*src_info.comp_info.add(ci);
+        let source_table = &source_tables[component.quant_tbl_no as usize];
+        let table = &(*dst_info.quant_tbl_ptrs[component.quant_tbl_no as usize]).quantval;
+        if source_table == table {
+            continue;
+        }
+
+        let access_virt_barray = (*src_info.common.mem).access_virt_barray.unwrap();
+        for row in 0..component.height_in_blocks {
+            let blocks = *access_virt_barray(&mut src_info.common, *coef_arrays.add(ci), row, 1, true as boolean);
+            for block in std::slice::from_raw_parts_mut(blocks, component.width_in_blocks as usize) {
+                for k in 0..64 {
+                    block[k] = requantize_coefficient(block[k], source_table[k], table[k]);
+                }
+            }
+        }
+    }
+
+    dst_info.optimize_coding = i32::from(!parameters.jpeg.arithmetic_coding);
+    let mut buf = ptr::null_mut();
+    let mut buf_size = 0;
+    jpeg_mem_dest(&mut dst_info, &mut buf, &mut buf_size);
+    set_scan_script(&mut dst_info, parameters);
+    let adobe = take_over_adobe_marker(&src_info, &mut dst_info);
+    jpeg_write_coefficients(&mut dst_info, coef_arrays);
+    write_adobe_marker(&mut dst_info, adobe);
+
+    if parameters.keep_metadata || parameters.jpeg.preserve_icc {
+        write_metadata(&mut src_info, &mut dst_info, false);
+    }
+
+    jpeg_finish_compress(&mut dst_info);
+    jpeg_destroy_compress(&mut dst_info);
+    jpeg_finish_decompress(&mut src_info);
+    jpeg_destroy_decompress(&mut src_info);
+
+    let slice = std::slice::from_raw_parts(buf, buf_size as usize);
+
+    let result = slice.to_vec();
+
+    free(buf as *mut c_void);
+
+    Ok(result)
+}
+
+fn requantize_coefficient(coefficient: JCOEF, source_step: u16, target_step: u16) -> JCOEF {
+    if source_step == target_step {
+        return coefficient;
+    }
+    let value = coefficient as i32 * source_step as i32;
+    let target_step = target_step as i32;
+    let rounded = (value.abs() + target_step / 2) / target_step;
+
+    (rounded * value.signum()) as JCOEF
+}
+
 unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
     let mut src_info: jpeg_decompress_struct = mem::zeroed();
     let mut src_err = mem::zeroed();
@@ -150,6 +755,7 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
     src_info.common.err = jpeg_std_error(&mut src_err);
     (*src_info.common.err).error_exit = Some(error_handler);
     (*src_info.common.err).output_message = Some(error_message_handler);
+    (*src_info.common.err).emit_message = Some(warning_handler);
 
     dst_info.common.err = jpeg_std_error(&mut dst_err);
     (*dst_info.common.err).error_exit = Some(error_handler);
@@ -167,7 +773,22 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
     let width = src_info.image_width;
     let height = src_info.image_height;
     let color_space = src_info.jpeg_color_space;
-    src_info.out_color_space = color_space;
+    let cmyk_to_rgb = parameters.jpeg.cmyk_to_rgb && matches!(color_space, JCS_CMYK | JCS_YCCK);
+    let source_icc = if (parameters.convert_to_srgb && matches!(color_space, JCS_YCbCr | JCS_RGB)) || cmyk_to_rgb {
+        extract_metadata(in_file).0
+    } else {
+        None
+    };
+    let convert_icc = source_icc
+        .clone()
+        .filter(|icc| !cmyk_to_rgb && needs_srgb_conversion(Some(icc)));
+    src_info.out_color_space = if cmyk_to_rgb {
+        JCS_CMYK
+    } else if convert_icc.is_some() {
+        JCS_RGB
+    } else {
+        color_space
+    };
     jpeg_start_decompress(&mut src_info);
     let row_stride = src_info.image_width as usize * src_info.output_components as usize;
     let buffer_size = row_stride * src_info.image_height as usize;
@@ -180,7 +801,39 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
         jpeg_read_scanlines(&mut src_info, jsamparray.as_mut_ptr(), 1);
     }
 
-    let input_components = match color_space {
+    let converted = if cmyk_to_rgb {
+        convert_cmyk_to_srgb(&buffer, adobe_marker(&src_info).is_some(), source_icc.as_deref()).map(|rgb| buffer = rgb)
+    } else if let Some(icc) = &convert_icc {
+        convert_buffer_to_srgb(&mut buffer, false, icc)
+    } else {
+        Ok(())
+    };
+    if let Err(e) = converted {
+        jpeg_destroy_decompress(&mut src_info);
+        jpeg_destroy_compress(&mut dst_info);
+        return Err(e);
+    }
+
+    let mut in_color_space = if cmyk_to_rgb || convert_icc.is_some() {
+        JCS_RGB
+    } else {
+        color_space
+    };
+    let grayscale = matches!(in_color_space, JCS_RGB | JCS_YCbCr)
+        && encode_as_grayscale(parameters, false, |tolerance| match in_color_space {
+            JCS_YCbCr => buffer
+                .chunks_exact(3)
+                .all(|p| p[1].abs_diff(128) <= tolerance && p[2].abs_diff(128) <= tolerance),
+            _ => is_neutral(&buffer, 3, tolerance as u32),
+        });
+    if grayscale {
+        buffer = match in_color_space {
+            JCS_YCbCr => buffer.chunks_exact(3).map(|p| p[0]).collect(),
+            _ => buffer.chunks_exact(3).map(|p| luma(p[0], p[1], p[2])).collect(),
+        };
+        in_color_space = JCS_GRAYSCALE;
+    }
+    let input_components = match in_color_space {
         JCS_GRAYSCALE => 1,
         JCS_RGB => 3,
         JCS_YCbCr => 3,
@@ -194,27 +847,52 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
 
     dst_info.image_width = width;
     dst_info.image_height = height;
-    dst_info.in_color_space = color_space;
+    dst_info.in_color_space = in_color_space;
     dst_info.input_components = input_components as c_int;
+    if parameters.jpeg.profile == JpegProfile::Fastest {
+        jpeg_c_set_int_param(&mut dst_info, JINT_COMPRESS_PROFILE, JCP_FASTEST as c_int);
+    }
     jpeg_set_defaults(&mut dst_info);
+    set_density(&mut dst_info, parameters.dpi);
+    set_tuning(&mut dst_info, parameters);
+    set_entropy_coding(&mut dst_info, parameters);
 
-    if input_components == 3 && parameters.jpeg.chroma_subsampling != ChromaSubsampling::Auto {
-        set_chroma_subsampling(parameters.jpeg.chroma_subsampling, &mut dst_info);
+    if input_components == 3 {
+        match parameters.jpeg.chroma_subsampling {
+            ChromaSubsampling::Preserve => copy_sampling_factors(&src_info, &mut dst_info),
+            ChromaSubsampling::Auto => {
+                //jpeg_set_defaults already picks 4:2:0
+                if has_sharp_chroma_edges(&buffer, width as usize, height as usize, in_color_space) {
+                    set_chroma_subsampling(ChromaSubsampling::CS444, &mut dst_info);
+                }
+            }
+            subsampling => set_chroma_subsampling(subsampling, &mut dst_info),
+        }
     }
 
     let row_stride = dst_info.image_width as usize * dst_info.input_components as usize;
     dst_info.dct_method = J_DCT_METHOD::JDCT_ISLOW;
-    dst_info.optimize_coding = i32::from(true);
-    jpeg_set_quality(&mut dst_info, parameters.jpeg.quality as i32, false as boolean);
+    dst_info.optimize_coding = i32::from(!parameters.jpeg.arithmetic_coding);
+    set_quality(&mut dst_info, parameters);
 
-    if !parameters.jpeg.progressive {
-        dst_info.scan_info = null();
-    }
+    set_scan_script(&mut dst_info, parameters);
 
+    let adobe = take_over_adobe_marker(&src_info, &mut dst_info);
     jpeg_start_compress(&mut dst_info, true as boolean);
+    write_adobe_marker(&mut dst_info, adobe);
 
+    //A color profile does not apply to gray data
     if parameters.keep_metadata || parameters.jpeg.preserve_icc {
-        write_metadata(&mut src_info, &mut dst_info);
+        write_metadata(
+            &mut src_info,
+            &mut dst_info,
+            convert_icc.is_some() || cmyk_to_rgb || grayscale,
+        );
+    }
+    if (convert_icc.is_some() || cmyk_to_rgb) && !grayscale {
+        if let Some(profile) = converted_profile(parameters) {
+            write_icc_profile(&mut dst_info, &profile);
+        }
     }
 
     while dst_info.next_scanline < dst_info.image_height {
@@ -237,7 +915,128 @@ unsafe fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Ca
     Ok(result)
 }
 
+/// Sets the quantization tables for the requested quality, scaling the chroma ones on their own when `chroma_quality` is set.
+unsafe fn set_quality(dst_info: &mut jpeg_compress_struct, parameters: &CSParameters) {
+    if let Some(chroma_quality) = parameters.jpeg.chroma_quality {
+        jpeg_set_quality(dst_info, chroma_quality as i32, false as boolean);
+        let chroma_table = (*dst_info.quant_tbl_ptrs[1]).quantval;
+        jpeg_set_quality(dst_info, parameters.jpeg.quality as i32, false as boolean);
+        (*dst_info.quant_tbl_ptrs[1]).quantval = chroma_table;
+    } else {
+        jpeg_set_quality(dst_info, parameters.jpeg.quality as i32, false as boolean);
+    }
+}
+
+//Must be called after the defaults are set and before the quality, which depends on the quantization tables
+unsafe fn set_tuning(dst_info: &mut jpeg_compress_struct, parameters: &CSParameters) {
+    let jpeg = &parameters.jpeg;
+    dst_info.smoothing_factor = jpeg.smoothing as c_int;
+    if jpeg.profile == JpegProfile::Fastest {
+        return;
+    }
+
+    jpeg_c_set_int_param(dst_info, JINT_BASE_QUANT_TBL_IDX, jpeg.quant_table as c_int);
+    jpeg_c_set_bool_param(dst_info, JBOOLEAN_TRELLIS_QUANT, jpeg.trellis as boolean);
+    jpeg_c_set_bool_param(dst_info, JBOOLEAN_TRELLIS_QUANT_DC, jpeg.trellis as boolean);
+    jpeg_c_set_bool_param(
+        dst_info,
+        JBOOLEAN_OVERSHOOT_DERINGING,
+        jpeg.overshoot_deringing as boolean,
+    );
+
+    //Same values as cjpeg -tune-*
+    let (lambda_log_scale1, lambda_log_scale2, use_lambda_weight_tbl) = match jpeg.tune {
+        JpegTune::PsnrHvs => (14.75, 16.5, true),
+        JpegTune::Psnr => (9.0, 0.0, false),
+        JpegTune::Ssim => (11.5, 12.75, false),
+        JpegTune::MsSsim => (12.0, 13.0, true),
+    };
+    jpeg_c_set_float_param(dst_info, JFLOAT_LAMBDA_LOG_SCALE1, lambda_log_scale1);
+    jpeg_c_set_float_param(dst_info, JFLOAT_LAMBDA_LOG_SCALE2, lambda_log_scale2);
+    jpeg_c_set_bool_param(
+        dst_info,
+        JBOOLEAN_USE_LAMBDA_WEIGHT_TBL,
+        use_lambda_weight_tbl as boolean,
+    );
+}
+
+/// Sets the resolution written in the JFIF header. Without one only the default 1:1 aspect ratio is written.
+unsafe fn set_density(dst_info: &mut jpeg_compress_struct, dpi: Option<Dpi>) {
+    match dpi {
+        Some(dpi) => {
+            dst_info.density_unit = 1;
+            dst_info.X_density = dpi.x.min(u16::MAX as u32) as u16;
+            dst_info.Y_density = dpi.y.min(u16::MAX as u32) as u16;
+        }
+        None => {
+            dst_info.density_unit = 0;
+            dst_info.X_density = 1;
+            dst_info.Y_density = 1;
+        }
+    }
+}
+
+unsafe fn set_entropy_coding(dst_info: &mut jpeg_compress_struct, parameters: &CSParameters) {
+    dst_info.restart_interval = parameters.jpeg.restart_interval;
+    dst_info.arith_code = parameters.jpeg.arithmetic_coding as boolean;
+}
+
+unsafe fn set_scan_script(dst_info: &mut jpeg_compress_struct, parameters: &CSParameters) {
+    let jpeg = &parameters.jpeg;
+    if let Some(scan_script) = &jpeg.scan_script {
+        jpeg_c_set_bool_param(dst_info, JBOOLEAN_OPTIMIZE_SCANS, false as boolean);
+        set_custom_scan_script(dst_info, scan_script);
+        return;
+    }
+
+    if !jpeg.progressive {
+        dst_info.scan_info = null();
+        return;
+    }
+
+    //The optimized mozjpeg scans only work with Huffman coding
+    let optimize_scans = jpeg.optimize_scans && jpeg.profile == JpegProfile::MaxCompression && !jpeg.arithmetic_coding;
+    jpeg_c_set_bool_param(dst_info, JBOOLEAN_OPTIMIZE_SCANS, optimize_scans as boolean);
+    if optimize_scans {
+        jpeg_c_set_int_param(dst_info, JINT_DC_SCAN_OPT_MODE, jpeg.dc_scan_mode as c_int);
+    }
+    jpeg_simple_progression(dst_info);
+}
+
+/// The script is validated by libjpeg when the compression starts.
+unsafe fn set_custom_scan_script(dst_info: &mut jpeg_compress_struct, scan_script: &[JpegScan]) {
+    let alloc_small = (*dst_info.common.mem).alloc_small.unwrap();
+    //Allocated in the permanent pool, as libjpeg reads it up to the end of the compression
+    let scan_info = alloc_small(
+        &mut dst_info.common,
+        JPOOL_PERMANENT,
+        scan_script.len() * mem::size_of::<jpeg_scan_info>(),
+    ) as *mut jpeg_scan_info;
+    for (i, scan) in scan_script.iter().enumerate() {
+        let mut info = jpeg_scan_info {
+            comps_in_scan: scan.component_count as c_int,
+            Ss: scan.spectral_start as c_int,
+            Se: scan.spectral_end as c_int,
+            Ah: scan.approx_high as c_int,
+            Al: scan.approx_low as c_int,
+            ..Default::default()
+        };
+        for (index, component) in info
+            .component_index
+            .iter_mut()
+            .zip(&scan.components[..scan.component_count as usize])
+        {
+            *index = *component as c_int;
+        }
+        *scan_info.add(i) = info;
+    }
+    dst_info.scan_info = scan_info;
+    dst_info.num_scans = scan_script.len() as c_int;
+}
+
 unsafe fn save_markers(src_info: &mut jpeg_decompress_struct, parameters: &CSParameters) {
+    //The Adobe marker tells how to decode CMYK/YCCK and RGB data, so it's always kept
+    jpeg_save_markers(src_info, ADOBE_MARKER, 0xFFFF);
     if parameters.keep_metadata {
         jpeg_save_markers(src_info, 0xFE, 0xFFFF);
         for m in 0..16 {
@@ -288,13 +1087,72 @@ fn save_metadata(
     Ok(image_with_metadata)
 }
 
-unsafe fn write_metadata(src_info: &mut jpeg_decompress_struct, dst_info: &mut jpeg_compress_struct) {
+const ICC_MARKER_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+const JFIF_MARKER_SIGNATURE: &[u8] = b"JFIF\0";
+const ADOBE_MARKER: i32 = 0xEE;
+const JMSG_LENGTH_MAX: usize = 200;
+const ADOBE_MARKER_SIGNATURE: &[u8] = b"Adobe";
+const CHROMA_EDGE_THRESHOLD: i32 = 96;
+const CHROMA_EDGE_RATIO: f64 = 0.005;
+
+unsafe fn write_metadata(src_info: &mut jpeg_decompress_struct, dst_info: &mut jpeg_compress_struct, skip_icc: bool) {
+    let mut marker = src_info.marker_list;
+
+    while !marker.is_null() {
+        let data = std::slice::from_raw_parts((*marker).data, (*marker).data_length as usize);
+        let is_icc = (*marker).marker == 0xE2 && data.starts_with(ICC_MARKER_SIGNATURE);
+        //libjpeg writes its own JFIF header, holding the output resolution
+        let is_jfif = (*marker).marker == 0xE0 && data.starts_with(JFIF_MARKER_SIGNATURE);
+        if !(is_jfif || skip_icc && is_icc) && (*marker).marker as i32 != ADOBE_MARKER {
+            jpeg_write_marker(dst_info, (*marker).marker as i32, (*marker).data, (*marker).data_length);
+        }
+        marker = (*marker).next;
+    }
+}
+
+unsafe fn adobe_marker(src_info: &jpeg_decompress_struct) -> Option<&[u8]> {
     let mut marker = src_info.marker_list;
 
     while !marker.is_null() {
-        jpeg_write_marker(dst_info, (*marker).marker as i32, (*marker).data, (*marker).data_length);
+        let data = std::slice::from_raw_parts((*marker).data, (*marker).data_length as usize);
+        if (*marker).marker as i32 == ADOBE_MARKER && data.len() >= 12 && data.starts_with(ADOBE_MARKER_SIGNATURE) {
+            return Some(data);
+        }
         marker = (*marker).next;
     }
+
+    None
+}
+
+/// Replaces the Adobe marker libjpeg would write with the source one, so its version and flags are kept.
+/// The transform flag is updated to match the output color space. Must be called before writing the headers.
+unsafe fn take_over_adobe_marker(
+    src_info: &jpeg_decompress_struct,
+    dst_info: &mut jpeg_compress_struct,
+) -> Option<Vec<u8>> {
+    let mut data = adobe_marker(src_info)?.to_vec();
+    data[11] = match dst_info.jpeg_color_space {
+        JCS_YCbCr => 1,
+        JCS_YCCK => 2,
+        _ => 0,
+    };
+    dst_info.write_Adobe_marker = false as boolean;
+
+    Some(data)
+}
+
+unsafe fn write_adobe_marker(dst_info: &mut jpeg_compress_struct, adobe: Option<Vec<u8>>) {
+    if let Some(data) = adobe {
+        jpeg_write_marker(dst_info, ADOBE_MARKER, data.as_ptr(), data.len() as c_uint);
+    }
+}
+
+//The sRGB profile is small enough to always fit in a single APP2 segment
+unsafe fn write_icc_profile(dst_info: &mut jpeg_compress_struct, profile: &[u8]) {
+    let mut data = ICC_MARKER_SIGNATURE.to_vec();
+    data.extend_from_slice(&[1, 1]);
+    data.extend_from_slice(profile);
+    jpeg_write_marker(dst_info, 0xE2, data.as_ptr(), data.len() as c_uint);
 }
 
 unsafe fn set_chroma_subsampling(subsampling: ChromaSubsampling, dst_info: &mut jpeg_compress_struct) {
@@ -323,9 +1181,83 @@ unsafe fn set_chroma_subsampling(subsampling: ChromaSubsampling, dst_info: &mut
     }
 }
 
+unsafe fn copy_sampling_factors(src_info: &jpeg_decompress_struct, dst_info: &mut jpeg_compress_struct) {
+    let components = src_info.num_components.min(dst_info.num_components) as usize;
+    for ci in 0..components {
+        let src_component = &*src_info.comp_info.add(ci);
+        let dst_component = &mut *dst_info.comp_info.add(ci);
+        dst_component.h_samp_factor = src_component.h_samp_factor;
+        dst_component.v_samp_factor = src_component.v_samp_factor;
+    }
+}
+
+/// Whether enough neighbouring pixels have a sharp chroma transition for 4:2:0 to visibly bleed colors.
+/// Photos rarely have such steps, while colored text and UI elements in screenshots are made of them.
+fn has_sharp_chroma_edges(buffer: &[u8], width: usize, height: usize, color_space: J_COLOR_SPACE) -> bool {
+    if width < 2 || height < 2 {
+        return false;
+    }
+
+    let chroma = |i: usize| {
+        let (a, b, c) = (buffer[i * 3] as i32, buffer[i * 3 + 1] as i32, buffer[i * 3 + 2] as i32);
+        if color_space == JCS_YCbCr {
+            (b, c)
+        } else {
+            //BT.601 Cb and Cr, as written by libjpeg, scaled by 2^16
+            (
+                (-11059 * a - 21709 * b + 32768 * c) >> 16,
+                (32768 * a - 27439 * b - 5329 * c) >> 16,
+            )
+        }
+    };
+    let is_edge = |a: (i32, i32), b: (i32, i32)| (a.0 - b.0).abs() + (a.1 - b.1).abs() > CHROMA_EDGE_THRESHOLD;
+
+    let max_edges = (CHROMA_EDGE_RATIO * (2 * (width - 1) * (height - 1)) as f64) as usize;
+    let mut edges = 0;
+    for y in 0..height - 1 {
+        let mut current = chroma(y * width);
+        for x in 0..width - 1 {
+            let i = y * width + x;
+            let right = chroma(i + 1);
+            edges += is_edge(current, right) as usize + is_edge(current, chroma(i + width)) as usize;
+            current = right;
+        }
+        if edges > max_edges {
+            return true;
+        }
+    }
+
+    false
+}
+
 unsafe extern "C-unwind" fn error_handler(cinfo: &mut jpeg_common_struct) {
     JPEG_ERROR.store((*cinfo.err).msg_code, Ordering::SeqCst);
     panic!("Internal JPEG error: {}", JPEG_ERROR.load(Ordering::SeqCst));
 }
 
+/// Collects the warnings, libjpeg uses them for the recoverable issues of the input, like corrupted or missing data.
+/// The other levels are trace messages.
+unsafe extern "C-unwind" fn warning_handler(cinfo: &mut jpeg_common_struct, msg_level: c_int) {
+    if msg_level != -1 {
+        return;
+    }
+
+    let err = &mut *cinfo.err;
+    err.num_warnings += 1;
+    //The bindings declare the buffer as shared and too small, but libjpeg writes up to JMSG_LENGTH_MAX bytes in it
+    let format_message: unsafe extern "C-unwind" fn(&mut jpeg_common_struct, *mut u8) =
+        mem::transmute(err.format_message.unwrap());
+    let mut buffer = [0u8; JMSG_LENGTH_MAX];
+    format_message(cinfo, buffer.as_mut_ptr());
+    let message = CStr::from_bytes_until_nul(&buffer)
+        .map(|m| m.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    JPEG_WARNINGS.with_borrow_mut(|warnings| {
+        //Corrupted data usually triggers the same warning many times
+        if !warnings.iter().any(|w| w.message == message) {
+            warnings.push(CaesiumError { message, code: 20117 });
+        }
+    });
+}
+
 unsafe extern "C-unwind" fn error_message_handler(_cinfo: &mut jpeg_common_struct) {}
@@ -4,19 +4,28 @@ use std::fs;
 use std::fs::File;
 use std::io::Write;
 
+use crate::info::ImageInfo;
+#[cfg(feature = "jpg")]
+use crate::parameters::JpegTransform;
 #[cfg(feature = "tiff")]
 use crate::parameters::TiffCompression::{Deflate, Lzw, Packbits};
-use crate::parameters::{CSParameters, TiffDeflateLevel};
+use crate::parameters::{CSParameters, PngDeflater, TiffDeflateLevel};
 use crate::utils::{get_filetype_from_memory, get_filetype_from_path};
 use error::CaesiumError;
 
 mod convert;
+mod density;
 pub mod error;
 #[cfg(feature = "gif")]
 mod gif;
+mod grayscale;
+mod icc;
+pub mod info;
 mod interface;
 #[cfg(feature = "jpg")]
 mod jpeg;
+#[cfg(feature = "jpg")]
+mod mpf;
 pub mod parameters;
 #[cfg(feature = "png")]
 mod png;
@@ -39,29 +48,48 @@ mod webp;
 ///
 /// * `Result<(), CaesiumError>` - Returns `Ok(())` if compression is successful, otherwise returns a `CaesiumError`.
 pub fn compress(input_path: String, output_path: String, parameters: &CSParameters) -> error::Result<()> {
+    compress_with_warnings(input_path, output_path, parameters).map(|_| ())
+}
+
+/// Compresses an image file like `compress`, also returning the issues found in the input that did not stop the
+/// compression, as `compress_in_memory_with_warnings` does.
+///
+/// # Arguments
+///
+/// * `input_path` - A string representing the path to the input image file.
+/// * `output_path` - A string representing the path to the output compressed image file.
+/// * `parameters` - A reference to `CSParameters` containing compression settings.
+///
+/// # Returns
+///
+/// * `Result<Vec<CaesiumError>, CaesiumError>` - Returns the warnings if compression is successful, otherwise returns a `CaesiumError`.
+pub fn compress_with_warnings(
+    input_path: String,
+    output_path: String,
+    parameters: &CSParameters,
+) -> error::Result<Vec<CaesiumError>> {
     validate_parameters(parameters)?;
     let file_type = get_filetype_from_path(&input_path);
 
-    match file_type {
+    let warnings = match file_type {
         #[cfg(feature = "jpg")]
-        SupportedFileTypes::Jpeg => {
-            jpeg::compress(input_path, output_path, parameters)?;
-        }
+        SupportedFileTypes::Jpeg => jpeg::compress(input_path, output_path, parameters)?,
         #[cfg(feature = "png")]
-        SupportedFileTypes::Png => {
-            png::compress(input_path, output_path, parameters)?;
-        }
+        SupportedFileTypes::Png => png::compress(input_path, output_path, parameters)?,
         #[cfg(feature = "webp")]
         SupportedFileTypes::WebP => {
             webp::compress(input_path, output_path, parameters)?;
+            vec![]
         }
         #[cfg(feature = "gif")]
         SupportedFileTypes::Gif => {
             gif::compress(input_path, output_path, parameters)?;
+            vec![]
         }
         #[cfg(feature = "tiff")]
         SupportedFileTypes::Tiff => {
             tiff::compress(input_path, output_path, parameters)?;
+            vec![]
         }
         _ => {
             return Err(CaesiumError {
@@ -69,9 +97,9 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
                 code: 10000,
             });
         }
-    }
+    };
 
-    Ok(())
+    Ok(warnings)
 }
 
 /// Compresses an image file in memory and returns the compressed image as a byte vector.
@@ -85,18 +113,38 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
 ///
 /// * `Result<Vec<u8>, CaesiumError>` - Returns a vector of bytes representing the compressed image if successful, otherwise returns a `CaesiumError`.
 pub fn compress_in_memory(in_file: Vec<u8>, parameters: &CSParameters) -> error::Result<Vec<u8>> {
+    compress_in_memory_with_warnings(in_file, parameters).map(|(output, _)| output)
+}
+
+/// Compresses an image file in memory like `compress_in_memory`, also returning the issues found in the input that did
+/// not stop the compression. With `jpeg.recover_corrupted` these include the corrupted or missing parts of a JPEG, and
+/// PNGs report the reduction of 16-bit channels to 8 bits.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+/// * `parameters` - A reference to `CSParameters` containing compression settings.
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError>` - Returns the compressed image and the warnings if successful, otherwise returns a `CaesiumError`.
+pub fn compress_in_memory_with_warnings(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+) -> error::Result<(Vec<u8>, Vec<CaesiumError>)> {
+    validate_parameters(parameters)?;
     let file_type = get_filetype_from_memory(in_file.as_slice());
     let compressed_file = match file_type {
         #[cfg(feature = "jpg")]
-        SupportedFileTypes::Jpeg => jpeg::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Jpeg => jpeg::compress_in_memory_with_warnings(&in_file, parameters)?,
         #[cfg(feature = "png")]
-        SupportedFileTypes::Png => png::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Png => png::compress_in_memory_with_warnings(&in_file, parameters)?,
         #[cfg(feature = "gif")]
-        SupportedFileTypes::Gif => gif::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Gif => (gif::compress_in_memory(&in_file, parameters)?, vec![]),
         #[cfg(feature = "webp")]
-        SupportedFileTypes::WebP => webp::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::WebP => (webp::compress_in_memory(&in_file, parameters)?, vec![]),
         #[cfg(feature = "tiff")]
-        SupportedFileTypes::Tiff => tiff::compress_in_memory(&in_file, parameters)?,
+        SupportedFileTypes::Tiff => (tiff::compress_in_memory(&in_file, parameters)?, vec![]),
         _ => {
             return Err(CaesiumError {
                 message: "Format not supported for compression in memory".into(),
@@ -126,6 +174,100 @@ pub fn compress_to_size_in_memory(
     max_output_size: usize,
     return_smallest: bool,
 ) -> error::Result<Vec<u8>> {
+    compress_to_size_in_memory_with_warnings(in_file, parameters, max_output_size, return_smallest)
+        .map(|(output, _)| output)
+}
+
+/// Compresses an image file in memory up to a specified size like `compress_to_size_in_memory`, also returning the
+/// warnings of the returned compression. Lossy still WebP images are first compressed with the rate control of
+/// libwebp, falling back to the search of the quality with a warning when the size is missed.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+/// * `parameters` - A mutable reference to `CSParameters` containing compression settings.
+/// * `max_output_size` - The maximum size of the output compressed image in bytes.
+/// * `return_smallest` - A boolean indicating whether to return the smallest compressed image if the desired size is not achieved.
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError>` - Returns the compressed image and the warnings if successful, otherwise returns a `CaesiumError`.
+pub fn compress_to_size_in_memory_with_warnings(
+    in_file: Vec<u8>,
+    parameters: &mut CSParameters,
+    max_output_size: usize,
+    return_smallest: bool,
+) -> error::Result<(Vec<u8>, Vec<CaesiumError>)> {
+    validate_parameters(parameters)?;
+    #[cfg(feature = "webp")]
+    if uses_webp_rate_control(&in_file, parameters) {
+        if let Some(compressed_file) = compress_webp_to_size(&in_file, parameters, max_output_size)? {
+            return Ok((compressed_file, vec![]));
+        }
+
+        let (compressed_file, search_warnings) = search_quality(in_file, parameters, max_output_size, return_smallest)?;
+        let rate_control_warning = CaesiumError {
+            message: "WebP rate control missed the target size, searching the quality instead".into(),
+            code: 10205,
+        };
+        return Ok((
+            compressed_file,
+            std::iter::once(rate_control_warning).chain(search_warnings).collect(),
+        ));
+    }
+
+    search_quality(in_file, parameters, max_output_size, return_smallest)
+}
+
+/// Number of encodings with the libwebp rate control before falling back to the search of the quality.
+#[cfg(feature = "webp")]
+const WEBP_RATE_CONTROL_TRIES: u32 = 3;
+/// Percentage of the size needed to fit the previous try that the next one aims for, as the rate control can overshoot.
+#[cfg(feature = "webp")]
+const WEBP_RATE_CONTROL_MARGIN: u64 = 98;
+
+/// Whether the size of the image can be targeted with the libwebp rate control, only for lossy still WebP images.
+#[cfg(feature = "webp")]
+fn uses_webp_rate_control(in_file: &[u8], parameters: &CSParameters) -> bool {
+    get_filetype_from_memory(in_file) == SupportedFileTypes::WebP
+        && !parameters.webp.lossless
+        && info::inspect(in_file).is_ok_and(|i| i.frame_count <= 1)
+}
+
+/// Compresses a WebP with the libwebp rate control, returning `None` when it misses `max_output_size`.
+#[cfg(feature = "webp")]
+fn compress_webp_to_size(
+    in_file: &[u8],
+    parameters: &CSParameters,
+    max_output_size: usize,
+) -> error::Result<Option<Vec<u8>>> {
+    let mut targeted = parameters.clone();
+    let mut target_size = max_output_size.min(u32::MAX as usize) as u64;
+    for _ in 0..WEBP_RATE_CONTROL_TRIES {
+        targeted.webp.target_size = target_size as u32;
+        let compressed_file = webp::compress_in_memory(in_file, &targeted)?;
+        if compressed_file.len() <= max_output_size {
+            return Ok(Some(compressed_file));
+        }
+        target_size =
+            target_size * max_output_size as u64 * WEBP_RATE_CONTROL_MARGIN / (compressed_file.len() as u64 * 100);
+        //libwebp reads a zero target as no rate control
+        if target_size == 0 {
+            break;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Compresses with the quality closest to `max_output_size`, found by bisection. The warnings are the ones of the
+/// returned compression.
+fn search_quality(
+    in_file: Vec<u8>,
+    parameters: &mut CSParameters,
+    max_output_size: usize,
+    return_smallest: bool,
+) -> error::Result<(Vec<u8>, Vec<CaesiumError>)> {
     let file_type = get_filetype_from_memory(&in_file);
 
     let tolerance_percentage = 2;
@@ -135,6 +277,12 @@ pub fn compress_to_size_in_memory(
     let mut last_high = 101;
     let max_tries: u32 = 10;
     let mut tries: u32 = 0;
+    //The JPEG chroma quality is searched along with the luma one, keeping the requested gap between the two
+    #[cfg(feature = "jpg")]
+    let chroma_quality_offset = parameters
+        .jpeg
+        .chroma_quality
+        .map(|q| q as i32 - parameters.jpeg.quality as i32);
 
     let compressed_file = match file_type {
         #[cfg(feature = "tiff")]
@@ -151,7 +299,7 @@ pub fn compress_to_size_in_memory(
                 }
             }
             return if return_smallest || smallest_result.len() <= max_output_size {
-                Ok(smallest_result)
+                Ok((smallest_result, vec![]))
             } else {
                 Err(CaesiumError {
                     message: "Cannot compress to desired quality".into(),
@@ -171,22 +319,27 @@ pub fn compress_to_size_in_memory(
                 #[cfg(feature = "jpg")]
                 SupportedFileTypes::Jpeg => {
                     parameters.jpeg.quality = quality;
-                    jpeg::compress_in_memory(&in_file, parameters)?
+                    parameters.jpeg.chroma_quality =
+                        chroma_quality_offset.map(|offset| (quality as i32 + offset).clamp(1, 100) as u32);
+                    jpeg::compress_in_memory_with_warnings(&in_file, parameters)?
                 }
                 #[cfg(feature = "png")]
                 SupportedFileTypes::Png => {
                     parameters.png.quality = quality;
-                    png::compress_in_memory(&in_file, parameters)?
+                    png::compress_in_memory_with_warnings(&in_file, parameters)?
                 }
                 #[cfg(feature = "gif")]
                 SupportedFileTypes::Gif => {
                     parameters.gif.quality = quality;
-                    gif::compress_in_memory(&in_file, parameters)?
+                    (gif::compress_in_memory(&in_file, parameters)?, vec![])
                 }
                 #[cfg(feature = "webp")]
                 SupportedFileTypes::WebP => {
                     parameters.webp.quality = quality;
-                    webp::compress_in_memory(&in_file, parameters)?
+                    //The rate control of libwebp would override the searched quality
+                    parameters.webp.target_size = 0;
+                    parameters.webp.target_psnr = 0.0;
+                    (webp::compress_in_memory(&in_file, parameters)?, vec![])
                 }
                 _ => {
                     return Err(CaesiumError {
@@ -196,7 +349,7 @@ pub fn compress_to_size_in_memory(
                 }
             };
 
-            let compressed_file_size = compressed_file.len();
+            let compressed_file_size = compressed_file.0.len();
 
             if compressed_file_size <= max_output_size && max_output_size - compressed_file_size < tolerance {
                 break compressed_file;
@@ -251,6 +404,30 @@ pub fn compress_to_size(
     max_output_size: usize,
     return_smallest: bool,
 ) -> error::Result<()> {
+    compress_to_size_with_warnings(input_path, output_path, parameters, max_output_size, return_smallest).map(|_| ())
+}
+
+/// Compresses an image file up to a specified size like `compress_to_size`, also returning the warnings as
+/// `compress_to_size_in_memory_with_warnings` does.
+///
+/// # Arguments
+///
+/// * `input_path` - A string representing the path to the input image file.
+/// * `output_path` - A string representing the path to the output compressed image file.
+/// * `parameters` - A mutable reference to `CSParameters` containing compression settings.
+/// * `max_output_size` - The maximum size of the output compressed image in bytes.
+/// * `return_smallest` - A boolean indicating whether to return the smallest compressed image if the desired size is not achieved.
+///
+/// # Returns
+///
+/// * `Result<Vec<CaesiumError>, CaesiumError>` - Returns the warnings if compression is successful, otherwise returns a `CaesiumError`.
+pub fn compress_to_size_with_warnings(
+    input_path: String,
+    output_path: String,
+    parameters: &mut CSParameters,
+    max_output_size: usize,
+    return_smallest: bool,
+) -> error::Result<Vec<CaesiumError>> {
     let in_file = fs::read(input_path.clone()).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 10201,
@@ -260,16 +437,17 @@ pub fn compress_to_size(
     // If we resize, we should always go for at least a round of compression
     if !(parameters.width > 0 || parameters.height > 0) && original_size <= max_output_size {
         if input_path == output_path {
-            return Ok(());
+            return Ok(vec![]);
         }
         fs::copy(input_path, output_path).map_err(|e| CaesiumError {
             message: e.to_string(),
             code: 10202,
         })?;
-        return Ok(());
+        return Ok(vec![]);
     }
 
-    let compressed_file = compress_to_size_in_memory(in_file, parameters, max_output_size, return_smallest)?;
+    let (compressed_file, warnings) =
+        compress_to_size_in_memory_with_warnings(in_file, parameters, max_output_size, return_smallest)?;
     let mut out_file = File::create(output_path).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 10203,
@@ -279,7 +457,7 @@ pub fn compress_to_size(
         code: 10204,
     })?;
 
-    Ok(())
+    Ok(warnings)
 }
 
 /// Converts an image file from the input path to a specified format and writes the converted image to the output path.
@@ -300,6 +478,28 @@ pub fn convert(
     parameters: &CSParameters,
     format: SupportedFileTypes,
 ) -> error::Result<()> {
+    convert_with_warnings(input_path, output_path, parameters, format).map(|_| ())
+}
+
+/// Converts an image file like `convert`, also returning the warnings of the conversion, such as the parts of a
+/// corrupted JPEG dropped by `jpeg.recover_corrupted`.
+///
+/// # Arguments
+///
+/// * `input_path` - A string representing the path to the input image file.
+/// * `output_path` - A string representing the path to the output converted image file.
+/// * `parameters` - A reference to `CSParameters` containing conversion settings.
+/// * `format` - The target format to convert the image to.
+///
+/// # Returns
+///
+/// * `Result<Vec<CaesiumError>, CaesiumError>` - Returns the warnings if conversion is successful, otherwise returns a `CaesiumError`.
+pub fn convert_with_warnings(
+    input_path: String,
+    output_path: String,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+) -> error::Result<Vec<CaesiumError>> {
     let file_type = get_filetype_from_path(&input_path);
 
     if file_type == format {
@@ -313,10 +513,11 @@ pub fn convert(
         message: e.to_string(),
         code: 10410,
     })?;
-    let output_buffer = convert_in_memory(in_file, parameters, format).map_err(|e| CaesiumError {
-        message: e.to_string(),
-        code: 10411,
-    })?;
+    let (output_buffer, warnings) =
+        convert_in_memory_with_warnings(in_file, parameters, format).map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 10411,
+        })?;
 
     let mut out_file = File::create(output_path).map_err(|e| CaesiumError {
         message: e.to_string(),
@@ -328,7 +529,7 @@ pub fn convert(
         code: 10413,
     })?;
 
-    Ok(())
+    Ok(warnings)
 }
 
 /// Converts an image file in memory to a specified format and returns the converted image as a byte vector.
@@ -347,9 +548,221 @@ pub fn convert_in_memory(
     parameters: &CSParameters,
     format: SupportedFileTypes,
 ) -> Result<Vec<u8>, CaesiumError> {
+    convert_in_memory_with_warnings(in_file, parameters, format).map(|(output, _)| output)
+}
+
+/// Converts an image file in memory like `convert_in_memory`, also returning the warnings of the conversion.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+/// * `parameters` - A reference to `CSParameters` containing conversion settings.
+/// * `format` - The target format to convert the image to.
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError>` - Returns the converted image and the warnings if successful, otherwise returns a `CaesiumError`.
+pub fn convert_in_memory_with_warnings(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+) -> error::Result<(Vec<u8>, Vec<CaesiumError>)> {
     convert::convert_in_memory(in_file, format, parameters)
 }
 
+/// Converts an image file in memory to a specified format, compressing it up to a specified size, and returns the
+/// converted image as a byte vector.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+/// * `parameters` - A reference to `CSParameters` containing conversion settings.
+/// * `format` - The target format to convert the image to.
+/// * `max_output_size` - The maximum size of the output converted image in bytes.
+/// * `return_smallest` - A boolean indicating whether to return the smallest converted image if the desired size is not achieved.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, CaesiumError>` - Returns a vector of bytes representing the converted image if successful, otherwise returns a `CaesiumError`.
+pub fn convert_to_size_in_memory(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+    max_output_size: usize,
+    return_smallest: bool,
+) -> error::Result<Vec<u8>> {
+    convert_to_size_in_memory_with_warnings(in_file, parameters, format, max_output_size, return_smallest)
+        .map(|(output, _)| output)
+}
+
+/// Converts an image file in memory to a specified format up to a specified size like `convert_to_size_in_memory`,
+/// also returning the warnings of the compression as `compress_to_size_in_memory_with_warnings` does. Converting to
+/// lossy WebP uses the rate control of libwebp first.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input image file.
+/// * `parameters` - A reference to `CSParameters` containing conversion settings.
+/// * `format` - The target format to convert the image to.
+/// * `max_output_size` - The maximum size of the output converted image in bytes.
+/// * `return_smallest` - A boolean indicating whether to return the smallest converted image if the desired size is not achieved.
+///
+/// # Returns
+///
+/// * `Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError>` - Returns the converted image and the warnings if successful, otherwise returns a `CaesiumError`.
+pub fn convert_to_size_in_memory_with_warnings(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+    max_output_size: usize,
+    return_smallest: bool,
+) -> error::Result<(Vec<u8>, Vec<CaesiumError>)> {
+    convert::convert_to_size_in_memory(in_file, format, parameters, max_output_size, return_smallest)
+}
+
+/// Splits a TIFF image in memory in one file for each page in `tiff.pages`, in page order. The pages are compressed as
+/// TIFF or converted to `format`.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input TIFF file.
+/// * `parameters` - A reference to `CSParameters` containing compression and conversion settings.
+/// * `format` - The format of the output files.
+///
+/// # Returns
+///
+/// * `Result<Vec<Vec<u8>>, CaesiumError>` - Returns a vector of bytes for each page if successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "tiff")]
+pub fn split_pages_in_memory(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+) -> error::Result<Vec<Vec<u8>>> {
+    check_multi_page(&in_file)?;
+    let pages = tiff::split_pages(&in_file, parameters)?;
+    if format == SupportedFileTypes::Tiff {
+        return Ok(pages);
+    }
+
+    //The pages are already resized
+    let parameters = CSParameters {
+        width: 0,
+        height: 0,
+        ..parameters.clone()
+    };
+    pages
+        .into_iter()
+        .map(|page| convert_in_memory(page, &parameters, format))
+        .collect()
+}
+
+/// Converts the pages in `tiff.pages` of a TIFF image in memory to the frames of an animated WebP or GIF. Pages of
+/// different sizes are placed at the top left of a transparent canvas as large as the biggest one.
+///
+/// # Arguments
+///
+/// * `in_file` - A vector of bytes representing the input TIFF file.
+/// * `parameters` - A reference to `CSParameters` containing compression settings of the animation format.
+/// * `format` - The format of the animation, either WebP or GIF.
+/// * `frame_duration_ms` - How long each page is shown, in milliseconds.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, CaesiumError>` - Returns a vector of bytes representing the animation if successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "tiff")]
+pub fn convert_pages_to_animation_in_memory(
+    in_file: Vec<u8>,
+    parameters: &CSParameters,
+    format: SupportedFileTypes,
+    frame_duration_ms: u32,
+) -> error::Result<Vec<u8>> {
+    check_multi_page(&in_file)?;
+    match format {
+        #[cfg(feature = "webp")]
+        SupportedFileTypes::WebP => webp::encode_frames(
+            tiff::decode_canvases(&in_file, parameters)?,
+            frame_duration_ms,
+            parameters,
+        ),
+        #[cfg(feature = "gif")]
+        SupportedFileTypes::Gif => gif::encode_frames(
+            tiff::decode_canvases(&in_file, parameters)?,
+            frame_duration_ms,
+            parameters,
+        ),
+        _ => Err(CaesiumError {
+            message: "Animations can only be WebP or GIF".into(),
+            code: 10415,
+        }),
+    }
+}
+
+#[cfg(feature = "tiff")]
+fn check_multi_page(in_file: &[u8]) -> error::Result<()> {
+    if get_filetype_from_memory(in_file) != SupportedFileTypes::Tiff {
+        return Err(CaesiumError {
+            message: "Pages can only be read from TIFF images".into(),
+            code: 10414,
+        });
+    }
+
+    Ok(())
+}
+
+/// Applies a lossless transformation (rotation, flip, crop) to a JPEG image in memory, working on the DCT coefficients.
+/// All the metadata is kept as is, except for the index of multi-picture files like Ultra HDR: only the primary image is
+/// transformed and the secondary ones are dropped.
+///
+/// # Arguments
+///
+/// * `in_file` - A slice of bytes representing the input JPEG file.
+/// * `transform` - A reference to `JpegTransform` describing the transformation.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>, CaesiumError>` - Returns a vector of bytes representing the transformed image if successful, otherwise returns a `CaesiumError`.
+#[cfg(feature = "jpg")]
+pub fn jpeg_transform(in_file: &[u8], transform: &JpegTransform) -> error::Result<Vec<u8>> {
+    if get_filetype_from_memory(in_file) != SupportedFileTypes::Jpeg {
+        return Err(CaesiumError {
+            message: "Only JPEG images can be transformed".into(),
+            code: 10700,
+        });
+    }
+
+    let transformed = jpeg::transform(in_file, transform)?;
+    match mpf::split(in_file) {
+        Some(_) => mpf::strip(transformed),
+        None => Ok(transformed),
+    }
+}
+
+/// Inspects an image in memory and returns information about it, without decoding the pixel data.
+///
+/// # Arguments
+///
+/// * `in_file` - A slice of bytes representing the input image file.
+///
+/// # Returns
+///
+/// * `Result<ImageInfo, CaesiumError>` - Returns the `ImageInfo` of the image if successful, otherwise returns a `CaesiumError`.
+pub fn inspect(in_file: &[u8]) -> error::Result<ImageInfo> {
+    info::inspect(in_file)
+}
+
+/// Estimates the quality a JPEG was saved with, comparing its quantization tables with the standard ones.
+///
+/// # Arguments
+///
+/// * `in_file` - A slice of bytes representing the input JPEG file.
+///
+/// # Returns
+///
+/// * `Option<u32>` - The estimated quality (1-100), or `None` if the quantization tables cannot be found.
+pub fn estimate_jpeg_quality(in_file: &[u8]) -> Option<u32> {
+    info::estimate_jpeg_quality(in_file)
+}
+
 fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
     if parameters.jpeg.quality > 100 {
         return Err(CaesiumError {
@@ -358,6 +771,49 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    if parameters.jpeg.chroma_quality.is_some_and(|q| q > 100) {
+        return Err(CaesiumError {
+            message: "Invalid JPEG chroma quality value".into(),
+            code: 10009,
+        });
+    }
+
+    if parameters
+        .jpeg
+        .scan_script
+        .as_ref()
+        .is_some_and(|s| s.is_empty() || s.iter().any(|scan| !(1..=4).contains(&scan.component_count)))
+    {
+        return Err(CaesiumError {
+            message: "Invalid JPEG scan script".into(),
+            code: 10010,
+        });
+    }
+
+    if parameters
+        .dpi
+        .is_some_and(|d| !(1..=u16::MAX as u32).contains(&d.x) || !(1..=u16::MAX as u32).contains(&d.y))
+    {
+        return Err(CaesiumError {
+            message: "Invalid DPI value".into(),
+            code: 10011,
+        });
+    }
+
+    if parameters.jpeg.smoothing > 100 {
+        return Err(CaesiumError {
+            message: "Invalid JPEG smoothing factor".into(),
+            code: 10007,
+        });
+    }
+
+    if parameters.jpeg.restart_interval > u16::MAX as u32 {
+        return Err(CaesiumError {
+            message: "Invalid JPEG restart interval".into(),
+            code: 10008,
+        });
+    }
+
     if parameters.png.quality > 100 {
         return Err(CaesiumError {
             message: "Invalid PNG quality value".into(),
@@ -372,6 +828,57 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    if !(2..=256).contains(&parameters.png.max_colors) {
+        return Err(CaesiumError {
+            message: "Invalid PNG max colors value".into(),
+            code: 10012,
+        });
+    }
+
+    if !(0.0..=1.0).contains(&parameters.png.dithering_level) {
+        return Err(CaesiumError {
+            message: "Invalid PNG dithering level".into(),
+            code: 10013,
+        });
+    }
+
+    if !(1..=10).contains(&parameters.png.speed) {
+        return Err(CaesiumError {
+            message: "Invalid PNG speed".into(),
+            code: 10014,
+        });
+    }
+
+    if parameters.png.min_quality > 100 {
+        return Err(CaesiumError {
+            message: "Invalid PNG minimum quality value".into(),
+            code: 10015,
+        });
+    }
+
+    if parameters.png.posterization > 4 {
+        return Err(CaesiumError {
+            message: "Invalid PNG posterization value".into(),
+            code: 10016,
+        });
+    }
+
+    match parameters.png.deflater {
+        Some(PngDeflater::Libdeflater { level }) if level > 12 => {
+            return Err(CaesiumError {
+                message: "Invalid PNG deflate level".into(),
+                code: 10017,
+            });
+        }
+        Some(PngDeflater::Zopfli { iterations: 0 }) => {
+            return Err(CaesiumError {
+                message: "Invalid PNG Zopfli iterations".into(),
+                code: 10018,
+            });
+        }
+        _ => {}
+    }
+
     if parameters.gif.quality > 100 || parameters.gif.quality < 1 {
         return Err(CaesiumError {
             message: "Invalid GIF quality value".into(),
@@ -386,11 +893,81 @@ fn validate_parameters(parameters: &CSParameters) -> error::Result<()> {
         });
     }
 
+    if parameters.webp.method > 6 {
+        return Err(CaesiumError {
+            message: "Invalid WebP method".into(),
+            code: 10019,
+        });
+    }
+
+    if parameters.webp.sns_strength.is_some_and(|s| s > 100) {
+        return Err(CaesiumError {
+            message: "Invalid WebP SNS strength".into(),
+            code: 10020,
+        });
+    }
+
+    if parameters.webp.filter_strength.is_some_and(|s| s > 100) {
+        return Err(CaesiumError {
+            message: "Invalid WebP filter strength".into(),
+            code: 10021,
+        });
+    }
+
+    if parameters.webp.filter_sharpness.is_some_and(|s| s > 7) {
+        return Err(CaesiumError {
+            message: "Invalid WebP filter sharpness".into(),
+            code: 10022,
+        });
+    }
+
+    if !(1..=4).contains(&parameters.webp.segments) {
+        return Err(CaesiumError {
+            message: "Invalid WebP segments".into(),
+            code: 10023,
+        });
+    }
+
+    if !(1..=10).contains(&parameters.webp.pass) {
+        return Err(CaesiumError {
+            message: "Invalid WebP pass count".into(),
+            code: 10024,
+        });
+    }
+
+    if parameters.webp.alpha_quality > 100 {
+        return Err(CaesiumError {
+            message: "Invalid WebP alpha quality".into(),
+            code: 10025,
+        });
+    }
+
+    if parameters.webp.near_lossless > 100 {
+        return Err(CaesiumError {
+            message: "Invalid WebP near lossless value".into(),
+            code: 10026,
+        });
+    }
+
+    if !(0.0..=99.0).contains(&parameters.webp.target_psnr) {
+        return Err(CaesiumError {
+            message: "Invalid WebP target PSNR".into(),
+            code: 10027,
+        });
+    }
+
+    if parameters.tiff.pages.is_some_and(|(first, last)| first > last) {
+        return Err(CaesiumError {
+            message: "Invalid TIFF page range".into(),
+            code: 10028,
+        });
+    }
+
     Ok(())
 }
 
 #[repr(C)]
-#[derive(PartialEq, Eq, Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum SupportedFileTypes {
     Jpeg,
     Png,
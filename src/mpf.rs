@@ -0,0 +1,365 @@
+use bytes::Bytes;
+use img_parts::jpeg::{Jpeg as PartsJpeg, JpegSegment};
+
+use crate::error::CaesiumError;
+
+const MPF_SIGNATURE: &[u8] = b"MPF\0";
+const XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const ISO_GAIN_MAP_SIGNATURE: &[u8] = b"urn:iso:std:iso:ts:21496:-1\0";
+const NUMBER_OF_IMAGES_TAG: u16 = 0xB001;
+const MP_ENTRY_TAG: u16 = 0xB002;
+const MP_ENTRY_LENGTH: usize = 16;
+const APP1: u8 = 0xE1;
+const APP2: u8 = 0xE2;
+
+/// JPEG holding more images after the primary one, indexed by an MPF (CIPA DC-007) segment. This is how Ultra HDR
+/// and ISO 21496-1 files store their gain maps.
+pub struct MultiPicture<'a> {
+    pub primary: &'a [u8],
+    pub secondary: Vec<&'a [u8]>,
+    //Positions of the secondary images in the MPF index, which may have gaps once some are dropped
+    entries: Vec<usize>,
+    //Segments of the primary image describing the secondary ones, in their original order
+    container_segments: Vec<JpegSegment>,
+}
+
+/// Splits a multi-picture JPEG in its images. Returns `None` for plain JPEGs and for broken MPF indexes.
+pub fn split(in_file: &[u8]) -> Option<MultiPicture<'_>> {
+    let (segment_offset, contents) = find_mpf_segment(in_file)?;
+    let entries = read_entries(contents)?;
+    if entries.len() < 2 {
+        return None;
+    }
+
+    //Offsets are relative to the MP endian field, right after the signature
+    let base = segment_offset + 4 + MPF_SIGNATURE.len();
+    let mut secondary = Vec::with_capacity(entries.len() - 1);
+    for entry in &entries[1..] {
+        let start = base.checked_add(entry.offset as usize)?;
+        let end = start.checked_add(entry.size as usize)?;
+        let image = in_file.get(start..end)?;
+        if !image.starts_with(&[0xFF, 0xD8]) {
+            return None;
+        }
+        secondary.push(image);
+    }
+
+    let first_secondary = entries[1..].iter().map(|e| base + e.offset as usize).min()?;
+    let primary = &in_file[..(entries[0].size as usize).min(first_secondary)];
+    if !primary.ends_with(&[0xFF, 0xD9]) {
+        return None;
+    }
+    let container_segments = PartsJpeg::from_bytes(Bytes::copy_from_slice(primary))
+        .ok()?
+        .segments()
+        .iter()
+        .filter(|s| is_container_segment(s))
+        .cloned()
+        .collect();
+
+    Some(MultiPicture {
+        primary,
+        secondary,
+        entries: (1..entries.len()).collect(),
+        container_segments,
+    })
+}
+
+impl MultiPicture<'_> {
+    /// Drops the secondary images that are not gain maps, like previews or the other views of a stereo picture.
+    pub fn retain_gain_maps(&mut self) {
+        (self.secondary, self.entries) = self
+            .secondary
+            .iter()
+            .zip(&self.entries)
+            .filter(|(image, _)| is_gain_map(image))
+            .map(|(image, entry)| (*image, *entry))
+            .unzip();
+    }
+}
+
+/// Puts the compressed images back together, writing the MPF index and the gain map lengths of the Ultra HDR XMP
+/// for their new sizes.
+pub fn join(primary: Vec<u8>, picture: &MultiPicture, secondary: Vec<Vec<u8>>) -> Result<Vec<u8>, CaesiumError> {
+    let lengths: Vec<usize> = secondary.iter().map(Vec::len).collect();
+    //The XMP directory only lists the gain maps, so the lengths of other images like previews must not be used
+    let gain_map_lengths: Vec<usize> = picture
+        .secondary
+        .iter()
+        .zip(&lengths)
+        .filter(|(image, _)| is_gain_map(image))
+        .map(|(_, length)| *length)
+        .collect();
+    let container_segments = picture
+        .container_segments
+        .iter()
+        .map(|s| match s.marker() {
+            APP1 => JpegSegment::new_with_contents(APP1, set_xmp_item_lengths(s.contents(), &gain_map_lengths)),
+            _ => s.clone(),
+        })
+        .collect();
+    let mut output = replace_container_segments(primary, container_segments)?;
+
+    //The segment was moved by the compression, so it has to be found again
+    let Some((segment_offset, (little_endian, entries_start, count))) =
+        find_mpf_segment(&output).and_then(|(offset, tiff)| Some((offset, entries_position(tiff)?)))
+    else {
+        return Err(CaesiumError {
+            message: "Invalid MPF index".to_string(),
+            code: 20120,
+        });
+    };
+    let base = segment_offset + 4 + MPF_SIGNATURE.len();
+
+    let start = base + entries_start;
+    let original_entries = output[start..start + count * MP_ENTRY_LENGTH].to_vec();
+
+    let primary_length = output.len();
+    let mut offset = primary_length;
+    let mut fields = vec![(0, primary_length as u32, 0u32)];
+    for (entry, length) in picture.entries.iter().zip(&lengths) {
+        fields.push((*entry, *length as u32, (offset - base) as u32));
+        offset += length;
+    }
+    if fields.len() < count {
+        set_image_count(&mut output[base..], little_endian, fields.len());
+    }
+    for (i, (entry, size, entry_offset)) in fields.into_iter().enumerate() {
+        let position = start + i * MP_ENTRY_LENGTH;
+        output[position..position + MP_ENTRY_LENGTH]
+            .copy_from_slice(&original_entries[entry * MP_ENTRY_LENGTH..(entry + 1) * MP_ENTRY_LENGTH]);
+        output[position + 4..position + 8].copy_from_slice(&to_bytes(size, little_endian));
+        output[position + 8..position + 12].copy_from_slice(&to_bytes(entry_offset, little_endian));
+    }
+
+    for image in secondary {
+        output.extend(image);
+    }
+    Ok(output)
+}
+
+/// Removes the segments referring to the secondary images, leaving a plain JPEG. The Ultra HDR XMP also holds the
+/// metadata of the primary image, so only its container directory is removed.
+pub fn strip(primary: Vec<u8>) -> Result<Vec<u8>, CaesiumError> {
+    edit_segments(primary, |segments| {
+        segments.retain_mut(|s| match s.marker() {
+            APP1 if is_container_segment(s) => {
+                *s = JpegSegment::new_with_contents(APP1, remove_xmp_directory(s.contents()));
+                true
+            }
+            _ => !is_container_segment(s),
+        })
+    })
+}
+
+/// Whether a secondary image is a gain map, holding its own Ultra HDR XMP or ISO 21496-1 metadata.
+fn is_gain_map(image: &[u8]) -> bool {
+    find_segment(image, |marker, contents| match marker {
+        APP1 => contents.starts_with(XMP_SIGNATURE) && contains(contents, b"hdrgm"),
+        APP2 => contents.starts_with(ISO_GAIN_MAP_SIGNATURE),
+        _ => false,
+    })
+    .is_some()
+}
+
+fn is_container_segment(segment: &JpegSegment) -> bool {
+    let contents = segment.contents();
+    match segment.marker() {
+        APP1 => contents.starts_with(XMP_SIGNATURE) && contains(contents, b"hdrgm"),
+        APP2 => contents.starts_with(MPF_SIGNATURE) || contents.starts_with(ISO_GAIN_MAP_SIGNATURE),
+        _ => false,
+    }
+}
+
+fn replace_container_segments(image: Vec<u8>, container_segments: Vec<JpegSegment>) -> Result<Vec<u8>, CaesiumError> {
+    edit_segments(image, |segments| {
+        segments.retain(|s| !is_container_segment(s));
+        //MPF must follow the JFIF and EXIF segments
+        let position = segments
+            .iter()
+            .take_while(|s| matches!(s.marker(), 0xE0 | APP1))
+            .count();
+        segments.splice(position..position, container_segments);
+    })
+}
+
+fn edit_segments(image: Vec<u8>, edit: impl FnOnce(&mut Vec<JpegSegment>)) -> Result<Vec<u8>, CaesiumError> {
+    let mut jpeg = PartsJpeg::from_bytes(Bytes::from(image)).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20119,
+    })?;
+    edit(jpeg.segments_mut());
+
+    let mut output = vec![];
+    jpeg.encoder().write_to(&mut output).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20119,
+    })?;
+    Ok(output)
+}
+
+/// Finds the MPF segment of the primary image, returning its offset and the TIFF structure following the signature.
+fn find_mpf_segment(in_file: &[u8]) -> Option<(usize, &[u8])> {
+    let (pos, contents) = find_segment(in_file, |marker, contents| {
+        marker == APP2 && contents.starts_with(MPF_SIGNATURE)
+    })?;
+    Some((pos, &contents[MPF_SIGNATURE.len()..]))
+}
+
+/// Finds the first segment before the image data matching `matches`, returning its offset and contents.
+fn find_segment(in_file: &[u8], matches: impl Fn(u8, &[u8]) -> bool) -> Option<(usize, &[u8])> {
+    let mut pos = 2;
+    while pos + 4 <= in_file.len() && in_file[pos] == 0xFF {
+        let marker = in_file[pos + 1];
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let length = u16::from_be_bytes([in_file[pos + 2], in_file[pos + 3]]) as usize;
+        let contents = in_file.get(pos + 4..pos + 2 + length)?;
+        if matches(marker, contents) {
+            return Some((pos, contents));
+        }
+        pos += 2 + length;
+    }
+
+    None
+}
+
+struct MpEntry {
+    size: u32,
+    offset: u32,
+}
+
+fn read_entries(tiff: &[u8]) -> Option<Vec<MpEntry>> {
+    let (little_endian, start, count) = entries_position(tiff)?;
+
+    (0..count)
+        .map(|i| {
+            let entry = start + i * MP_ENTRY_LENGTH;
+            Some(MpEntry {
+                size: read_u32(tiff, entry + 4, little_endian)?,
+                offset: read_u32(tiff, entry + 8, little_endian)?,
+            })
+        })
+        .collect()
+}
+
+/// Finds the MP entries in the TIFF structure of the MPF segment, returning the byte order, their offset and count.
+fn entries_position(tiff: &[u8]) -> Option<(bool, usize, usize)> {
+    let little_endian = match tiff.get(..4)? {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let ifd = read_u32(tiff, 4, little_endian)? as usize;
+    let entry_count = read_u16(tiff, ifd, little_endian)? as usize;
+
+    (0..entry_count).find_map(|i| {
+        let entry = ifd + 2 + i * 12;
+        if read_u16(tiff, entry, little_endian)? != MP_ENTRY_TAG {
+            return None;
+        }
+        let length = read_u32(tiff, entry + 4, little_endian)? as usize;
+        let start = read_u32(tiff, entry + 8, little_endian)? as usize;
+        tiff.get(start..start.checked_add(length)?)?;
+        Some((little_endian, start, length / MP_ENTRY_LENGTH))
+    })
+}
+
+/// Updates the image count and the length of the MP entries after secondary images were dropped. The entries left
+/// past the new length are no longer referenced.
+fn set_image_count(tiff: &mut [u8], little_endian: bool, count: usize) {
+    let Some(ifd) = read_u32(tiff, 4, little_endian) else {
+        return;
+    };
+    let entry_count = read_u16(tiff, ifd as usize, little_endian).unwrap_or(0) as usize;
+
+    for i in 0..entry_count {
+        let entry = ifd as usize + 2 + i * 12;
+        let (position, value) = match read_u16(tiff, entry, little_endian) {
+            Some(NUMBER_OF_IMAGES_TAG) => (entry + 8, count),
+            Some(MP_ENTRY_TAG) => (entry + 4, count * MP_ENTRY_LENGTH),
+            _ => continue,
+        };
+        if let Some(field) = tiff.get_mut(position..position + 4) {
+            field.copy_from_slice(&to_bytes(value as u32, little_endian));
+        }
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize, little_endian: bool) -> Option<u16> {
+    let bytes = data.get(offset..offset + 2)?.try_into().ok()?;
+    Some(if little_endian {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if little_endian {
+        u32::from_le_bytes(bytes)
+    } else {
+        u32::from_be_bytes(bytes)
+    })
+}
+
+fn to_bytes(value: u32, little_endian: bool) -> [u8; 4] {
+    if little_endian {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    }
+}
+
+fn contains(data: &[u8], pattern: &[u8]) -> bool {
+    data.windows(pattern.len()).any(|w| w == pattern)
+}
+
+/// Removes the container directory listing the images from the Ultra HDR XMP.
+fn remove_xmp_directory(xmp: &[u8]) -> Bytes {
+    const START: &str = "<Container:Directory";
+    const END: &str = "</Container:Directory>";
+    let stripped = std::str::from_utf8(xmp).ok().and_then(|text| {
+        let start = text.find(START)?;
+        let end = start + text[start..].find(END)? + END.len();
+        Some(format!("{}{}", &text[..start], &text[end..]))
+    });
+
+    stripped.map_or_else(|| Bytes::copy_from_slice(xmp), Bytes::from)
+}
+
+/// Updates the `Item:Length` of the gain map items in the container directory of the Ultra HDR XMP, in order. Other
+/// items and the gain maps left without a length are not changed.
+fn set_xmp_item_lengths(xmp: &[u8], gain_map_lengths: &[usize]) -> Bytes {
+    let Ok(text) = std::str::from_utf8(xmp) else {
+        return Bytes::copy_from_slice(xmp);
+    };
+
+    let mut output = String::with_capacity(text.len());
+    let mut lengths = gain_map_lengths.iter();
+    let mut rest = text;
+    while let Some(start) = rest.find("<Container:Item") {
+        let end = rest[start..].find('>').map_or(rest.len(), |e| start + e);
+        let item = &rest[start..end];
+        output.push_str(&rest[..start]);
+        match (item.contains("Item:Semantic=\"GainMap\""), item.find("Item:Length=\"")) {
+            (true, Some(attribute)) => {
+                let value_start = attribute + "Item:Length=\"".len();
+                let value_end = item[value_start..].find('"').map_or(item.len(), |e| value_start + e);
+                output.push_str(&item[..value_start]);
+                match lengths.next() {
+                    Some(length) => output.push_str(&length.to_string()),
+                    None => output.push_str(&item[value_start..value_end]),
+                }
+                output.push_str(&item[value_end..]);
+            }
+            _ => output.push_str(item),
+        }
+        rest = &rest[end..];
+    }
+    output.push_str(rest);
+
+    Bytes::from(output)
+}
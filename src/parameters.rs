@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use crate::parameters::TiffCompression::Deflate;
 
 /// Enum representing different chroma subsampling options for JPEG compression.
@@ -6,14 +8,16 @@ use crate::parameters::TiffCompression::Deflate;
 /// - `CS422`: 4:2:2 chroma subsampling
 /// - `CS420`: 4:2:0 chroma subsampling
 /// - `CS411`: 4:1:1 chroma subsampling
-/// - `Auto`: Automatic chroma subsampling
-#[derive(Copy, Clone, PartialEq)]
+/// - `Auto`: 4:4:4 when the image has sharp color edges that would bleed (e.g. colored text), 4:2:0 otherwise
+/// - `Preserve`: Same sampling factors as the source
+#[derive(Copy, Clone, PartialEq, Debug)]
 pub enum ChromaSubsampling {
     CS444,
     CS422,
     CS420,
     CS411,
     Auto,
+    Preserve,
 }
 
 /// Enum representing different compression algorithms for TIFF images.
@@ -42,21 +46,281 @@ pub enum TiffDeflateLevel {
     Best = 9,
 }
 
+/// Enum representing mozjpeg compression profiles.
+///
+/// - `MaxCompression`: Best compression ratio, with all mozjpeg extensions
+/// - `Fastest`: libjpeg-turbo defaults, without mozjpeg extensions
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegProfile {
+    MaxCompression,
+    Fastest,
+}
+
+/// Enum representing the base quantization tables scaled by the quality.
+///
+/// - `AnnexK`: JPEG Annex K tables
+/// - `Flat`: Flat tables
+/// - `MssimTuned`: Tables tuned for MS-SSIM on Kodak image set
+/// - `ImageMagick`: ImageMagick tables by N. Robidoux
+/// - `PsnrHvsTuned`: Tables tuned for PSNR-HVS-M on Kodak image set
+/// - `Klein`: Tables from Klein, Silverstein and Carney
+/// - `Watson`: Tables from Watson, Taylor and Borthwick
+/// - `Ahumada`: Tables from Ahumada, Watson, Peterson
+/// - `Peterson`: Tables from Peterson, Ahumada and Watson
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegQuantTable {
+    AnnexK = 0,
+    Flat = 1,
+    MssimTuned = 2,
+    ImageMagick = 3,
+    PsnrHvsTuned = 4,
+    Klein = 5,
+    Watson = 6,
+    Ahumada = 7,
+    Peterson = 8,
+}
+
+/// Enum representing the metric trellis quantization is tuned for.
+///
+/// - `PsnrHvs`: PSNR-HVS
+/// - `Psnr`: PSNR
+/// - `Ssim`: SSIM
+/// - `MsSsim`: MS-SSIM
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegTune {
+    PsnrHvs,
+    Psnr,
+    Ssim,
+    MsSsim,
+}
+
+/// Enum representing how mozjpeg groups the DC coefficients when optimizing the progressive scans.
+///
+/// - `Interleaved`: One scan for all the components
+/// - `PerComponent`: One scan for each component
+/// - `LumaSeparate`: Either one scan for all the components or one for the luma component and one for the chroma ones,
+///   whichever is smaller
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegDcScanMode {
+    Interleaved = 0,
+    PerComponent = 1,
+    LumaSeparate = 2,
+}
+
+/// Struct representing a scan of a JPEG scan script, as in the jpegtran `-scans` files.
+///
+/// Fields:
+/// - `components`: Indexes of the components coded in the scan, only the first `component_count` are used
+/// - `component_count`: Number of components coded in the scan (1-4)
+/// - `spectral_start`: First coefficient of the spectral band (0-63)
+/// - `spectral_end`: Last coefficient of the spectral band (0-63)
+/// - `approx_high`: Successive approximation bit position of the previous scan of the band, 0 for the first one
+/// - `approx_low`: Successive approximation bit position of this scan
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct JpegScan {
+    pub components: [u8; 4],
+    pub component_count: u8,
+    pub spectral_start: u8,
+    pub spectral_end: u8,
+    pub approx_high: u8,
+    pub approx_low: u8,
+}
+
+/// Enum representing what to do when the target quality is higher than the estimated quality of the source JPEG.
+///
+/// - `Ignore`: Always encode with the target quality
+/// - `Cap`: Lower the target quality to the estimated one of the source
+/// - `Lossless`: Only optimize the source losslessly. Falls back to `Cap` when the image is resized or converted
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegSourceQualityPolicy {
+    Ignore,
+    Cap,
+    Lossless,
+}
+
+/// Enum representing lossless transformations of JPEG images.
+///
+/// - `None`: No transformation, useful to only crop
+/// - `FlipHorizontal`: Mirror horizontally
+/// - `FlipVertical`: Mirror vertically
+/// - `Transpose`: Flip along the upper-left to lower-right diagonal
+/// - `Transverse`: Flip along the upper-right to lower-left diagonal
+/// - `Rotate90`: Rotate 90 degrees clockwise
+/// - `Rotate180`: Rotate 180 degrees
+/// - `Rotate270`: Rotate 270 degrees clockwise
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum JpegTransformation {
+    None,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    Transverse,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// Struct representing a crop region in pixels.
+///
+/// Fields:
+/// - `x`: Left offset, moved to the previous MCU boundary extending the region
+/// - `y`: Top offset, moved to the previous MCU boundary extending the region
+/// - `width`: Width of the region
+/// - `height`: Height of the region
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CropRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Struct representing a lossless JPEG transformation, performed on the DCT coefficients.
+///
+/// Fields:
+/// - `transformation`: Rotation or flip to apply
+/// - `crop`: Region to keep, in the coordinates of the transformed image. The region is extended to the left and top
+///   when the offsets are not MCU aligned
+/// - `perfect`: Fail instead of trimming the partial edge blocks that cannot be transformed
+#[derive(Copy, Clone)]
+pub struct JpegTransform {
+    pub transformation: JpegTransformation,
+    pub crop: Option<CropRegion>,
+    pub perfect: bool,
+}
+
+/// Enum representing the row filters oxipng can try on PNG images.
+///
+/// - `None`, `Sub`, `Up`, `Average`, `Paeth`: Standard PNG filters, applied to all the rows
+/// - `MinSum`: Heuristic picking the filter with the minimum sum of absolute differences for each row
+/// - `Entropy`: Heuristic picking the filter with the lowest entropy for each row
+/// - `Bigrams`: Heuristic picking the filter with the fewest distinct bigrams for each row
+/// - `BigEnt`: Heuristic picking the filter with the lowest bigram entropy for each row
+/// - `Brute`: Picks the filter compressing each row best, slow
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+    MinSum,
+    Entropy,
+    Bigrams,
+    BigEnt,
+    Brute,
+}
+
+/// Enum representing the interlacing of PNG images.
+///
+/// - `None`: Rows stored top to bottom
+/// - `Adam7`: Adam7 interlacing, allowing progressive rendering but usually increasing the size
+/// - `Keep`: Same interlacing as the source
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PngInterlace {
+    None,
+    Adam7,
+    Keep,
+}
+
+/// Enum representing the deflate implementations for PNG images.
+///
+/// - `Libdeflater`: libdeflater with the given compression level (0-12)
+/// - `Zopfli`: Zopfli with the given number of iterations (1-255), much slower but usually smaller
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PngDeflater {
+    Libdeflater { level: u8 },
+    Zopfli { iterations: u8 },
+}
+
+/// Enum representing the libwebp presets, tuning the encoder for a kind of image.
+///
+/// - `Default`: Default preset
+/// - `Picture`: Digital picture, like portrait or inner shot
+/// - `Photo`: Outdoor photograph, with natural lighting
+/// - `Drawing`: Hand or line drawing, with high-contrast details
+/// - `Icon`: Small-sized colorful images
+/// - `Text`: Text-like images
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WebPPreset {
+    Default,
+    Picture,
+    Photo,
+    Drawing,
+    Icon,
+    Text,
+}
+
+/// Enum representing the predictive filtering of the alpha plane of lossy WebP images.
+///
+/// - `None`: No filtering
+/// - `Fast`: Fast filtering
+/// - `Best`: Slower filtering, trying all the predictors
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WebPAlphaFiltering {
+    None = 0,
+    Fast = 1,
+    Best = 2,
+}
+
+/// Ancillary PNG chunks describing the colors of the image, kept by default when stripping the metadata.
+pub const PNG_COLOR_CHUNKS: [[u8; 4]; 4] = [*b"gAMA", *b"cHRM", *b"sRGB", *b"cICP"];
+
 /// Struct representing parameters for JPEG compression.
 ///
 /// Fields:
 /// - `quality`: Quality of the JPEG image (0-100)
+/// - `chroma_quality`: Quality of the chroma components (0-100), `None` to use `quality` for all of them
 /// - `chroma_subsampling`: Chroma subsampling option
-/// - `progressive`: Whether to use progressive JPEG
+/// - `progressive`: Whether to use progressive JPEG, also for lossless optimization of baseline images
+/// - `optimize_scans`: Whether to let mozjpeg search the progressive scans producing the smallest file.
+///   Only with the `MaxCompression` profile and Huffman coding
+/// - `dc_scan_mode`: How DC coefficients are grouped when optimizing the scans
+/// - `scan_script`: Custom scan script replacing the one chosen by `progressive` and `optimize_scans`
 /// - `optimize`: Whether to use lossless optimization for JPEG
 /// - `preserve_icc`: Always keep the original ICC data regardless of other options
-#[derive(Copy, Clone)]
+/// - `cmyk_to_rgb`: Convert CMYK/YCCK images to RGB, through the embedded ICC profile when present
+/// - `source_quality_policy`: What to do when `quality` is higher than the estimated quality of the source
+/// - `recover_corrupted`: Compress what can be decoded of truncated or corrupted images instead of failing.
+///   Missing parts are filled gray. The libjpeg warnings about the input are returned whether or not it is enabled
+/// - `keep_gain_map`: Keep the Ultra HDR or ISO 21496-1 gain maps of multi-picture (MPF) files, compressing them with
+///   the same settings. The other secondary images, like previews, are only kept with `keep_metadata`. When false they
+///   are all dropped together with the metadata referring to them
+/// - `requantize`: Lower the quality by requantizing the DCT coefficients instead of decoding and re-encoding the pixels.
+///   Chroma subsampling is kept from the source and color conversions still require a full re-encoding
+/// - `profile`: mozjpeg compression profile. `Fastest` disables the mozjpeg extensions, so `quant_table`, `trellis`,
+///   `tune` and `overshoot_deringing` are ignored
+/// - `quant_table`: Base quantization tables
+/// - `trellis`: Whether to use trellis quantization
+/// - `tune`: Metric trellis quantization is tuned for
+/// - `overshoot_deringing`: Whether to reduce ringing on hard edges of saturated areas
+/// - `smoothing`: Smoothing factor applied to the input (0-100)
+/// - `restart_interval`: Number of MCUs between restart markers, 0 disables them
+/// - `arithmetic_coding`: Whether to use arithmetic coding instead of Huffman, not supported by many decoders
+#[derive(Clone)]
 pub struct JpegParameters {
     pub quality: u32,
+    pub chroma_quality: Option<u32>,
     pub chroma_subsampling: ChromaSubsampling,
     pub progressive: bool,
+    pub optimize_scans: bool,
+    pub dc_scan_mode: JpegDcScanMode,
+    pub scan_script: Option<Vec<JpegScan>>,
     pub optimize: bool,
     pub preserve_icc: bool,
+    pub cmyk_to_rgb: bool,
+    pub source_quality_policy: JpegSourceQualityPolicy,
+    pub recover_corrupted: bool,
+    pub keep_gain_map: bool,
+    pub requantize: bool,
+    pub profile: JpegProfile,
+    pub quant_table: JpegQuantTable,
+    pub trellis: bool,
+    pub tune: JpegTune,
+    pub overshoot_deringing: bool,
+    pub smoothing: u32,
+    pub restart_interval: u32,
+    pub arithmetic_coding: bool,
 }
 
 /// Struct representing parameters for PNG compression.
@@ -66,12 +330,47 @@ pub struct JpegParameters {
 /// - `force_zopfli`: Whether to force the use of Zopfli compression (can be very slow)
 /// - `optimization_level`: Optimization level for PNG compression (0-6)
 /// - `optimize`: Whether to use lossless optimization for PNG
-#[derive(Copy, Clone)]
+/// - `max_colors`: Maximum number of colors in the palette of lossy PNGs (2-256)
+/// - `dithering_level`: Amount of dithering applied to lossy PNGs, from none to full (0.0-1.0)
+/// - `speed`: Speed of the quantization, lower values are slower but give better palettes (1-10)
+/// - `min_quality`: Minimum quality of lossy PNGs, below which the image is optimized losslessly instead (0-100)
+/// - `posterization`: Number of least significant bits to ignore in the palette colors (0-4)
+/// - `keep_16_bit`: Whether lossless optimization keeps 16 bits per channel, lossy compression always reduces them to 8
+/// - `dither_16_bit`: Whether to dither the channels reduced from 16 to 8 bits instead of rounding them
+/// - `filters`: Row filters to try, `None` to use the ones of `optimization_level`
+/// - `interlace`: Interlacing of the output
+/// - `optimize_alpha`: Whether to change the color of fully transparent pixels to improve compression
+/// - `bit_depth_reduction`: Whether to reduce the bit depth when no information is lost
+/// - `color_type_reduction`: Whether to reduce the color type when no information is lost, e.g. opaque RGBA to RGB
+/// - `palette_reduction`: Whether to remove unused and duplicated palette entries
+/// - `strip_chunks`: Chunks to remove, `None` to remove the ones not affecting the display unless `keep_metadata` is set
+/// - `keep_chunks`: Ancillary chunks kept when `keep_metadata` is not set, in addition to the ones needed for display
+///   (cICP, iCCP, sRGB and the APNG ones). Defaults to `PNG_COLOR_CHUNKS`
+/// - `deflater`: Deflate implementation, `None` to use the one of `optimization_level`, or Zopfli with `force_zopfli`
+/// - `timeout`: Maximum time spent optimizing, after which the smallest result found so far is used
+#[derive(Clone)]
 pub struct PngParameters {
     pub quality: u32,
     pub force_zopfli: bool,
     pub optimization_level: u8,
     pub optimize: bool,
+    pub max_colors: u32,
+    pub dithering_level: f32,
+    pub speed: u8,
+    pub min_quality: u32,
+    pub posterization: u8,
+    pub keep_16_bit: bool,
+    pub dither_16_bit: bool,
+    pub filters: Option<Vec<PngFilter>>,
+    pub interlace: PngInterlace,
+    pub optimize_alpha: bool,
+    pub bit_depth_reduction: bool,
+    pub color_type_reduction: bool,
+    pub palette_reduction: bool,
+    pub strip_chunks: Option<Vec<[u8; 4]>>,
+    pub keep_chunks: Vec<[u8; 4]>,
+    pub deflater: Option<PngDeflater>,
+    pub timeout: Option<Duration>,
 }
 
 /// Struct representing parameters for GIF compression.
@@ -88,10 +387,47 @@ pub struct GifParameters {
 /// Fields:
 /// - `quality`: Quality of the WebP image (0-100)
 /// - `lossless`: Whether to use lossless compression for WebP
+/// - `method`: Compression effort, higher is slower and smaller (0-6)
+/// - `preset`: libwebp preset setting the defaults of `sns_strength`, `filter_strength` and `filter_sharpness`
+/// - `sns_strength`: Spatial noise shaping strength (0-100), `None` to use the one of `preset`
+/// - `filter_strength`: Deblocking filter strength (0-100), `None` to use the one of `preset`
+/// - `filter_sharpness`: Deblocking filter sharpness (0-7), `None` to use the one of `preset`
+/// - `segments`: Number of segments the image is split in for quantization (1-4)
+/// - `pass`: Number of entropy analysis passes (1-10). The default of 1 is raised to 6 when `target_psnr` or
+///   `target_size` is set
+/// - `alpha_quality`: Quality of the alpha plane of lossy images (0-100)
+/// - `alpha_filtering`: Predictive filtering of the alpha plane of lossy images
+/// - `near_lossless`: Near lossless preprocessing of lossless images, 100 disables it (0-100)
+/// - `exact`: Whether to keep the RGB values under fully transparent pixels
+/// - `use_sharp_yuv`: Whether to use the slower but sharper RGB to YUV conversion
+/// - `target_psnr`: PSNR to reach in dB, overriding `quality`. 0 disables it
+/// - `target_size`: Size in bytes libwebp aims for with its own rate control, overriding `quality`. 0 disables it.
+///   Only for still lossy images
+/// - `allow_mixed`: Whether each frame of an animation can be either lossy or lossless, whichever is smaller
+/// - `kmin`: Minimum distance between key frames of an animation, `None` to use the libwebp default
+/// - `kmax`: Maximum distance between key frames of an animation, `None` to use the libwebp default. 0 disables key
+///   frames after the first one, 1 makes every frame a key frame
 #[derive(Copy, Clone)]
 pub struct WebPParameters {
     pub quality: u32,
     pub lossless: bool,
+    pub method: u8,
+    pub preset: WebPPreset,
+    pub sns_strength: Option<u8>,
+    pub filter_strength: Option<u8>,
+    pub filter_sharpness: Option<u8>,
+    pub segments: u8,
+    pub pass: u8,
+    pub alpha_quality: u8,
+    pub alpha_filtering: WebPAlphaFiltering,
+    pub near_lossless: u8,
+    pub exact: bool,
+    pub use_sharp_yuv: bool,
+    pub target_psnr: f32,
+    pub target_size: u32,
+    pub allow_mixed: bool,
+    pub kmin: Option<u32>,
+    pub kmax: Option<u32>,
 }
 
 /// Struct representing parameters for TIFF compression.
@@ -99,10 +435,24 @@ pub struct WebPParameters {
 /// Fields:
 /// - `algorithm`: Compression algorithm for TIFF
 /// - `deflate_level`: Deflate level for TIFF compression
+/// - `pages`: First and last page to keep, 0-based and inclusive, a last page past the end keeps all the following
+///   ones. `None` keeps every page
 #[derive(Copy, Clone)]
 pub struct TiffParameters {
     pub algorithm: TiffCompression,
     pub deflate_level: TiffDeflateLevel,
+    pub pages: Option<(u32, u32)>,
+}
+
+/// Struct representing the resolution of an image in dots per inch.
+///
+/// Fields:
+/// - `x`: Horizontal resolution
+/// - `y`: Vertical resolution
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Dpi {
+    pub x: u32,
+    pub y: u32,
 }
 
 /// Struct representing overall compression parameters.
@@ -114,9 +464,19 @@ pub struct TiffParameters {
 /// - `webp`: WebP compression parameters
 /// - `tiff`: TIFF compression parameters
 /// - `keep_metadata`: Whether to keep metadata in the compressed image
+/// - `convert_to_srgb`: Whether to convert pixels tagged with a non-sRGB ICC profile to sRGB before encoding
+/// - `embed_srgb_profile`: Whether to embed a compact sRGB profile in place of the converted one
+/// - `keep_dpi`: Whether to keep the resolution of the source even when the other metadata is stripped. WebP only
+///   stores it in a generated EXIF when it differs from 72 dpi. Not supported for GIF
+/// - `dpi`: Resolution to write in the output (1-65535), `None` to keep the source one according to `keep_dpi`
+/// - `to_grayscale`: Whether to always encode in grayscale. Only for JPEG, PNG and TIFF
+/// - `detect_grayscale`: Whether to encode in grayscale the images whose pixels are all neutral gray. Only for JPEG,
+///   PNG and TIFF
+/// - `grayscale_tolerance`: Maximum difference between the color channels of a pixel still considered gray by lossy
+///   encoders, 0 by default. Lossless ones require exactly neutral pixels
 /// - `width`: Width of the output image
 /// - `height`: Height of the output image
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct CSParameters {
     pub jpeg: JpegParameters,
     pub png: PngParameters,
@@ -124,6 +484,13 @@ pub struct CSParameters {
     pub webp: WebPParameters,
     pub tiff: TiffParameters,
     pub keep_metadata: bool,
+    pub convert_to_srgb: bool,
+    pub embed_srgb_profile: bool,
+    pub keep_dpi: bool,
+    pub dpi: Option<Dpi>,
+    pub to_grayscale: bool,
+    pub detect_grayscale: bool,
+    pub grayscale_tolerance: u8,
     pub width: u32,
     pub height: u32,
 }
@@ -142,25 +509,77 @@ impl CSParameters {
 fn initialize_parameters() -> CSParameters {
     let jpeg = JpegParameters {
         quality: 80,
+        chroma_quality: None,
         chroma_subsampling: ChromaSubsampling::Auto,
         progressive: true,
+        optimize_scans: true,
+        dc_scan_mode: JpegDcScanMode::PerComponent,
+        scan_script: None,
         optimize: false,
         preserve_icc: true,
+        cmyk_to_rgb: false,
+        source_quality_policy: JpegSourceQualityPolicy::Ignore,
+        recover_corrupted: false,
+        keep_gain_map: true,
+        requantize: false,
+        profile: JpegProfile::MaxCompression,
+        quant_table: JpegQuantTable::ImageMagick,
+        trellis: true,
+        tune: JpegTune::PsnrHvs,
+        overshoot_deringing: true,
+        smoothing: 0,
+        restart_interval: 0,
+        arithmetic_coding: false,
     };
     let png = PngParameters {
         quality: 80,
         force_zopfli: false,
         optimization_level: 3,
         optimize: false,
+        max_colors: 256,
+        dithering_level: 1.0,
+        speed: 4,
+        min_quality: 0,
+        posterization: 0,
+        keep_16_bit: true,
+        dither_16_bit: false,
+        filters: None,
+        interlace: PngInterlace::None,
+        optimize_alpha: false,
+        bit_depth_reduction: true,
+        color_type_reduction: true,
+        palette_reduction: true,
+        strip_chunks: None,
+        keep_chunks: PNG_COLOR_CHUNKS.to_vec(),
+        deflater: None,
+        timeout: None,
     };
     let gif = GifParameters { quality: 80 };
     let webp = WebPParameters {
         quality: 80,
         lossless: false,
+        method: 4,
+        preset: WebPPreset::Default,
+        sns_strength: None,
+        filter_strength: None,
+        filter_sharpness: None,
+        segments: 4,
+        pass: 1,
+        alpha_quality: 100,
+        alpha_filtering: WebPAlphaFiltering::Fast,
+        near_lossless: 100,
+        exact: false,
+        use_sharp_yuv: false,
+        target_psnr: 0.0,
+        target_size: 0,
+        allow_mixed: false,
+        kmin: None,
+        kmax: None,
     };
     let tiff = TiffParameters {
         algorithm: Deflate,
         deflate_level: TiffDeflateLevel::Balanced,
+        pages: None,
     };
 
     CSParameters {
@@ -170,6 +589,13 @@ fn initialize_parameters() -> CSParameters {
         webp,
         tiff,
         keep_metadata: false,
+        convert_to_srgb: false,
+        embed_srgb_profile: false,
+        keep_dpi: true,
+        dpi: None,
+        to_grayscale: false,
+        detect_grayscale: true,
+        grayscale_tolerance: 0,
         width: 0,
         height: 0,
     }
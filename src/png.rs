@@ -1,30 +1,34 @@
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Cursor, Write};
 use std::num::NonZeroU8;
 
+use crate::density::{output_dpi, set_png_dpi};
 use crate::error::CaesiumError;
+use crate::grayscale::{encode_as_grayscale, luma, to_grayscale};
+use crate::icc::{convert_image_to_srgb, converted_profile, needs_srgb_conversion};
+use crate::parameters::{PngDeflater, PngFilter, PngInterlace};
 use crate::resize::resize;
 use crate::CSParameters;
-use image::ImageFormat;
+use image::{DynamicImage, ImageBuffer, ImageFormat};
 use imagequant::RGBA;
-use oxipng::Deflaters::Zopfli;
+use oxipng::{Deflaters, Interlacing, RowFilter, StripChunks};
 
 use bytes::Bytes;
-use img_parts::png::Png as PartsPng;
+use img_parts::png::{Png as PartsPng, PngChunk};
 use img_parts::{ImageEXIF, ImageICC};
 
-pub fn compress(input_path: String, output_path: String, parameters: &CSParameters) -> Result<(), CaesiumError> {
-    let mut in_file = fs::read(input_path).map_err(|e| CaesiumError {
+pub fn compress(
+    input_path: String,
+    output_path: String,
+    parameters: &CSParameters,
+) -> Result<Vec<CaesiumError>, CaesiumError> {
+    let in_file = fs::read(input_path).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20200,
     })?;
 
-    if parameters.width > 0 || parameters.height > 0 {
-        in_file = resize(&in_file, parameters.width, parameters.height, ImageFormat::Png)?;
-    }
-
-    let optimized_png = compress_in_memory(&in_file, parameters)?;
+    let (optimized_png, warnings) = compress_in_memory_with_warnings(&in_file, parameters)?;
     let mut output_file_buffer = File::create(output_path).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20202,
@@ -36,39 +40,89 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
             code: 20203,
         })?;
 
-    Ok(())
+    Ok(warnings)
 }
 
-pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    if parameters.width > 0 || parameters.height > 0 {
-        let input = resize(in_file, parameters.width, parameters.height, ImageFormat::Png)?;
+/// Compresses a PNG in memory, also returning a warning when the precision of the channels was reduced.
+pub fn compress_in_memory_with_warnings(
+    in_file: &[u8],
+    parameters: &CSParameters,
+) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    //pHYs goes with the other metadata, and both the color conversion and the resize encode the image again
+    let dpi = output_dpi(in_file, parameters);
+    let (output, warnings) = optimize(in_file, parameters)?;
+    let output = match dpi {
+        Some(dpi) => set_png_dpi(output, dpi)?,
+        None => output,
+    };
+    Ok((output, warnings))
+}
 
-        if parameters.png.optimize {
-            Ok(lossless(&input, parameters)?)
-        } else {
-            Ok(lossy(&input, parameters)?)
-        }
-    } else if parameters.png.optimize {
-        Ok(lossless(in_file, parameters)?)
+fn optimize(in_file: &[u8], parameters: &CSParameters) -> Result<(Vec<u8>, Vec<CaesiumError>), CaesiumError> {
+    let converted = if parameters.convert_to_srgb {
+        convert_to_srgb(in_file, parameters)?
     } else {
-        Ok(lossy(in_file, parameters)?)
-    }
-}
+        None
+    };
+    let in_file = converted.as_deref().unwrap_or(in_file);
 
-fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let (iccp, exif) = if parameters.keep_metadata {
-        extract_metadata(in_file)
+    let resized = if parameters.width > 0 || parameters.height > 0 {
+        let resized = resize(in_file, parameters.width, parameters.height, ImageFormat::Png)?;
+        Some(copy_chunks(in_file, resized, parameters, |_| true)?)
     } else {
-        (None, None)
+        None
     };
+    let in_file = resized.as_deref().unwrap_or(in_file);
 
-    let rgba_bitmap = lodepng::decode32(in_file).map_err(|e| CaesiumError {
+    //Palettes only hold 8 bits per channel
+    let mut warnings = vec![];
+    let reduced = if is_16_bit(in_file) && (!parameters.png.optimize || !parameters.png.keep_16_bit) {
+        warnings.push(CaesiumError {
+            message: "16-bit channels were reduced to 8 bits".to_string(),
+            code: 20217,
+        });
+        Some(reduce_to_8_bit(in_file, parameters)?)
+    } else {
+        None
+    };
+    let in_file = reduced.as_deref().unwrap_or(in_file);
+
+    let output = if parameters.png.optimize {
+        lossless(in_file, parameters)?
+    } else {
+        lossy(in_file, parameters)?
+    };
+    Ok((output, warnings))
+}
+
+fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let mut rgba_bitmap = lodepng::decode32(in_file).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20204,
     })?;
+    //The output is always a palette, made of grays only
+    let grayscale = encode_as_grayscale(parameters, false, |tolerance| {
+        let is_gray = |px: &RGBA| px.r.max(px.g).max(px.b) - px.r.min(px.g).min(px.b) <= tolerance;
+        rgba_bitmap.buffer.iter().all(is_gray)
+    });
+    if grayscale {
+        for px in rgba_bitmap.buffer.iter_mut() {
+            let gray = luma(px.r, px.g, px.b);
+            (px.r, px.g, px.b) = (gray, gray, gray);
+        }
+    }
 
+    let png_parameters = &parameters.png;
     let mut liq = imagequant::new();
-    liq.set_quality(0, parameters.png.quality as u8)
+    liq.set_max_colors(png_parameters.max_colors)
+        .and_then(|_| liq.set_speed(png_parameters.speed as i32))
+        .and_then(|_| liq.set_min_posterization(png_parameters.posterization))
+        .and_then(|_| {
+            liq.set_quality(
+                png_parameters.min_quality.min(png_parameters.quality) as u8,
+                png_parameters.quality as u8,
+            )
+        })
         .map_err(|e| CaesiumError {
             message: e.to_string(),
             code: 20205,
@@ -86,10 +140,23 @@ fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumEr
             code: 20206,
         })?;
 
-    let mut quantization = liq.quantize(&mut liq_image).map_err(|e| CaesiumError {
-        message: e.to_string(),
-        code: 20207,
-    })?;
+    let mut quantization = match liq.quantize(&mut liq_image) {
+        Ok(quantization) => quantization,
+        //The palette would not reach the minimum quality
+        Err(imagequant::Error::QualityTooLow) => return lossless(in_file, parameters),
+        Err(e) => {
+            return Err(CaesiumError {
+                message: e.to_string(),
+                code: 20207,
+            })
+        }
+    };
+    quantization
+        .set_dithering_level(png_parameters.dithering_level)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20207,
+        })?;
 
     let (palette, pixels) = quantization.remapped(&mut liq_image).map_err(|e| CaesiumError {
         message: e.to_string(),
@@ -119,32 +186,195 @@ fn lossy(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumEr
             code: 20209,
         })?;
 
-    if parameters.keep_metadata && (iccp.is_some() || exif.is_some()) {
-        return save_metadata(png_vec, iccp, exif);
+    //Color profiles do not apply to gray data
+    let png_vec = copy_chunks(in_file, png_vec, parameters, |name| {
+        !grayscale || !matches!(name, b"iCCP" | b"sRGB")
+    })?;
+    //oxipng drops sBIT when it reduces the bit depth of the palette, whose entries still have the same precision
+    let quantized = copy_chunks(in_file, optimize_deflate(&png_vec, parameters)?, parameters, |name| {
+        name == b"sBIT"
+    })?;
+
+    //Images with few colors, or already well compressed, can be smaller without losing anything
+    let lossless = lossless(in_file, parameters)?;
+    if lossless.len() <= quantized.len() {
+        return Ok(lossless);
     }
 
-    Ok(png_vec)
+    Ok(quantized)
 }
 
 fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let mut oxipng_options = oxipng::Options::default();
-    if !parameters.keep_metadata {
-        oxipng_options.strip = oxipng::StripChunks::Safe;
+    //Exactly neutral images are already reduced to gray by oxipng
+    let converted = if parameters.to_grayscale {
+        Some(convert_to_grayscale(in_file, parameters)?)
+    } else {
+        None
+    };
+    let in_file = converted.as_deref().unwrap_or(in_file);
+
+    optimize_deflate(in_file, parameters)
+}
+
+/// Ancillary chunks kept by oxipng to display the image correctly, even when stripping the metadata. pHYs follows
+/// `keep_dpi` instead, written back after the optimization.
+const DISPLAY_CHUNKS: [[u8; 4]; 6] = [*b"cICP", *b"iCCP", *b"sRGB", *b"acTL", *b"fcTL", *b"fdAT"];
+
+/// Whether an ancillary chunk has to be kept: with `keep_metadata` all of them are, otherwise the ones needed for
+/// display and `keep_chunks`. An explicit `strip_chunks` list takes precedence.
+fn keeps_chunk(name: &[u8; 4], parameters: &CSParameters) -> bool {
+    match &parameters.png.strip_chunks {
+        Some(chunks) => !chunks.contains(name),
+        None => parameters.keep_metadata || DISPLAY_CHUNKS.contains(name) || parameters.png.keep_chunks.contains(name),
     }
+}
 
-    if parameters.png.optimize && parameters.png.force_zopfli {
-        let mut iterations = 15;
-        if in_file.len() > 2000000 {
-            iterations = 5;
+fn strip_chunks(parameters: &CSParameters) -> StripChunks {
+    match &parameters.png.strip_chunks {
+        //C2PA signatures do not survive the optimization, oxipng refuses to keep them
+        Some(chunks) => StripChunks::Strip(chunks.iter().chain(&[*b"caBX"]).copied().collect()),
+        None if parameters.keep_metadata => StripChunks::None,
+        None => StripChunks::Keep(
+            DISPLAY_CHUNKS
+                .iter()
+                .chain(&parameters.png.keep_chunks)
+                .copied()
+                .collect(),
+        ),
+    }
+}
+
+/// Copies the ancillary chunks of `source` that have to be kept to `image`, encoded again from it. sBIT is rewritten for
+/// the color type of `image`, the other chunks depending on it or on the animation frames are dropped, as they no
+/// longer match the image data.
+fn copy_chunks(
+    source: &[u8],
+    image: Vec<u8>,
+    parameters: &CSParameters,
+    filter: impl Fn(&[u8; 4]) -> bool,
+) -> Result<Vec<u8>, CaesiumError> {
+    let Ok(source) = PartsPng::from_bytes(Bytes::copy_from_slice(source)) else {
+        return Ok(image);
+    };
+    let mut png = PartsPng::from_bytes(Bytes::from(image)).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20210,
+    })?;
+
+    let (mut before_data, mut after_data) = (vec![], vec![]);
+    let mut data_found = false;
+    for chunk in source.chunks() {
+        let name = chunk.kind();
+        data_found |= name == *b"IDAT";
+        if name == *b"sBIT" && keeps_chunk(&name, parameters) && filter(&name) {
+            let significant_bits = source
+                .chunk_by_type(*b"IHDR")
+                .zip(png.chunk_by_type(*b"IHDR"))
+                .and_then(|(from, to)| fit_significant_bits(chunk.contents(), from.contents(), to.contents()));
+            if let Some(significant_bits) = significant_bits {
+                before_data.push(PngChunk::new(name, Bytes::from(significant_bits)));
+            }
+            continue;
+        }
+        let copied = !matches!(
+            &name,
+            b"IHDR" | b"PLTE" | b"IDAT" | b"IEND" | b"tRNS" | b"bKGD" | b"sBIT" | b"hIST" | b"acTL" | b"fcTL" | b"fdAT"
+        ) && keeps_chunk(&name, parameters)
+            && filter(&name);
+        if copied && data_found {
+            after_data.push(chunk.clone());
+        } else if copied {
+            before_data.push(chunk.clone());
         }
-        oxipng_options.deflate = Zopfli {
-            iterations: NonZeroU8::new(iterations).unwrap(),
-        };
-    } else {
-        let optimization_level = parameters.png.optimization_level.clamp(0, 6);
-        oxipng_options = oxipng::Options::from_preset(optimization_level);
     }
 
+    let chunks = png.chunks_mut();
+    chunks.retain(|c| !before_data.iter().chain(&after_data).any(|k| k.kind() == c.kind()));
+    //Right after IHDR all of them are valid, even the ones that must precede PLTE
+    chunks.splice(1..1, before_data);
+    let end = chunks.len() - 1;
+    chunks.splice(end..end, after_data);
+
+    let mut output = Vec::new();
+    png.encoder().write_to(&mut output).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20211,
+    })?;
+    Ok(output)
+}
+
+/// Converts the sBIT contents of an image with the `from` header to the channels of one with the `to` header, with a
+/// gray channel as significant as the most significant color one. Palettes have 8-bit RGB channels and no alpha.
+fn fit_significant_bits(significant_bits: &[u8], from: &[u8], to: &[u8]) -> Option<Vec<u8>> {
+    let (from_depth, from_color_type) = (*from.get(8)?, *from.get(9)?);
+    let (to_depth, to_color_type) = (*to.get(8)?, *to.get(9)?);
+    let from_depth = if from_color_type == 3 { 8 } else { from_depth };
+    let (rgb, alpha) = match (from_color_type, significant_bits) {
+        (0, [g]) => ([*g; 3], from_depth),
+        (2 | 3, [r, g, b]) => ([*r, *g, *b], from_depth),
+        (4, [g, a]) => ([*g; 3], *a),
+        (6, [r, g, b, a]) => ([*r, *g, *b], *a),
+        _ => return None,
+    };
+
+    let to_depth = if to_color_type == 3 { 8 } else { to_depth };
+    let gray = rgb.into_iter().max()?;
+    let channels = match to_color_type {
+        0 => vec![gray],
+        2 | 3 => rgb.to_vec(),
+        4 => vec![gray, alpha],
+        6 => vec![rgb[0], rgb[1], rgb[2], alpha],
+        _ => return None,
+    };
+    Some(channels.into_iter().map(|bits| bits.clamp(1, to_depth)).collect())
+}
+
+/// Runs oxipng with the preset of `optimization_level`, overridden by the options set in `PngParameters`.
+fn optimize_deflate(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let png_parameters = &parameters.png;
+    let optimization_level = png_parameters.optimization_level.clamp(0, 6);
+    let mut oxipng_options = oxipng::Options::from_preset(optimization_level);
+    oxipng_options.strip = strip_chunks(parameters);
+
+    if let Some(filters) = &png_parameters.filters {
+        oxipng_options.filter = filters.iter().map(|f| row_filter(*f)).collect();
+    }
+    oxipng_options.interlace = match png_parameters.interlace {
+        PngInterlace::None => Some(Interlacing::None),
+        PngInterlace::Adam7 => Some(Interlacing::Adam7),
+        PngInterlace::Keep => None,
+    };
+
+    match png_parameters.deflater {
+        Some(PngDeflater::Libdeflater { level }) => {
+            oxipng_options.deflate = Deflaters::Libdeflater {
+                compression: level.min(12),
+            }
+        }
+        Some(PngDeflater::Zopfli { iterations }) => {
+            oxipng_options.deflate = Deflaters::Zopfli {
+                iterations: NonZeroU8::new(iterations).unwrap_or(NonZeroU8::MIN),
+            }
+        }
+        None if png_parameters.force_zopfli => {
+            let mut iterations = 15;
+            if in_file.len() > 2000000 {
+                iterations = 5;
+            }
+            oxipng_options.deflate = Deflaters::Zopfli {
+                iterations: NonZeroU8::new(iterations).unwrap(),
+            };
+        }
+        None => {}
+    }
+
+    oxipng_options.optimize_alpha = png_parameters.optimize_alpha;
+    oxipng_options.bit_depth_reduction = png_parameters.bit_depth_reduction;
+    oxipng_options.color_type_reduction = png_parameters.color_type_reduction;
+    oxipng_options.palette_reduction = png_parameters.palette_reduction;
+    oxipng_options.grayscale_reduction = parameters.detect_grayscale;
+    oxipng_options.timeout = png_parameters.timeout;
+
     let optimized_png = oxipng::optimize_from_memory(in_file, &oxipng_options).map_err(|e| CaesiumError {
         message: e.to_string(),
         code: 20210,
@@ -153,6 +383,159 @@ fn lossless(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, Caesiu
     Ok(optimized_png)
 }
 
+fn row_filter(filter: PngFilter) -> RowFilter {
+    match filter {
+        PngFilter::None => RowFilter::None,
+        PngFilter::Sub => RowFilter::Sub,
+        PngFilter::Up => RowFilter::Up,
+        PngFilter::Average => RowFilter::Average,
+        PngFilter::Paeth => RowFilter::Paeth,
+        PngFilter::MinSum => RowFilter::MinSum,
+        PngFilter::Entropy => RowFilter::Entropy,
+        PngFilter::Bigrams => RowFilter::Bigrams,
+        PngFilter::BigEnt => RowFilter::BigEnt,
+        PngFilter::Brute => RowFilter::Brute,
+    }
+}
+
+fn is_16_bit(in_file: &[u8]) -> bool {
+    //Bit depth field of IHDR, after the signature, the chunk header, the width and the height
+    in_file.get(24) == Some(&16)
+}
+
+fn reduce_to_8_bit(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let dither = parameters.png.dither_16_bit;
+    let image = image::load_from_memory_with_format(in_file, ImageFormat::Png).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20218,
+    })?;
+
+    let (width, height) = (image.width(), image.height());
+    let reduced = match image {
+        DynamicImage::ImageLuma16(i) => {
+            ImageBuffer::from_raw(width, height, to_8_bit(&i, width, 1, dither)).map(DynamicImage::ImageLuma8)
+        }
+        DynamicImage::ImageLumaA16(i) => {
+            ImageBuffer::from_raw(width, height, to_8_bit(&i, width, 2, dither)).map(DynamicImage::ImageLumaA8)
+        }
+        DynamicImage::ImageRgb16(i) => {
+            ImageBuffer::from_raw(width, height, to_8_bit(&i, width, 3, dither)).map(DynamicImage::ImageRgb8)
+        }
+        DynamicImage::ImageRgba16(i) => {
+            ImageBuffer::from_raw(width, height, to_8_bit(&i, width, 4, dither)).map(DynamicImage::ImageRgba8)
+        }
+        image => Some(image),
+    };
+
+    let Some(reduced) = reduced else {
+        return Err(CaesiumError {
+            message: "Invalid image buffer".to_string(),
+            code: 20218,
+        });
+    };
+
+    let mut png_vec = vec![];
+    reduced
+        .write_to(&mut Cursor::new(&mut png_vec), ImageFormat::Png)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20219,
+        })?;
+
+    copy_chunks(in_file, png_vec, parameters, |_| true)
+}
+
+/// Reduces interleaved 16-bit channels to 8 bits. When dithering, the rounding error of the color channels is spread
+/// on the neighbouring pixels with Floyd-Steinberg, while alpha is always rounded.
+fn to_8_bit(pixels: &[u16], width: u32, channels: usize, dither: bool) -> Vec<u8> {
+    let round = |value: i32| ((value + 128) / 257) as u8;
+    if !dither {
+        return pixels.iter().map(|v| round(*v as i32)).collect();
+    }
+
+    let color_channels = if channels.is_multiple_of(2) {
+        channels - 1
+    } else {
+        channels
+    };
+    let row_length = width as usize * channels;
+    //Errors for the current and the next row, scaled by 16
+    let mut errors = vec![0i32; row_length * 2];
+    let mut output = Vec::with_capacity(pixels.len());
+    for row in pixels.chunks_exact(row_length) {
+        let (current, next) = errors.split_at_mut(row_length);
+        for (i, value) in row.iter().enumerate() {
+            if i % channels >= color_channels {
+                output.push(round(*value as i32));
+                continue;
+            }
+
+            let value = (*value as i32 + current[i] / 16).clamp(0, u16::MAX as i32);
+            let reduced = round(value);
+            let error = value - reduced as i32 * 257;
+            if i + channels < row_length {
+                current[i + channels] += error * 7;
+                next[i + channels] += error;
+            }
+            if i >= channels {
+                next[i - channels] += error * 3;
+            }
+            next[i] += error * 5;
+            output.push(reduced);
+        }
+        current.copy_from_slice(next);
+        next.fill(0);
+    }
+
+    output
+}
+
+fn convert_to_srgb(in_file: &[u8], parameters: &CSParameters) -> Result<Option<Vec<u8>>, CaesiumError> {
+    let (iccp, exif) = extract_metadata(in_file);
+    let Some(iccp) = iccp.filter(|i| needs_srgb_conversion(Some(i))) else {
+        return Ok(None);
+    };
+
+    let image = image::load_from_memory_with_format(in_file, ImageFormat::Png).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20213,
+    })?;
+    let image = convert_image_to_srgb(image, &iccp)?;
+
+    let mut png_vec = vec![];
+    image
+        .write_to(&mut Cursor::new(&mut png_vec), ImageFormat::Png)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20214,
+        })?;
+
+    let exif = exif.filter(|_| parameters.keep_metadata);
+    Ok(Some(save_metadata(png_vec, converted_profile(parameters), exif)?))
+}
+
+fn convert_to_grayscale(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let image = image::load_from_memory_with_format(in_file, ImageFormat::Png).map_err(|e| CaesiumError {
+        message: e.to_string(),
+        code: 20215,
+    })?;
+
+    let mut png_vec = vec![];
+    to_grayscale(image)
+        .write_to(&mut Cursor::new(&mut png_vec), ImageFormat::Png)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20216,
+        })?;
+
+    //A color profile does not apply to gray data
+    let (_, exif) = extract_metadata(in_file);
+    match exif.filter(|_| parameters.keep_metadata) {
+        Some(exif) => save_metadata(png_vec, None, Some(exif)),
+        None => Ok(png_vec),
+    }
+}
+
 fn extract_metadata(image: &[u8]) -> (Option<Bytes>, Option<Bytes>) {
     let Ok(png) = PartsPng::from_bytes(Bytes::from(image.to_vec())) else {
         return (None, None);
@@ -12,16 +12,7 @@ pub fn resize(
     height: u32,
     format: image::ImageFormat,
 ) -> Result<Vec<u8>, CaesiumError> {
-    let (mut desired_width, mut desired_height) = (width, height);
-    if format == image::ImageFormat::Jpeg {
-        let orientation = get_jpeg_orientation(image_buffer);
-        (desired_width, desired_height) = match orientation {
-            5..=8 => (height, width),
-            _ => (width, height),
-        };
-    }
-
-    let mut image = ImageReader::new(Cursor::new(image_buffer))
+    let image = ImageReader::new(Cursor::new(image_buffer))
         .with_guessed_format()
         .map_err(|e| CaesiumError {
             message: e.to_string(),
@@ -33,6 +24,26 @@ pub fn resize(
             code: 10301,
         })?;
 
+    resize_decoded(image, image_buffer, width, height, format)
+}
+
+/// Same as `resize`, for an image already decoded from `image_buffer`.
+pub fn resize_decoded(
+    mut image: DynamicImage,
+    image_buffer: &[u8],
+    width: u32,
+    height: u32,
+    format: image::ImageFormat,
+) -> Result<Vec<u8>, CaesiumError> {
+    let (mut desired_width, mut desired_height) = (width, height);
+    if format == image::ImageFormat::Jpeg {
+        let orientation = get_jpeg_orientation(image_buffer);
+        (desired_width, desired_height) = match orientation {
+            5..=8 => (height, width),
+            _ => (width, height),
+        };
+    }
+
     let dimensions = compute_dimensions(image.width(), image.height(), desired_width, desired_height);
     image = image.resize_exact(dimensions.0, dimensions.1, FilterType::Lanczos3);
 
@@ -1,14 +1,21 @@
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::{Cursor, Read, Write};
+use std::io::{Cursor, Read, Seek, Write};
 use std::panic;
 
-use image::ImageFormat::Tiff;
-use tiff::encoder::colortype::{RGB8, RGBA8};
-use tiff::encoder::compression::{Deflate, DeflateLevel, Lzw, Packbits, Uncompressed};
-use tiff::encoder::TiffEncoder;
+use bytes::Bytes;
+use image::{imageops, ColorType, DynamicImage, ImageBuffer, Pixel, RgbaImage};
+use tiff::decoder::ifd::Value;
+use tiff::decoder::{Decoder, DecodingResult, IfdDecoder};
+use tiff::encoder::colortype::{self, Gray16, Gray8, RGB32Float, RGBA32Float, CMYK8, RGB16, RGB8, RGBA16, RGBA8};
+use tiff::encoder::{Compression, DeflateLevel, Rational, TiffEncoder, TiffValue};
+use tiff::tags::{ByteOrder, ExtraSamples, ResolutionUnit, Tag, Type, ValueBuffer};
+use tiff::{ColorType as TiffColorType, TiffError, TiffResult};
 
 use crate::error::CaesiumError;
-use crate::parameters::TiffCompression;
+use crate::grayscale::{encode_as_grayscale, is_grayscale, to_grayscale};
+use crate::icc::{convert_cmyk_to_srgb, convert_image_to_srgb, converted_profile, needs_srgb_conversion};
+use crate::parameters::{Dpi, TiffCompression};
 use crate::resize::resize_image;
 use crate::{CSParameters, TiffDeflateLevel};
 
@@ -38,78 +45,454 @@ pub fn compress(input_path: String, output_path: String, parameters: &CSParamete
     Ok(())
 }
 
-pub fn compress_in_memory(in_file: &Vec<u8>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
-    let decoding_result = match panic::catch_unwind(|| image::load_from_memory_with_format(in_file.as_slice(), Tiff)) {
-        Ok(i) => i,
-        Err(_) => {
-            return Err(CaesiumError {
-                message: "Failed to decode TIFF image".to_string(),
-                code: 20504,
-            });
+pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let pages = decode_pages(in_file, parameters)?;
+    encode_pages(pages, parameters)
+}
+
+/// Compresses each page in `tiff.pages` to its own single-page TIFF, in page order.
+pub fn split_pages(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<Vec<u8>>, CaesiumError> {
+    decode_pages(in_file, parameters)?
+        .into_iter()
+        .map(|page| encode_pages(vec![page], parameters))
+        .collect()
+}
+
+/// Decodes the pages in `tiff.pages` to canvases of the same size, each page at the top left of a transparent canvas
+/// as large as the biggest one.
+pub fn decode_canvases(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<RgbaImage>, CaesiumError> {
+    let pages = decode_pages(in_file, parameters)?;
+    let width = pages.iter().map(|p| p.image.width()).max().unwrap_or(0);
+    let height = pages.iter().map(|p| p.image.height()).max().unwrap_or(0);
+
+    pages
+        .into_iter()
+        .map(|page| {
+            let rgba = if page.cmyk {
+                let rgb = convert_cmyk_to_srgb(page.image.as_bytes(), false, page.icc.as_deref())?;
+                ImageBuffer::from_raw(page.image.width(), page.image.height(), rgb)
+                    .map(|rgb| DynamicImage::ImageRgb8(rgb).to_rgba8())
+                    .ok_or(CaesiumError {
+                        message: "Cannot create image buffer after color conversion".into(),
+                        code: 10602,
+                    })?
+            } else {
+                page.image.to_rgba8()
+            };
+            let mut canvas = RgbaImage::new(width, height);
+            imageops::overlay(&mut canvas, &rgba, 0, 0);
+            Ok(canvas)
+        })
+        .collect()
+}
+
+/// A page with its color profile, resolution and metadata. CMYK pages hold their inks in the four channels of an
+/// `Rgba8` image.
+struct Page {
+    image: DynamicImage,
+    cmyk: bool,
+    icc: Option<Bytes>,
+    resolution: Vec<(Tag, ValueBuffer)>,
+    metadata: Metadata,
+}
+
+/// Tags copied from the source page, the ones pointing to a directory come with the tags of that directory.
+#[derive(Default)]
+struct Metadata {
+    tags: Vec<(Tag, ValueBuffer)>,
+    directories: Vec<(Tag, Vec<(Tag, ValueBuffer)>)>,
+}
+
+fn decode_pages(in_file: &[u8], parameters: &CSParameters) -> Result<Vec<Page>, CaesiumError> {
+    let (first_page, last_page) = parameters.tiff.pages.unwrap_or((0, u32::MAX));
+    let decoding_result = panic::catch_unwind(|| -> Result<Vec<Page>, CaesiumError> {
+        let mut decoder = Decoder::new(Cursor::new(in_file)).map_err(decoding_error)?;
+        let mut pages = vec![];
+        for index in 0..=last_page {
+            if index >= first_page {
+                let icc = tag_bytes(&mut decoder, Tag::Unknown(ICC_PROFILE_TAG)).map(Bytes::from);
+                //Pages of the same file can have different resolutions
+                let resolution = if parameters.dpi.is_none() && parameters.keep_dpi {
+                    read_tags(&mut decoder.image_ifd(), RESOLUTION_TAGS)
+                } else {
+                    vec![]
+                };
+                let metadata = if parameters.keep_metadata {
+                    read_metadata(&mut decoder)
+                } else {
+                    Metadata::default()
+                };
+                let (image, cmyk) = decode_page(&mut decoder)?;
+                pages.push(Page {
+                    image,
+                    cmyk,
+                    icc,
+                    resolution,
+                    metadata,
+                });
+            }
+            if !decoder.more_images() {
+                break;
+            }
+            decoder.next_image().map_err(decoding_error)?;
         }
+        Ok(pages)
+    });
+    let Ok(pages) = decoding_result else {
+        return Err(CaesiumError {
+            message: "Failed to decode TIFF image".to_string(),
+            code: 20504,
+        });
     };
-    let mut image = match decoding_result {
-        Ok(i) => i,
-        Err(e) => {
-            return Err(CaesiumError {
-                message: e.to_string(),
-                code: 20504,
-            })
+    let pages = pages?;
+    if pages.is_empty() {
+        return Err(CaesiumError {
+            message: format!("The TIFF image has no page {first_page}"),
+            code: 20508,
+        });
+    }
+
+    //The color profile of the output replaces the one of the source
+    pages
+        .into_iter()
+        .map(|mut page| {
+            if parameters.width > 0 || parameters.height > 0 {
+                page.image = resize_image(page.image, parameters.width, parameters.height);
+            }
+
+            //A CMYK profile cannot be replaced without converting the inks, so it stays with them
+            if page.cmyk {
+                return Ok(page);
+            }
+
+            let icc = page.icc.take();
+            page.icc = icc.clone().filter(|_| parameters.keep_metadata);
+            if let Some(icc) = icc.filter(|i| parameters.convert_to_srgb && needs_srgb_conversion(Some(i))) {
+                page.image = convert_image_to_srgb(page.image, &icc)?;
+                page.icc = converted_profile(parameters);
+            }
+
+            Ok(page)
+        })
+        .collect()
+}
+
+/// Decodes the current page, also returning whether it holds CMYK inks.
+fn decode_page<R: Read + Seek>(decoder: &mut Decoder<R>) -> Result<(DynamicImage, bool), CaesiumError> {
+    let (width, height) = decoder.dimensions().map_err(decoding_error)?;
+    let color_type = decoder.colortype().map_err(decoding_error)?;
+    let image = match (color_type, decoder.read_image().map_err(decoding_error)?) {
+        (TiffColorType::Gray(1), DecodingResult::U8(v)) => {
+            //Rows of packed bits, padded to a whole byte
+            let row_bytes = width.div_ceil(8) as usize;
+            let pixels = v
+                .chunks(row_bytes)
+                .flat_map(|row| (0..width as usize).map(move |x| ((row[x / 8] >> (7 - x % 8)) & 1) * u8::MAX))
+                .collect();
+            ImageBuffer::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8)
+        }
+        (TiffColorType::Gray(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLuma8)
+        }
+        (TiffColorType::Gray(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLuma16)
+        }
+        (
+            TiffColorType::GrayA(8)
+            | TiffColorType::Multiband {
+                bit_depth: 8,
+                num_samples: 2,
+            },
+            DecodingResult::U8(v),
+        ) => ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLumaA8),
+        (
+            TiffColorType::GrayA(16)
+            | TiffColorType::Multiband {
+                bit_depth: 16,
+                num_samples: 2,
+            },
+            DecodingResult::U16(v),
+        ) => ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageLumaA16),
+        (TiffColorType::RGB(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgb8)
+        }
+        (TiffColorType::RGB(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgb16)
+        }
+        (TiffColorType::RGB(32), DecodingResult::F32(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgb32F)
         }
+        (TiffColorType::RGBA(8), DecodingResult::U8(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgba8)
+        }
+        (TiffColorType::RGBA(16), DecodingResult::U16(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgba16)
+        }
+        (TiffColorType::RGBA(32), DecodingResult::F32(v)) => {
+            ImageBuffer::from_raw(width, height, v).map(DynamicImage::ImageRgba32F)
+        }
+        (TiffColorType::CMYK(8), DecodingResult::U8(v)) => {
+            return ImageBuffer::from_raw(width, height, v)
+                .map(|inks| (DynamicImage::ImageRgba8(inks), true))
+                .ok_or(CaesiumError {
+                    message: "Invalid TIFF CMYK buffer".to_string(),
+                    code: 20504,
+                });
+        }
+        _ => None,
     };
 
-    if parameters.width > 0 || parameters.height > 0 {
-        image = resize_image(image, parameters.width, parameters.height);
+    image.map(|image| (image, false)).ok_or(CaesiumError {
+        message: format!("Unsupported TIFF color type ({color_type:?})"),
+        code: 20506,
+    })
+}
+
+/// Reads a BYTE or UNDEFINED tag, which the decoder does not return in the same way.
+fn tag_bytes<R: Read + Seek>(decoder: &mut Decoder<R>, tag: Tag) -> Option<Vec<u8>> {
+    let to_byte = |value: Value| match value {
+        Value::Byte(b) => Some(b),
+        Value::Unsigned(u) => u8::try_from(u).ok(),
+        Value::UnsignedBig(u) => u8::try_from(u).ok(),
+        _ => None,
+    };
+    match decoder.find_tag(tag).ok()?? {
+        Value::List(values) => values.into_iter().map(to_byte).collect(),
+        value => to_byte(value).map(|b| vec![b]),
     }
+}
+
+/// Reads the metadata tags of the current page, in the byte order of the output.
+fn read_metadata<R: Read + Seek>(decoder: &mut Decoder<R>) -> Metadata {
+    let tags = read_tags(&mut decoder.image_ifd(), METADATA_TAGS);
 
-    let color_type = image.color();
+    let mut directories = vec![];
+    for tag in METADATA_DIRECTORIES {
+        let Some(pointer) = decoder
+            .find_tag(tag)
+            .ok()
+            .flatten()
+            .and_then(|v| v.into_ifd_pointer().ok())
+        else {
+            continue;
+        };
+        let Ok(directory) = decoder.read_directory(pointer) else {
+            continue;
+        };
+        //The offsets of nested directories would point to nothing in the output
+        let directory_tags: Vec<Tag> = directory
+            .iter()
+            .filter(|(tag, entry)| {
+                *tag != Tag::Unknown(INTEROPERABILITY_TAG) && !matches!(entry.field_type(), Type::IFD | Type::IFD8)
+            })
+            .map(|(tag, _)| tag)
+            .collect();
+        directories.push((
+            tag,
+            read_tags(&mut decoder.read_directory_tags(&directory), directory_tags),
+        ));
+    }
+
+    Metadata { tags, directories }
+}
+
+fn read_tags(ifd: &mut IfdDecoder, tags: impl IntoIterator<Item = Tag>) -> Vec<(Tag, ValueBuffer)> {
+    tags.into_iter()
+        .filter_map(|tag| {
+            let mut value = ValueBuffer::empty(Type::BYTE);
+            ifd.find_tag_buf(tag, &mut value).ok()??;
+            value.set_byte_order(ByteOrder::native());
+            Some((tag, value))
+        })
+        .collect()
+}
+
+fn decoding_error(e: TiffError) -> CaesiumError {
+    CaesiumError {
+        message: e.to_string(),
+        code: 20504,
+    }
+}
+
+fn encode_pages(pages: Vec<Page>, parameters: &CSParameters) -> Result<Vec<u8>, CaesiumError> {
+    let dpi = parameters.dpi;
+    let compression = match parameters.tiff.algorithm {
+        TiffCompression::Deflate => Compression::Deflate(parse_deflate_level(parameters.tiff.deflate_level)),
+        TiffCompression::Lzw => Compression::Lzw,
+        TiffCompression::Packbits => Compression::Packbits,
+        TiffCompression::Uncompressed => Compression::Uncompressed,
+    };
     let output_buff = vec![];
     let mut output_stream = Cursor::new(output_buff);
-    let mut encoder = TiffEncoder::new(&mut output_stream).map_err(|e| CaesiumError {
-        message: e.to_string(),
-        code: 20505,
-    })?;
+    let mut encoder = TiffEncoder::new(&mut output_stream)
+        .map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20505,
+        })?
+        .with_compression(compression);
 
-    macro_rules! write_with_compression {
-        ($compression:expr) => {
-            match color_type {
-                image::ColorType::Rgb8 => encoder.write_image_with_compression::<RGB8, _>(
-                    image.width(),
-                    image.height(),
-                    $compression,
-                    image.as_bytes(),
-                ),
-                image::ColorType::Rgba8 => encoder.write_image_with_compression::<RGBA8, _>(
-                    image.width(),
-                    image.height(),
-                    $compression,
-                    image.as_bytes(),
-                ),
-                _ => {
-                    return Err(CaesiumError {
-                        message: format!("Unsupported TIFF color type ({color_type:?})"),
-                        code: 20506,
-                    });
-                }
+    for Page {
+        mut image,
+        cmyk,
+        mut icc,
+        resolution,
+        metadata,
+    } in pages
+    {
+        let is_gray = |image: &DynamicImage| {
+            matches!(
+                image.color(),
+                ColorType::L8 | ColorType::L16 | ColorType::La8 | ColorType::La16
+            )
+        };
+        if !cmyk
+            && !is_gray(&image)
+            && encode_as_grayscale(parameters, true, |tolerance| is_grayscale(&image, tolerance))
+        {
+            image = to_grayscale(image);
+            //A color profile does not apply to gray data
+            if is_gray(&image) {
+                icc = None;
+            }
+        }
+
+        let icc = icc.as_deref();
+        let alpha = [ExtraSamples::UnassociatedAlpha];
+        let write_result = match &image {
+            DynamicImage::ImageRgba8(i) if cmyk => {
+                write_image::<CMYK8, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageRgb8(i) => {
+                write_image::<RGB8, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageRgba8(i) => {
+                write_image::<RGBA8, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageRgb16(i) => {
+                write_image::<RGB16, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageRgba16(i) => {
+                write_image::<RGBA16, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageRgb32F(i) => {
+                write_image::<RGB32Float, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageRgba32F(i) => {
+                write_image::<RGBA32Float, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageLuma8(i) => {
+                write_image::<Gray8, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageLuma16(i) => {
+                write_image::<Gray16, _, _>(&mut encoder, i, &[], icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageLumaA8(i) => {
+                write_image::<Gray8, _, _>(&mut encoder, i, &alpha, icc, dpi, &resolution, &metadata)
+            }
+            DynamicImage::ImageLumaA16(i) => {
+                write_image::<Gray16, _, _>(&mut encoder, i, &alpha, icc, dpi, &resolution, &metadata)
+            }
+            _ => {
+                return Err(CaesiumError {
+                    message: format!("Unsupported TIFF color type ({:?})", image.color()),
+                    code: 20506,
+                });
             }
         };
+        write_result.map_err(|e| CaesiumError {
+            message: e.to_string(),
+            code: 20507,
+        })?;
     }
 
-    let compression_result = match parameters.tiff.algorithm {
-        TiffCompression::Deflate => {
-            write_with_compression!(Deflate::with_level(parse_deflate_level(parameters.tiff.deflate_level)))
+    Ok(output_stream.into_inner())
+}
+
+/// Writes `image` as a new page. `extra_samples` describes the channels of `P` past the ones of `C`.
+fn write_image<C, P, W>(
+    encoder: &mut TiffEncoder<W>,
+    image: &ImageBuffer<P, Vec<C::Inner>>,
+    extra_samples: &[ExtraSamples],
+    icc: Option<&[u8]>,
+    dpi: Option<Dpi>,
+    resolution: &[(Tag, ValueBuffer)],
+    metadata: &Metadata,
+) -> TiffResult<()>
+where
+    C: colortype::ColorType,
+    P: Pixel<Subpixel = C::Inner>,
+    [C::Inner]: TiffValue,
+    W: Write + Seek,
+{
+    let mut directories = vec![];
+    for (tag, tags) in &metadata.directories {
+        let mut directory = encoder.extra_directory()?;
+        for (tag, value) in tags {
+            directory.write_tag_buf(*tag, value)?;
         }
-        TiffCompression::Lzw => write_with_compression!(Lzw),
-        TiffCompression::Packbits => write_with_compression!(Packbits),
-        TiffCompression::Uncompressed => write_with_compression!(Uncompressed),
-    };
+        directories.push((*tag, directory.finish_with_offsets()?.offset));
+    }
 
-    match compression_result {
-        Ok(_) => Ok(output_stream.get_ref().to_vec()),
-        Err(e) => Err(CaesiumError {
-            message: e.to_string(),
-            code: 20507,
-        }),
+    let mut tiff_image = encoder.new_image::<C>(image.width(), image.height())?;
+    if !extra_samples.is_empty() {
+        tiff_image.extra_samples(extra_samples)?;
+    }
+    if let Some(icc) = icc {
+        tiff_image
+            .encoder()
+            .write_tag(Tag::Unknown(ICC_PROFILE_TAG), Undefined(icc))?;
+    }
+    if let Some(dpi) = dpi {
+        tiff_image.resolution_unit(ResolutionUnit::Inch);
+        tiff_image.x_resolution(Rational { n: dpi.x, d: 1 });
+        tiff_image.y_resolution(Rational { n: dpi.y, d: 1 });
+    }
+    for (tag, value) in resolution.iter().chain(&metadata.tags) {
+        tiff_image.encoder().write_tag_buf(*tag, value)?;
+    }
+    for (tag, offset) in directories {
+        tiff_image.encoder().write_tag(tag, offset)?;
+    }
+    tiff_image.write_data(image.as_raw())
+}
+
+const ICC_PROFILE_TAG: u16 = 34675;
+const XMP_TAG: u16 = 700;
+const IPTC_TAG: u16 = 33723;
+const INTEROPERABILITY_TAG: u16 = 40965;
+
+/// Tags kept with `keep_metadata`, as they are. The ICC profile is handled with the color conversion.
+const METADATA_TAGS: [Tag; 11] = [
+    Tag::ImageDescription,
+    Tag::Make,
+    Tag::Model,
+    Tag::Software,
+    Tag::DateTime,
+    Tag::Artist,
+    Tag::HostComputer,
+    Tag::Copyright,
+    Tag::Orientation,
+    Tag::Unknown(XMP_TAG),
+    Tag::Unknown(IPTC_TAG),
+];
+/// Kept with `keep_dpi` unless a resolution is requested, for each page.
+const RESOLUTION_TAGS: [Tag; 3] = [Tag::XResolution, Tag::YResolution, Tag::ResolutionUnit];
+/// Tags pointing to a directory of metadata, kept with `keep_metadata`.
+const METADATA_DIRECTORIES: [Tag; 2] = [Tag::ExifDirectory, Tag::GpsDirectory];
+
+/// Opaque byte payload, written with the UNDEFINED field type.
+struct Undefined<'a>(&'a [u8]);
+
+impl TiffValue for Undefined<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
     }
 }
 
@@ -1,14 +1,24 @@
+use std::ffi::CStr;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::ops::Deref;
+use std::mem::MaybeUninit;
+use std::{ptr, slice};
 
 use bytes::Bytes;
-use image::{DynamicImage, ImageBuffer};
+use image::{DynamicImage, ImageBuffer, RgbaImage};
 use img_parts::webp::WebP as PartsWebp;
 use img_parts::{DynImage, ImageEXIF, ImageICC};
-use webp::{AnimDecoder, AnimEncoder, AnimFrame, WebPConfig};
+use libwebp_sys::{
+    WebPAnimEncoder, WebPAnimEncoderAdd, WebPAnimEncoderAssemble, WebPAnimEncoderDelete, WebPAnimEncoderGetError,
+    WebPAnimEncoderNewInternal, WebPAnimEncoderOptions, WebPAnimEncoderOptionsInitInternal, WebPData, WebPDataClear,
+    WebPGetMuxABIVersion, WebPPicture, WebPPictureFree, WebPPictureImportRGBA,
+};
+use webp::{AnimDecoder, AnimFrame, WebPConfig};
 
+use crate::density::{generated_exif_dpi, resolution_exif};
 use crate::error::CaesiumError;
+use crate::icc::{convert_image_to_srgb, converted_profile, needs_srgb_conversion};
+use crate::parameters::WebPPreset;
 use crate::resize::resize_image;
 use crate::CSParameters;
 
@@ -42,7 +52,7 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
     let mut iccp: Option<Bytes> = None;
     let mut exif: Option<Bytes> = None;
 
-    if parameters.keep_metadata {
+    if parameters.keep_metadata || parameters.convert_to_srgb {
         (iccp, exif) = DynImage::from_bytes(in_file.to_vec().into())
             .map_err(|e| CaesiumError {
                 message: e.to_string(),
@@ -51,6 +61,22 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
             .map_or((None, None), |dyn_img| (dyn_img.icc_profile(), dyn_img.exif()));
     }
 
+    let convert_icc = iccp
+        .clone()
+        .filter(|i| parameters.convert_to_srgb && needs_srgb_conversion(Some(i)));
+    if convert_icc.is_some() {
+        iccp = converted_profile(parameters);
+    } else if !parameters.keep_metadata {
+        iccp = None;
+    }
+    if !parameters.keep_metadata {
+        exif = None;
+    }
+    //WebP has no resolution field of its own, so it's stored in EXIF. Kept EXIF data is left untouched
+    if exif.is_none() {
+        exif = generated_exif_dpi(in_file, parameters).map(resolution_exif);
+    }
+
     let must_resize = parameters.width > 0 || parameters.height > 0;
 
     let anim_decoder = AnimDecoder::new(in_file);
@@ -60,65 +86,21 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
     })?;
     let is_animated = frames.has_animation();
 
-    let encoded_image_memory = if is_animated {
-        let mut config = match WebPConfig::new() {
-            Ok(c) => c,
-            Err(_) => {
-                return Err(CaesiumError {
-                    message: "Cannot initialize WebP config".into(),
-                    code: 20309,
-                });
-            }
-        };
-        config.lossless = if parameters.webp.lossless { 1 } else { 0 };
-        config.alpha_compression = if parameters.webp.lossless { 0 } else { 1 };
-        config.quality = parameters.webp.quality as f32;
-
-        let mut images_data = vec![];
-        let mut width = 0;
-        let mut height = 0;
-
-        for (i, f) in frames.into_iter().enumerate() {
+    let encoded_image = if is_animated {
+        let mut canvases = vec![];
+        for f in frames.into_iter() {
+            let end_ms = f.get_time_ms();
+            let mut canvas = to_dynamic_image(f);
             if must_resize {
-                let mut dyn_image = to_dynamic_image(f);
-                dyn_image = resize_image(dyn_image, parameters.width, parameters.height);
-                if i == 0 {
-                    width = dyn_image.width();
-                    height = dyn_image.height();
-                }
-
-                images_data.push(dyn_image);
-            } else {
-                width = f.width();
-                height = f.height();
-                break;
+                canvas = resize_image(canvas, parameters.width, parameters.height);
             }
-        }
-
-        let mut encoder = AnimEncoder::new(width, height, &config);
-        encoder.set_bgcolor(to_rgba(frames.bg_color));
-        encoder.set_loop_count(frames.loop_count as i32);
-
-        let mut last_ms = 0;
-        for (i, f) in frames.into_iter().enumerate() {
-            let delay_ms = f.get_time_ms() - last_ms;
-            last_ms += delay_ms;
-
-            if must_resize {
-                if images_data.get(i).is_some() {
-                    encoder.add_frame(
-                        AnimFrame::from_image(images_data.get(i).unwrap(), last_ms).map_err(|e| CaesiumError {
-                            message: e.to_string(),
-                            code: 20310,
-                        })?,
-                    );
-                }
-            } else {
-                encoder.add_frame(f);
+            if let Some(icc) = &convert_icc {
+                canvas = convert_image_to_srgb(canvas, icc)?;
             }
+            canvases.push((canvas.to_rgba8(), end_ms));
         }
 
-        encoder.encode()
+        encode_animation(&canvases, frames.loop_count, frames.bg_color, parameters)?
     } else {
         let first_frame = match frames.get_frame(0) {
             None => {
@@ -133,6 +115,9 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
         if must_resize {
             input_image = resize_image(input_image, parameters.width, parameters.height);
         }
+        if let Some(icc) = &convert_icc {
+            input_image = convert_image_to_srgb(input_image, icc)?;
+        }
 
         let encoder = match webp::Encoder::from_image(&input_image) {
             Ok(encoder) => encoder,
@@ -144,20 +129,16 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
             }
         };
 
-        if parameters.webp.lossless {
-            if must_resize {
-                encoder.encode(100.0)
-            } else {
-                //TODO With resize can throw an error
-                encoder.encode_lossless()
-            }
-        } else {
-            encoder.encode(parameters.webp.quality as f32)
-        }
+        let config = webp_config(parameters)?;
+        encoder
+            .encode_advanced(&config)
+            .map_err(|e| CaesiumError {
+                message: format!("{e:?}"),
+                code: 20312,
+            })?
+            .to_vec()
     };
 
-    let encoded_image = encoded_image_memory.deref().to_vec();
-
     if iccp.is_some() || exif.is_some() {
         let mut image_with_metadata: Vec<u8> = vec![];
         let mut dyn_img = match PartsWebp::from_bytes(encoded_image.clone().into()) {
@@ -180,13 +161,170 @@ pub fn compress_in_memory(in_file: &[u8], parameters: &CSParameters) -> Result<V
     }
 }
 
-fn to_rgba(value: u32) -> [u8; 4] {
-    [
-        ((value >> 24) & 0xFF) as u8,
-        ((value >> 16) & 0xFF) as u8,
-        ((value >> 8) & 0xFF) as u8,
-        (value & 0xFF) as u8,
-    ]
+fn webp_config(parameters: &CSParameters) -> Result<WebPConfig, CaesiumError> {
+    let webp = parameters.webp;
+    let preset = match webp.preset {
+        WebPPreset::Default => libwebp_sys::WebPPreset::WEBP_PRESET_DEFAULT,
+        WebPPreset::Picture => libwebp_sys::WebPPreset::WEBP_PRESET_PICTURE,
+        WebPPreset::Photo => libwebp_sys::WebPPreset::WEBP_PRESET_PHOTO,
+        WebPPreset::Drawing => libwebp_sys::WebPPreset::WEBP_PRESET_DRAWING,
+        WebPPreset::Icon => libwebp_sys::WebPPreset::WEBP_PRESET_ICON,
+        WebPPreset::Text => libwebp_sys::WebPPreset::WEBP_PRESET_TEXT,
+    };
+    let Ok(mut config) = WebPConfig::new_with_preset(preset, webp.quality as f32) else {
+        return Err(CaesiumError {
+            message: "Cannot initialize WebP config".into(),
+            code: 20309,
+        });
+    };
+
+    config.lossless = if webp.lossless { 1 } else { 0 };
+    config.alpha_compression = if webp.lossless { 0 } else { 1 };
+    config.method = webp.method as i32;
+    if let Some(sns_strength) = webp.sns_strength {
+        config.sns_strength = sns_strength as i32;
+    }
+    if let Some(filter_strength) = webp.filter_strength {
+        config.filter_strength = filter_strength as i32;
+    }
+    if let Some(filter_sharpness) = webp.filter_sharpness {
+        config.filter_sharpness = filter_sharpness as i32;
+    }
+    config.segments = webp.segments as i32;
+    config.pass = webp.pass as i32;
+    config.alpha_quality = webp.alpha_quality as i32;
+    config.alpha_filtering = webp.alpha_filtering as i32;
+    config.near_lossless = webp.near_lossless as i32;
+    config.exact = webp.exact as i32;
+    config.use_sharp_yuv = webp.use_sharp_yuv as i32;
+    config.target_PSNR = webp.target_psnr;
+    config.target_size = webp.target_size.min(i32::MAX as u32) as i32;
+    if (webp.target_psnr > 0.0 || webp.target_size > 0) && webp.pass == 1 {
+        //Rate control refines the quantization on each pass, so the default single one is raised
+        config.pass = 6;
+    }
+
+    Ok(config)
+}
+
+/// Encodes canvases of the same size as the frames of an endless animation, each shown for `frame_duration_ms`.
+pub fn encode_frames(
+    canvases: Vec<RgbaImage>,
+    frame_duration_ms: u32,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    let canvases: Vec<_> = canvases
+        .into_iter()
+        .zip(1..)
+        .map(|(canvas, i)| (canvas, frame_duration_ms.saturating_mul(i).min(i32::MAX as u32) as i32))
+        .collect();
+
+    encode_animation(&canvases, 0, 0, parameters)
+}
+
+/// Encodes the full canvas of each frame, paired with the timestamp its display ends at, with the libwebp animation
+/// encoder. Only the sub-rectangle that changed from the previous canvas is stored, with the blending and disposal
+/// giving the smallest frame.
+fn encode_animation(
+    canvases: &[(RgbaImage, i32)],
+    loop_count: u32,
+    bg_color: u32,
+    parameters: &CSParameters,
+) -> Result<Vec<u8>, CaesiumError> {
+    let mut config = webp_config(parameters)?;
+    //The target size is for the whole file, not for each frame
+    config.target_size = 0;
+
+    let Some((first_canvas, _)) = canvases.first() else {
+        return Err(CaesiumError {
+            message: "Cannot get first frame".into(),
+            code: 20311,
+        });
+    };
+
+    let mut options = MaybeUninit::<WebPAnimEncoderOptions>::uninit();
+    if unsafe { WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), WebPGetMuxABIVersion()) } == 0 {
+        return Err(CaesiumError {
+            message: "Cannot initialize WebP animation encoder options".into(),
+            code: 20313,
+        });
+    }
+    let mut options = unsafe { options.assume_init() };
+    options.anim_params.loop_count = loop_count.min(i32::MAX as u32) as i32;
+    options.anim_params.bgcolor = bg_color;
+    options.allow_mixed = parameters.webp.allow_mixed as i32;
+    if let Some(kmin) = parameters.webp.kmin {
+        options.kmin = kmin.min(i32::MAX as u32) as i32;
+    }
+    if let Some(kmax) = parameters.webp.kmax {
+        options.kmax = kmax.min(i32::MAX as u32) as i32;
+    }
+
+    let encoder = unsafe {
+        WebPAnimEncoderNewInternal(
+            first_canvas.width() as i32,
+            first_canvas.height() as i32,
+            &options,
+            WebPGetMuxABIVersion(),
+        )
+    };
+    if encoder.is_null() {
+        return Err(CaesiumError {
+            message: "Cannot create WebP animation encoder".into(),
+            code: 20313,
+        });
+    }
+
+    let encoded = unsafe { assemble_animation(encoder, canvases, &config) };
+    unsafe { WebPAnimEncoderDelete(encoder) };
+    encoded
+}
+
+unsafe fn assemble_animation(
+    encoder: *mut WebPAnimEncoder,
+    canvases: &[(RgbaImage, i32)],
+    config: &WebPConfig,
+) -> Result<Vec<u8>, CaesiumError> {
+    let mut start_ms = 0;
+    for (canvas, end_ms) in canvases {
+        let Ok(mut picture) = WebPPicture::new() else {
+            return Err(animation_error(encoder, 20310));
+        };
+        picture.use_argb = 1;
+        picture.width = canvas.width() as i32;
+        picture.height = canvas.height() as i32;
+        let added = WebPPictureImportRGBA(&mut picture, canvas.as_ptr(), canvas.width() as i32 * 4) != 0
+            && WebPAnimEncoderAdd(encoder, &mut picture, start_ms, config) != 0;
+        WebPPictureFree(&mut picture);
+        if !added {
+            return Err(animation_error(encoder, 20310));
+        }
+        start_ms = *end_ms;
+    }
+    //A final empty frame sets the duration of the last one
+    if WebPAnimEncoderAdd(encoder, ptr::null_mut(), start_ms, ptr::null()) == 0 {
+        return Err(animation_error(encoder, 20310));
+    }
+
+    let mut data = WebPData::default();
+    if WebPAnimEncoderAssemble(encoder, &mut data) == 0 {
+        return Err(animation_error(encoder, 20313));
+    }
+    let encoded = slice::from_raw_parts(data.bytes, data.size).to_vec();
+    WebPDataClear(&mut data);
+
+    Ok(encoded)
+}
+
+unsafe fn animation_error(encoder: *mut WebPAnimEncoder, code: u32) -> CaesiumError {
+    let error = WebPAnimEncoderGetError(encoder);
+    let message = if error.is_null() || *error == 0 {
+        "Cannot encode WebP animation".into()
+    } else {
+        CStr::from_ptr(error).to_string_lossy().into_owned()
+    };
+
+    CaesiumError { message, code }
 }
 
 fn to_dynamic_image(frame: AnimFrame) -> DynamicImage {
@@ -1,5 +1,6 @@
 use crate::cleanup::remove_compressed_test_file;
 use caesium::parameters::CSParameters;
+use caesium::SupportedFileTypes;
 use std::fs::File;
 use std::sync::Once;
 
@@ -124,3 +125,104 @@ fn compress_to_higher_size_and_resize() {
     assert_eq!(image::image_dimensions(output).unwrap(), (800, 600));
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn compress_to_size_with_chroma_quality() {
+    let output = "tests/samples/output/compressed_chroma_quality.jpg";
+    let max_output_size = 500_000;
+    initialize(output);
+    let mut pars = CSParameters::new();
+    pars.jpeg.quality = 80;
+    pars.jpeg.chroma_quality = Some(60);
+    caesium::compress_to_size(
+        String::from("tests/samples/uncompressed_드림캐쳐.jpg"),
+        String::from(output),
+        &mut pars,
+        max_output_size,
+        false,
+    )
+    .unwrap();
+
+    assert!(File::open(output).unwrap().metadata().unwrap().len() < max_output_size as u64);
+    assert_eq!(
+        pars.jpeg.chroma_quality,
+        Some(pars.jpeg.quality.saturating_sub(20).max(1))
+    );
+    remove_compressed_test_file(output)
+}
+
+#[test]
+fn compress_webp_to_size_with_rate_control() {
+    let max_output_size = 300_000;
+    let in_file = std::fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    let mut pars = CSParameters::new();
+    let (compressed, warnings) =
+        caesium::compress_to_size_in_memory_with_warnings(in_file, &mut pars, max_output_size, false).unwrap();
+
+    assert!(compressed.len() <= max_output_size);
+    assert!(warnings.is_empty());
+    assert_eq!(infer::get(&compressed).unwrap().mime_type(), "image/webp");
+    assert_eq!(pars.webp.target_size, 0);
+
+    let in_file = std::fs::read("tests/samples/uncompressed_animated.webp").unwrap();
+    let (compressed, warnings) =
+        caesium::compress_to_size_in_memory_with_warnings(in_file, &mut pars, 50_000, true).unwrap();
+
+    assert!(!compressed.is_empty());
+    assert!(warnings.is_empty());
+
+    let in_file = std::fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    let (compressed, warnings) =
+        caesium::compress_to_size_in_memory_with_warnings(in_file, &mut pars, 20_000, true).unwrap();
+
+    assert!(!compressed.is_empty());
+    assert_eq!(warnings.iter().map(|w| w.code).collect::<Vec<_>>(), vec![10205]);
+
+    //A rate control set by the caller is not used by the search of the quality
+    let in_file = std::fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    pars.webp.target_size = 2_000_000;
+    pars.webp.target_psnr = 50.0;
+    let (targeted, warnings) =
+        caesium::compress_to_size_in_memory_with_warnings(in_file, &mut pars, 20_000, true).unwrap();
+
+    assert_eq!(targeted, compressed);
+    assert_eq!(warnings.iter().map(|w| w.code).collect::<Vec<_>>(), vec![10205]);
+    assert_eq!((pars.webp.target_size, pars.webp.target_psnr), (0, 0.0));
+
+    let in_file = std::fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    pars.webp.method = 7;
+    let result = caesium::compress_to_size_in_memory_with_warnings(in_file, &mut pars, max_output_size, false);
+    assert_eq!(result.unwrap_err().code, 10019);
+}
+
+#[test]
+fn convert_to_size_with_rate_control() {
+    let in_file = std::fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.width = 400;
+    let (converted, warnings) = caesium::convert_to_size_in_memory_with_warnings(
+        in_file.clone(),
+        &pars,
+        SupportedFileTypes::WebP,
+        10_000,
+        false,
+    )
+    .unwrap();
+
+    assert!(converted.len() <= 10_000);
+    assert!(warnings.is_empty());
+    assert_eq!(infer::get(&converted).unwrap().mime_type(), "image/webp");
+
+    let (converted, warnings) =
+        caesium::convert_to_size_in_memory_with_warnings(in_file, &pars, SupportedFileTypes::WebP, 500, true).unwrap();
+
+    assert!(!converted.is_empty());
+    assert_eq!(warnings.iter().map(|w| w.code).collect::<Vec<_>>(), vec![10205]);
+
+    let in_file = std::fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let pars = CSParameters::new();
+    let converted =
+        caesium::convert_to_size_in_memory(in_file, &pars, SupportedFileTypes::Jpeg, 10_000, false).unwrap();
+    assert!(converted.len() <= 10_000);
+    assert_eq!(infer::get(&converted).unwrap().mime_type(), "image/jpeg");
+}
@@ -236,6 +236,26 @@ fn convert_tiff_to_webp() {
     remove_compressed_test_file(output)
 }
 
+#[test]
+fn convert_cmyk_jpg_to_png() {
+    let in_file = fs::read("tests/samples/cmyk.jpg").unwrap();
+    let reference = image::load_from_memory(&in_file).unwrap().to_rgb8();
+    let mut params = CSParameters::new();
+    params.jpeg.cmyk_to_rgb = true;
+    params.png.optimize = true;
+
+    let output = caesium::convert_in_memory(in_file, &params, SupportedFileTypes::Png).unwrap();
+    assert_eq!(infer::get(&output).unwrap().mime_type(), "image/png");
+    let converted = image::load_from_memory(&output).unwrap().to_rgb8();
+    assert_eq!(converted.dimensions(), reference.dimensions());
+    let total: u64 = converted
+        .iter()
+        .zip(reference.iter())
+        .map(|(a, b)| a.abs_diff(*b) as u64)
+        .sum();
+    assert!((total as f64 / converted.len() as f64) < 2.0);
+}
+
 fn extract_exif(path: &Path) -> HashMap<String, String> {
     let file = fs::File::open(path).unwrap();
     let mut bufreader = std::io::BufReader::new(&file);
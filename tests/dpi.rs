@@ -0,0 +1,185 @@
+use bytes::Bytes;
+use caesium::parameters::{CSParameters, Dpi};
+use caesium::SupportedFileTypes;
+use img_parts::jpeg::Jpeg as PartsJpeg;
+use img_parts::png::Png as PartsPng;
+use img_parts::ImageEXIF;
+use std::fs;
+use std::io::Cursor;
+
+const JPEG: &str = "tests/samples/uncompressed_드림캐쳐.jpg";
+const PNG_72_DPI: &str = "tests/samples/metadata.png";
+
+fn jfif_density(jpeg: &[u8]) -> (u8, u16, u16) {
+    let jpeg = PartsJpeg::from_bytes(Bytes::copy_from_slice(jpeg)).unwrap();
+    let jfif = jpeg.segment_by_marker(0xE0).unwrap().contents();
+    assert!(jfif.starts_with(b"JFIF\0"));
+    (
+        jfif[7],
+        u16::from_be_bytes([jfif[8], jfif[9]]),
+        u16::from_be_bytes([jfif[10], jfif[11]]),
+    )
+}
+
+fn png_phys(png: &[u8]) -> Option<(u32, u32, u8)> {
+    let png = PartsPng::from_bytes(Bytes::copy_from_slice(png)).unwrap();
+    let phys = png.chunk_by_type(*b"pHYs")?.contents();
+    Some((
+        u32::from_be_bytes([phys[0], phys[1], phys[2], phys[3]]),
+        u32::from_be_bytes([phys[4], phys[5], phys[6], phys[7]]),
+        phys[8],
+    ))
+}
+
+fn exif_resolution(image: &[u8]) -> (f64, f64, u32) {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(image))
+        .unwrap();
+    let rational = |tag| match &exif.get_field(tag, exif::In::PRIMARY).unwrap().value {
+        exif::Value::Rational(v) => v[0].to_f64(),
+        _ => panic!("Resolution is not a rational"),
+    };
+    let unit = exif
+        .get_field(exif::Tag::ResolutionUnit, exif::In::PRIMARY)
+        .unwrap()
+        .value
+        .get_uint(0)
+        .unwrap();
+
+    (rational(exif::Tag::XResolution), rational(exif::Tag::YResolution), unit)
+}
+
+#[test]
+fn jpeg_explicit_dpi() {
+    let mut params = CSParameters::new();
+    params.dpi = Some(Dpi { x: 300, y: 150 });
+
+    let compressed = caesium::compress_in_memory(fs::read(JPEG).unwrap(), &params).unwrap();
+    assert_eq!(jfif_density(&compressed), (1, 300, 150));
+
+    params.jpeg.optimize = true;
+    let optimized = caesium::compress_in_memory(fs::read(JPEG).unwrap(), &params).unwrap();
+    assert_eq!(jfif_density(&optimized), (1, 300, 150));
+
+    params.jpeg.optimize = false;
+    params.width = 100;
+    let resized = caesium::compress_in_memory(fs::read(JPEG).unwrap(), &params).unwrap();
+    assert_eq!(jfif_density(&resized), (1, 300, 150));
+}
+
+#[test]
+fn jpeg_keeps_dpi() {
+    let mut params = CSParameters::new();
+    params.dpi = Some(Dpi { x: 300, y: 300 });
+    let with_dpi = caesium::compress_in_memory(fs::read(JPEG).unwrap(), &params).unwrap();
+
+    let mut params = CSParameters::new();
+    params.jpeg.quality = 50;
+    let compressed = caesium::compress_in_memory(with_dpi.clone(), &params).unwrap();
+    assert_eq!(jfif_density(&compressed), (1, 300, 300));
+
+    params.jpeg.requantize = true;
+    let requantized = caesium::compress_in_memory(with_dpi.clone(), &params).unwrap();
+    assert_eq!(jfif_density(&requantized), (1, 300, 300));
+
+    params.jpeg.requantize = false;
+    params.keep_dpi = false;
+    let stripped = caesium::compress_in_memory(with_dpi, &params).unwrap();
+    assert_eq!(jfif_density(&stripped), (0, 1, 1));
+}
+
+#[test]
+fn png_keeps_dpi() {
+    let mut params = CSParameters::new();
+    let compressed = caesium::compress_in_memory(fs::read(PNG_72_DPI).unwrap(), &params).unwrap();
+    assert_eq!(png_phys(&compressed), Some((2835, 2835, 1)));
+
+    params.png.optimize = true;
+    params.width = 50;
+    let optimized = caesium::compress_in_memory(fs::read(PNG_72_DPI).unwrap(), &params).unwrap();
+    assert_eq!(png_phys(&optimized), Some((2835, 2835, 1)));
+
+    let output = "tests/samples/output/resized_72_dpi.png";
+    caesium::compress(String::from(PNG_72_DPI), String::from(output), &params).unwrap();
+    assert_eq!(png_phys(&fs::read(output).unwrap()), Some((2835, 2835, 1)));
+    fs::remove_file(output).unwrap();
+
+    params.dpi = Some(Dpi { x: 300, y: 300 });
+    let with_dpi = caesium::compress_in_memory(fs::read(PNG_72_DPI).unwrap(), &params).unwrap();
+    assert_eq!(png_phys(&with_dpi), Some((11811, 11811, 1)));
+
+    params.dpi = None;
+    params.keep_dpi = false;
+    for optimize in [false, true] {
+        params.png.optimize = optimize;
+        let stripped = caesium::compress_in_memory(fs::read(PNG_72_DPI).unwrap(), &params).unwrap();
+        assert_eq!(png_phys(&stripped), None);
+
+        params.keep_metadata = true;
+        let kept = caesium::compress_in_memory(fs::read(PNG_72_DPI).unwrap(), &params).unwrap();
+        assert_eq!(png_phys(&kept), Some((2835, 2835, 1)));
+        params.keep_metadata = false;
+    }
+}
+
+#[test]
+fn convert_keeps_dpi() {
+    let params = CSParameters::new();
+    let png = fs::read(PNG_72_DPI).unwrap();
+
+    let jpeg = caesium::convert_in_memory(png.clone(), &params, SupportedFileTypes::Jpeg).unwrap();
+    assert_eq!(jfif_density(&jpeg), (1, 72, 72));
+
+    let tiff = caesium::convert_in_memory(png.clone(), &params, SupportedFileTypes::Tiff).unwrap();
+    assert_eq!(exif_resolution(&tiff), (72.0, 72.0, 2));
+
+    let png_300_dpi = png_with_dpi(&png, 300);
+    let png = caesium::convert_in_memory(jpeg, &params, SupportedFileTypes::Png).unwrap();
+    assert_eq!(png_phys(&png), Some((2835, 2835, 1)));
+
+    //WebP stores the resolution in a generated EXIF, not written for the default one
+    let webp = caesium::convert_in_memory(png, &params, SupportedFileTypes::WebP).unwrap();
+    assert!(img_parts::webp::WebP::from_bytes(Bytes::from(webp))
+        .unwrap()
+        .exif()
+        .is_none());
+
+    let webp = caesium::convert_in_memory(png_300_dpi, &params, SupportedFileTypes::WebP).unwrap();
+    assert_eq!(exif_resolution(&webp), (300.0, 300.0, 2));
+}
+
+fn png_with_dpi(png: &[u8], dpi: u32) -> Vec<u8> {
+    let mut params = CSParameters::new();
+    params.dpi = Some(Dpi { x: dpi, y: dpi });
+    caesium::compress_in_memory(png.to_vec(), &params).unwrap()
+}
+
+#[test]
+fn tiff_explicit_dpi() {
+    let mut params = CSParameters::new();
+    params.dpi = Some(Dpi { x: 600, y: 600 });
+    let compressed = caesium::compress_in_memory(fs::read("tests/samples/rgb8.tif").unwrap(), &params).unwrap();
+    assert_eq!(exif_resolution(&compressed), (600.0, 600.0, 2));
+
+    let mut params = CSParameters::new();
+    let recompressed = caesium::compress_in_memory(compressed.clone(), &params).unwrap();
+    assert_eq!(exif_resolution(&recompressed), (600.0, 600.0, 2));
+
+    //Like the PNG pHYs chunk, the resolution tags are not part of the kept metadata
+    params.keep_dpi = false;
+    params.keep_metadata = true;
+    let stripped = caesium::compress_in_memory(compressed, &params).unwrap();
+    assert_eq!(exif_resolution(&stripped), (1.0, 1.0, 1));
+}
+
+#[test]
+fn invalid_dpi() {
+    let mut params = CSParameters::new();
+    params.dpi = Some(Dpi { x: 0, y: 300 });
+    let result = caesium::compress(
+        String::from(JPEG),
+        String::from("tests/samples/output/invalid_dpi.jpg"),
+        &params,
+    );
+    assert_eq!(result.unwrap_err().code, 10011);
+}
@@ -0,0 +1,134 @@
+use caesium::parameters::CSParameters;
+use caesium::SupportedFileTypes;
+use img_parts::ImageICC;
+use std::fs;
+
+fn icc_description(image: &[u8]) -> Option<String> {
+    caesium::inspect(image).unwrap().icc_description
+}
+
+fn is_adobe_rgb(description: &Option<String>) -> bool {
+    description.as_ref().is_some_and(|d| d.contains("Adobe RGB"))
+}
+
+#[test]
+fn jpeg_convert_to_srgb() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.keep_metadata = true;
+
+    let preserved = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert!(is_adobe_rgb(&icc_description(&preserved)));
+
+    pars.convert_to_srgb = true;
+    let converted = caesium::compress_in_memory(in_file, &pars).unwrap();
+    assert!(icc_description(&converted).is_none());
+    assert_ne!(
+        image::load_from_memory(&preserved).unwrap().to_rgb8(),
+        image::load_from_memory(&converted).unwrap().to_rgb8()
+    );
+}
+
+#[test]
+fn jpeg_convert_to_srgb_with_resize() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.convert_to_srgb = true;
+    pars.embed_srgb_profile = true;
+    pars.width = 300;
+
+    let converted = caesium::compress_in_memory(in_file, &pars).unwrap();
+    let description = icc_description(&converted);
+    assert!(description.is_some());
+    assert!(!is_adobe_rgb(&description));
+    assert_eq!(image::load_from_memory(&converted).unwrap().width(), 300);
+}
+
+#[test]
+fn jpeg_embed_srgb_profile() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.convert_to_srgb = true;
+    pars.embed_srgb_profile = true;
+
+    let converted = caesium::compress_in_memory(in_file, &pars).unwrap();
+    let description = icc_description(&converted);
+    assert!(description.is_some());
+    assert!(!is_adobe_rgb(&description));
+}
+
+#[test]
+fn srgb_profile_is_kept() {
+    let in_file = fs::read("tests/samples/metadata.png").unwrap();
+    let original = icc_description(&in_file);
+    assert!(original.is_some());
+
+    let mut pars = CSParameters::new();
+    pars.keep_metadata = true;
+    pars.convert_to_srgb = true;
+    pars.png.quality = 80;
+
+    let compressed = caesium::compress_in_memory(in_file, &pars).unwrap();
+    assert_eq!(icc_description(&compressed), original);
+}
+
+#[test]
+fn convert_to_srgb_formats() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.convert_to_srgb = true;
+    pars.embed_srgb_profile = true;
+
+    for format in [SupportedFileTypes::Png, SupportedFileTypes::WebP] {
+        let converted = caesium::convert_in_memory(in_file.clone(), &pars, format).unwrap();
+        let description = icc_description(&converted);
+        assert!(description.is_some(), "{format:?}");
+        assert!(!is_adobe_rgb(&description), "{format:?}");
+    }
+}
+
+#[test]
+fn png_convert_to_srgb() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.keep_metadata = true;
+    pars.png.optimize = true;
+    let tagged_png = caesium::convert_in_memory(in_file, &pars, SupportedFileTypes::Png).unwrap();
+    assert!(is_adobe_rgb(&icc_description(&tagged_png)));
+
+    pars.convert_to_srgb = true;
+    pars.png.optimize = false;
+    let converted = caesium::compress_in_memory(tagged_png, &pars).unwrap();
+    assert!(icc_description(&converted).is_none());
+}
+
+#[test]
+fn tiff_convert_to_srgb() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let icc = img_parts::jpeg::Jpeg::from_bytes(in_file.clone().into())
+        .unwrap()
+        .icc_profile()
+        .unwrap();
+    let image = image::load_from_memory(&in_file).unwrap().to_rgb8();
+
+    let mut tagged_tiff = std::io::Cursor::new(vec![]);
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut tagged_tiff).unwrap();
+    let mut tiff_image = encoder
+        .new_image::<tiff::encoder::colortype::RGB8>(image.width(), image.height())
+        .unwrap();
+    tiff_image
+        .encoder()
+        .write_tag(tiff::tags::Tag::Unknown(34675), icc.as_ref())
+        .unwrap();
+    tiff_image.write_data(image.as_raw()).unwrap();
+    let tagged_tiff = tagged_tiff.into_inner();
+    assert!(is_adobe_rgb(&icc_description(&tagged_tiff)));
+
+    let mut pars = CSParameters::new();
+    pars.convert_to_srgb = true;
+    pars.embed_srgb_profile = true;
+    let converted = caesium::compress_in_memory(tagged_tiff, &pars).unwrap();
+    let description = icc_description(&converted);
+    assert!(description.is_some());
+    assert!(!is_adobe_rgb(&description));
+}
@@ -0,0 +1,129 @@
+use caesium::info::ColorModel;
+use caesium::parameters::ChromaSubsampling;
+use caesium::SupportedFileTypes;
+use std::fs;
+
+#[test]
+fn inspect_jpeg() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let info = caesium::inspect(&in_file).unwrap();
+    assert_eq!(info.format, SupportedFileTypes::Jpeg);
+    assert_eq!((info.width, info.height), (2400, 1600));
+    assert_eq!(info.color_model, ColorModel::YCbCr);
+    assert_eq!(info.bit_depth, 8);
+    assert_eq!(info.frame_count, 1);
+    assert!(!info.has_alpha);
+    let exif = info.exif.unwrap();
+    assert_eq!(exif.orientation, 1);
+    assert_eq!(exif.capture_date.as_deref(), Some("2019-08-16 17:24:03"));
+    assert!(!exif.has_gps);
+
+    let jpeg = info.jpeg.unwrap();
+    let quality = jpeg.estimated_quality.unwrap();
+    assert!((1..=100).contains(&quality));
+}
+
+#[test]
+fn inspect_exif() {
+    //Big endian TIFF header with Orientation = 6 in IFD0, pointing to an EXIF directory with DateTimeOriginal and to a
+    //GPS directory with GPSVersionID
+    let mut exif = b"MM\0\x2A\0\0\0\x08\0\x03".to_vec();
+    exif.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+    exif.extend([0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 50]);
+    exif.extend([0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 88]);
+    exif.extend([0, 0, 0, 0]);
+    exif.extend([0, 1, 0x90, 0x03, 0, 2, 0, 0, 0, 20, 0, 0, 0, 68, 0, 0, 0, 0]);
+    exif.extend(b"2021:03:04 05:06:07\0");
+    exif.extend([0, 1, 0, 0, 0, 1, 0, 0, 0, 4, 2, 2, 0, 0, 0, 0, 0, 0]);
+
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut jpeg = img_parts::jpeg::Jpeg::from_bytes(in_file.into()).unwrap();
+    img_parts::ImageEXIF::set_exif(&mut jpeg, Some(exif.into()));
+    let mut in_file = vec![];
+    jpeg.encoder().write_to(&mut in_file).unwrap();
+
+    let exif = caesium::inspect(&in_file).unwrap().exif.unwrap();
+    assert_eq!(exif.orientation, 6);
+    assert_eq!(exif.capture_date.as_deref(), Some("2021-03-04 05:06:07"));
+    assert!(exif.has_gps);
+}
+
+#[test]
+fn inspect_jpeg_estimated_quality() {
+    let mut pars = caesium::parameters::CSParameters::new();
+    pars.jpeg.quality = 50;
+    pars.jpeg.chroma_subsampling = ChromaSubsampling::CS444;
+    pars.jpeg.progressive = false;
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let compressed = caesium::compress_in_memory(in_file, &pars).unwrap();
+    let jpeg = caesium::inspect(&compressed).unwrap().jpeg.unwrap();
+    assert_eq!(jpeg.chroma_subsampling, Some(ChromaSubsampling::CS444));
+    assert!(!jpeg.progressive);
+    assert!(jpeg.estimated_quality.unwrap().abs_diff(50) <= 10);
+}
+
+#[test]
+fn inspect_icc() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let info = caesium::inspect(&in_file).unwrap();
+    assert!(info.icc_description.is_some_and(|d| !d.is_empty()));
+}
+
+#[test]
+fn inspect_png() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let info = caesium::inspect(&in_file).unwrap();
+    assert_eq!(info.format, SupportedFileTypes::Png);
+    assert_eq!((info.width, info.height), (380, 287));
+    assert_eq!(info.frame_count, 1);
+}
+
+#[test]
+fn inspect_animated_gif() {
+    let in_file = fs::read("tests/samples/uncompressed_은하.gif").unwrap();
+    let info = caesium::inspect(&in_file).unwrap();
+    assert_eq!(info.format, SupportedFileTypes::Gif);
+    assert_eq!(info.color_model, ColorModel::Indexed);
+    assert!(info.frame_count > 1);
+    assert!(info.duration_ms > 0);
+}
+
+#[test]
+fn inspect_long_gif() {
+    //Enough frames at the longest delay to overflow the total duration
+    let mut in_file = b"GIF89a\x01\x00\x01\x00\x00\x00\x00".to_vec();
+    for _ in 0..7000 {
+        in_file.extend([0x21, 0xF9, 0x04, 0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        in_file.extend([0x2C, 0, 0, 0, 0, 0x01, 0x00, 0x01, 0x00, 0x00]);
+        in_file.extend([0x02, 0x02, 0x44, 0x01, 0x00]);
+    }
+    in_file.push(0x3B);
+
+    let info = caesium::inspect(&in_file).unwrap();
+    assert_eq!(info.frame_count, 7000);
+    assert_eq!(info.duration_ms, u32::MAX);
+}
+
+#[test]
+fn inspect_animated_webp() {
+    let in_file = fs::read("tests/samples/uncompressed_animated.webp").unwrap();
+    let info = caesium::inspect(&in_file).unwrap();
+    assert_eq!(info.format, SupportedFileTypes::WebP);
+    assert!(info.frame_count > 1);
+    assert!(info.duration_ms > 0);
+}
+
+#[test]
+fn inspect_tiff() {
+    let in_file = fs::read("tests/samples/rgba8.tif").unwrap();
+    let info = caesium::inspect(&in_file).unwrap();
+    assert_eq!(info.format, SupportedFileTypes::Tiff);
+    assert_eq!(info.color_model, ColorModel::Rgba);
+    assert!(info.has_alpha);
+    assert_eq!(info.frame_count, 1);
+}
+
+#[test]
+fn inspect_unknown() {
+    assert!(caesium::inspect(&[0u8; 32]).is_err());
+}
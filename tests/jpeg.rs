@@ -1,7 +1,12 @@
 use crate::cleanup::remove_compressed_test_file;
-use caesium::parameters::CSParameters;
+use caesium::info::ColorModel;
+use caesium::parameters::{
+    CSParameters, ChromaSubsampling, CropRegion, JpegProfile, JpegQuantTable, JpegScan, JpegSourceQualityPolicy,
+    JpegTransform, JpegTransformation, JpegTune,
+};
 use dssim::Val;
-use img_parts::ImageICC;
+use img_parts::{ImageEXIF, ImageICC};
+use std::io::Cursor;
 use std::{fs, fs::File, sync::Once};
 
 mod cleanup;
@@ -274,3 +279,868 @@ fn preserve_icc() {
 
     assert!(output_iccp.is_none());
 }
+
+fn adobe_markers(image: &[u8]) -> usize {
+    img_parts::jpeg::Jpeg::from_bytes(image.to_vec().into())
+        .unwrap()
+        .segments_by_marker(0xEE)
+        .count()
+}
+
+fn mean_rgb_diff(a: &[u8], b: &[u8]) -> f64 {
+    mean_image_diff(
+        &image::load_from_memory(a).unwrap(),
+        &image::load_from_memory(b).unwrap(),
+    )
+}
+
+fn mean_image_diff(a: &image::DynamicImage, b: &image::DynamicImage) -> f64 {
+    let (a, b) = (a.to_rgb8(), b.to_rgb8());
+    assert_eq!(a.dimensions(), b.dimensions());
+    let total: u64 = a.iter().zip(b.iter()).map(|(x, y)| x.abs_diff(*y) as u64).sum();
+    total as f64 / a.len() as f64
+}
+
+#[test]
+fn compress_cmyk() {
+    let in_file = fs::read("tests/samples/cmyk.jpg").unwrap();
+    let mut pars = CSParameters::new();
+
+    for keep_metadata in [false, true] {
+        pars.keep_metadata = keep_metadata;
+        let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+        let info = caesium::inspect(&output).unwrap();
+        assert!(matches!(info.color_model, ColorModel::Cmyk | ColorModel::Ycck));
+        assert_eq!(adobe_markers(&output), 1);
+        assert!(mean_rgb_diff(&in_file, &output) < 10.0);
+    }
+}
+
+#[test]
+fn optimize_cmyk() {
+    let in_file = fs::read("tests/samples/cmyk.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.optimize = true;
+    pars.keep_metadata = true;
+
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Cmyk);
+    assert_eq!(adobe_markers(&output), 1);
+    assert_eq!(
+        image::load_from_memory(&in_file).unwrap().to_rgb8(),
+        image::load_from_memory(&output).unwrap().to_rgb8()
+    );
+}
+
+#[test]
+fn compress_cmyk_to_rgb() {
+    let in_file = fs::read("tests/samples/cmyk.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.cmyk_to_rgb = true;
+
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::YCbCr);
+    assert!(mean_rgb_diff(&in_file, &output) < 10.0);
+}
+
+#[test]
+fn downscale_cmyk() {
+    let in_file = fs::read("tests/samples/cmyk.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.width = 240;
+
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    let reference =
+        image::load_from_memory(&in_file)
+            .unwrap()
+            .resize_exact(240, 160, image::imageops::FilterType::Lanczos3);
+    let mut reference_file = vec![];
+    reference
+        .write_to(&mut std::io::Cursor::new(&mut reference_file), image::ImageFormat::Png)
+        .unwrap();
+
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::YCbCr);
+    assert!(mean_rgb_diff(&reference_file, &output) < 10.0);
+}
+
+fn transform(transformation: JpegTransformation, crop: Option<CropRegion>, perfect: bool) -> JpegTransform {
+    JpegTransform {
+        transformation,
+        crop,
+        perfect,
+    }
+}
+
+#[test]
+fn transform_rotate_and_flip() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let original = image::load_from_memory(&in_file).unwrap();
+
+    let cases = [
+        (JpegTransformation::Rotate90, original.rotate90()),
+        (JpegTransformation::Rotate180, original.rotate180()),
+        (JpegTransformation::Rotate270, original.rotate270()),
+        (JpegTransformation::FlipHorizontal, original.fliph()),
+        (JpegTransformation::FlipVertical, original.flipv()),
+        (JpegTransformation::Transpose, original.rotate90().fliph()),
+    ];
+    for (transformation, expected) in cases {
+        let output = caesium::jpeg_transform(&in_file, &transform(transformation, None, true)).unwrap();
+        assert!(mean_image_diff(&expected, &image::load_from_memory(&output).unwrap()) < 1.0);
+    }
+}
+
+#[test]
+fn transform_keeps_metadata() {
+    let in_file = fs::read("tests/samples/icc.jpg").unwrap();
+    let output = caesium::jpeg_transform(&in_file, &transform(JpegTransformation::Rotate180, None, false)).unwrap();
+    let input = img_parts::jpeg::Jpeg::from_bytes(in_file.into()).unwrap();
+    let output = img_parts::jpeg::Jpeg::from_bytes(output.into()).unwrap();
+    assert_eq!(input.icc_profile(), output.icc_profile());
+    assert_eq!(input.exif(), output.exif());
+}
+
+#[test]
+fn transform_crop() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+
+    let crop = CropRegion {
+        x: 32,
+        y: 64,
+        width: 320,
+        height: 200,
+    };
+    let output = caesium::jpeg_transform(&in_file, &transform(JpegTransformation::None, Some(crop), true)).unwrap();
+    let cropped = image::load_from_memory(&in_file).unwrap().crop_imm(32, 64, 320, 200);
+    assert!(mean_image_diff(&cropped, &image::load_from_memory(&output).unwrap()) < 1.0);
+
+    // the region is extended to the previous MCU boundary
+    let unaligned = CropRegion { x: 44, ..crop };
+    let output =
+        caesium::jpeg_transform(&in_file, &transform(JpegTransformation::None, Some(unaligned), true)).unwrap();
+    let aligned = CropRegion {
+        x: 40,
+        width: 324,
+        ..crop
+    };
+    let expected =
+        caesium::jpeg_transform(&in_file, &transform(JpegTransformation::None, Some(aligned), true)).unwrap();
+    assert_eq!(
+        image::load_from_memory(&output).unwrap().to_rgb8(),
+        image::load_from_memory(&expected).unwrap().to_rgb8()
+    );
+
+    let crop = CropRegion { x: 2300, ..crop };
+    assert!(caesium::jpeg_transform(&in_file, &transform(JpegTransformation::None, Some(crop), true)).is_err());
+}
+
+#[test]
+fn transform_perfect() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.width = 1000;
+    pars.height = 700;
+    let odd_sized = caesium::compress_in_memory(in_file, &pars).unwrap();
+
+    assert!(caesium::jpeg_transform(&odd_sized, &transform(JpegTransformation::Rotate90, None, true)).is_err());
+    let trimmed = caesium::jpeg_transform(&odd_sized, &transform(JpegTransformation::Rotate90, None, false)).unwrap();
+    let dimensions = image::load_from_memory(&trimmed).unwrap();
+    assert_eq!((dimensions.width(), dimensions.height()), (688, 1000));
+}
+
+#[test]
+fn auto_orient() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    // Big endian TIFF header with an IFD0 holding only Orientation = 6
+    let exif = [
+        b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1, 0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut rotated = img_parts::jpeg::Jpeg::from_bytes(in_file.into()).unwrap();
+    rotated.set_exif(Some(exif.to_vec().into()));
+    let mut in_file = vec![];
+    rotated.encoder().write_to(&mut in_file).unwrap();
+
+    let mut pars = CSParameters::new();
+    for optimize in [false, true] {
+        pars.jpeg.optimize = optimize;
+        let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 1600);
+        assert!(img_parts::jpeg::Jpeg::from_bytes(output.into())
+            .unwrap()
+            .exif()
+            .is_none());
+    }
+
+    pars.keep_metadata = true;
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_eq!(image::load_from_memory(&output).unwrap().width(), 2400);
+
+    // partial edge blocks would be trimmed by the rotation, so only the orientation is kept
+    pars.keep_metadata = false;
+    pars.width = 1000;
+    pars.height = 700;
+    let mut odd_sized =
+        img_parts::jpeg::Jpeg::from_bytes(caesium::compress_in_memory(in_file, &pars).unwrap().into()).unwrap();
+    odd_sized.set_exif(Some(exif.to_vec().into()));
+    let mut in_file = vec![];
+    odd_sized.encoder().write_to(&mut in_file).unwrap();
+    pars.width = 0;
+    pars.height = 0;
+    for optimize in [false, true] {
+        pars.jpeg.optimize = optimize;
+        let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (1000, 700));
+        let exif = img_parts::jpeg::Jpeg::from_bytes(output.into())
+            .unwrap()
+            .exif()
+            .unwrap();
+        let exif = exif::Reader::new().read_raw(exif.to_vec()).unwrap();
+        let orientation = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY).unwrap();
+        assert_eq!(orientation.value.get_uint(0), Some(6));
+    }
+}
+
+#[test]
+fn requantize() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let original = image::load_from_memory(&in_file).unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.requantize = true;
+    pars.jpeg.quality = 50;
+
+    let requantized = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert!(requantized.len() < in_file.len());
+    let info = caesium::inspect(&requantized).unwrap();
+    assert_eq!((info.width, info.height), (2400, 1600));
+    assert!(info.jpeg.unwrap().estimated_quality < caesium::inspect(&in_file).unwrap().jpeg.unwrap().estimated_quality);
+
+    pars.jpeg.requantize = false;
+    let reencoded = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    let requantized_diff = mean_image_diff(&original, &image::load_from_memory(&requantized).unwrap());
+    let reencoded_diff = mean_image_diff(&original, &image::load_from_memory(&reencoded).unwrap());
+    assert!(requantized_diff < reencoded_diff);
+}
+
+#[test]
+fn requantize_never_increases_quality() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.requantize = true;
+    pars.jpeg.quality = 100;
+
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_eq!(
+        image::load_from_memory(&in_file).unwrap().to_rgb8(),
+        image::load_from_memory(&output).unwrap().to_rgb8()
+    );
+}
+
+fn has_marker(image: &[u8], markers: &[u8]) -> bool {
+    img_parts::jpeg::Jpeg::from_bytes(image.to_vec().into())
+        .unwrap()
+        .segments()
+        .iter()
+        .any(|s| markers.contains(&s.marker()))
+}
+
+#[test]
+fn advanced_tuning() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    let default = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+
+    pars.jpeg.trellis = false;
+    let no_trellis = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert!(no_trellis.len() > default.len());
+
+    pars.jpeg.trellis = true;
+    pars.jpeg.quant_table = JpegQuantTable::Flat;
+    pars.jpeg.tune = JpegTune::Psnr;
+    pars.jpeg.overshoot_deringing = false;
+    pars.jpeg.smoothing = 20;
+    let tuned = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert_ne!(tuned, default);
+
+    pars = CSParameters::new();
+    pars.jpeg.profile = JpegProfile::Fastest;
+    pars.jpeg.progressive = false;
+    let fastest = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert!(!caesium::inspect(&fastest).unwrap().jpeg.unwrap().progressive);
+    pars.jpeg.progressive = true;
+    let fastest = caesium::compress_in_memory(in_file, &pars).unwrap();
+    assert!(caesium::inspect(&fastest).unwrap().jpeg.unwrap().progressive);
+}
+
+#[test]
+fn restart_interval_and_arithmetic_coding() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.restart_interval = 16;
+    pars.jpeg.arithmetic_coding = true;
+
+    for optimize in [false, true] {
+        pars.jpeg.optimize = optimize;
+        let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+        assert!(has_marker(&output, &[0xDD]));
+        assert!(has_marker(&output, &[0xC9, 0xCA]));
+    }
+
+    let arithmetic = caesium::compress_in_memory(in_file, &pars).unwrap();
+    let huffman = caesium::compress_in_memory(arithmetic.clone(), &CSParameters::new()).unwrap();
+    assert!(!has_marker(&huffman, &[0xC9, 0xCA]));
+    assert_eq!(image::load_from_memory(&huffman).unwrap().width(), 2400);
+}
+
+#[test]
+fn invalid_tuning() {
+    let output = "tests/samples/output/invalid_tuning.jpg";
+    let mut pars = CSParameters::new();
+    pars.jpeg.smoothing = 101;
+    assert!(caesium::compress(
+        String::from("tests/samples/uncompressed_드림캐쳐.jpg"),
+        String::from(output),
+        &pars
+    )
+    .is_err());
+
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let result = caesium::compress_in_memory(in_file.clone(), &pars);
+    assert_eq!(result.unwrap_err().code, 10007);
+
+    pars.jpeg.smoothing = 0;
+    pars.jpeg.restart_interval = 70000;
+    assert!(caesium::compress(
+        String::from("tests/samples/uncompressed_드림캐쳐.jpg"),
+        String::from(output),
+        &pars
+    )
+    .is_err());
+    let result = caesium::compress_in_memory(in_file.clone(), &pars);
+    assert_eq!(result.unwrap_err().code, 10008);
+    let result = caesium::compress_in_memory_with_warnings(in_file, &pars);
+    assert_eq!(result.unwrap_err().code, 10008);
+}
+
+fn quant_tables(image: &[u8]) -> Vec<Vec<u8>> {
+    let jpeg = img_parts::jpeg::Jpeg::from_bytes(image.to_vec().into()).unwrap();
+    let mut tables = vec![];
+    for segment in jpeg.segments().iter().filter(|s| s.marker() == 0xDB) {
+        let contents = segment.contents();
+        let mut pos = 0;
+        while pos < contents.len() {
+            let size = if contents[pos] >> 4 == 0 { 65 } else { 129 };
+            tables.push(contents[pos..pos + size].to_vec());
+            pos += size;
+        }
+    }
+    tables
+}
+
+#[test]
+fn chroma_quality() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.quality = 80;
+    let same_quality = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+
+    pars.jpeg.chroma_quality = Some(40);
+    let lower_chroma = caesium::compress_in_memory(in_file, &pars).unwrap();
+    assert!(lower_chroma.len() < same_quality.len());
+    let same_tables = quant_tables(&same_quality);
+    let lower_chroma_tables = quant_tables(&lower_chroma);
+    assert_eq!(same_tables[0], lower_chroma_tables[0]);
+    assert_ne!(same_tables[1], lower_chroma_tables[1]);
+
+    pars.jpeg.chroma_quality = Some(101);
+    assert!(caesium::compress(
+        String::from("tests/samples/uncompressed_드림캐쳐.jpg"),
+        String::from("tests/samples/output/invalid_chroma_quality.jpg"),
+        &pars
+    )
+    .is_err());
+}
+
+fn chroma_subsampling(image: &[u8]) -> Option<ChromaSubsampling> {
+    caesium::inspect(image).unwrap().jpeg.unwrap().chroma_subsampling
+}
+
+#[test]
+fn preserve_chroma_subsampling() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    for subsampling in [ChromaSubsampling::CS444, ChromaSubsampling::CS422] {
+        pars.jpeg.chroma_subsampling = subsampling;
+        let source = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+
+        pars.jpeg.chroma_subsampling = ChromaSubsampling::Preserve;
+        let output = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+        assert_eq!(chroma_subsampling(&output), Some(subsampling));
+
+        pars.width = 800;
+        let output = caesium::compress_in_memory(source, &pars).unwrap();
+        assert_eq!(chroma_subsampling(&output), Some(subsampling));
+        pars.width = 0;
+    }
+}
+
+#[test]
+fn auto_chroma_subsampling() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    let output = caesium::compress_in_memory(in_file, &pars).unwrap();
+    assert_eq!(chroma_subsampling(&output), Some(ChromaSubsampling::CS420));
+
+    //Red text on white, as in a screenshot
+    let mut text = image::RgbImage::from_pixel(640, 480, image::Rgb([255, 255, 255]));
+    for line in 0..20 {
+        for x in (20..600).filter(|x| (x / 7) % 2 == 0) {
+            for y in (0..10).filter(|y| (x + y) % 5 < 2) {
+                text.put_pixel(x, 20 + line * 22 + y, image::Rgb([220, 0, 0]));
+            }
+        }
+    }
+    let mut png = vec![];
+    text.write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    pars.jpeg.chroma_subsampling = ChromaSubsampling::CS444;
+    let screenshot = caesium::convert_in_memory(png, &pars, caesium::SupportedFileTypes::Jpeg).unwrap();
+
+    pars.jpeg.chroma_subsampling = ChromaSubsampling::Auto;
+    let output = caesium::compress_in_memory(screenshot, &pars).unwrap();
+    assert_eq!(chroma_subsampling(&output), Some(ChromaSubsampling::CS444));
+}
+
+//0xFF is always stuffed in the entropy coded data, so every FFDA is a SOS marker
+fn scan_count(image: &[u8]) -> usize {
+    image.windows(2).filter(|w| w == &[0xFF, 0xDA]).count()
+}
+
+#[test]
+fn lossless_progressive() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.progressive = false;
+    let baseline = caesium::compress_in_memory(in_file, &pars).unwrap();
+    assert!(!caesium::inspect(&baseline).unwrap().jpeg.unwrap().progressive);
+
+    pars.jpeg.optimize = true;
+    pars.jpeg.progressive = true;
+    for optimize_scans in [true, false] {
+        pars.jpeg.optimize_scans = optimize_scans;
+        let progressive = caesium::compress_in_memory(baseline.clone(), &pars).unwrap();
+        assert!(caesium::inspect(&progressive).unwrap().jpeg.unwrap().progressive);
+        assert_eq!(
+            image::load_from_memory(&baseline).unwrap().to_rgb8(),
+            image::load_from_memory(&progressive).unwrap().to_rgb8()
+        );
+    }
+}
+
+#[test]
+fn custom_scan_script() {
+    //DC first for a quick preview, then the full AC bands
+    let preview = vec![
+        JpegScan {
+            components: [0, 1, 2, 0],
+            component_count: 3,
+            spectral_start: 0,
+            spectral_end: 0,
+            approx_high: 0,
+            approx_low: 0,
+        },
+        JpegScan {
+            components: [0, 0, 0, 0],
+            component_count: 1,
+            spectral_start: 1,
+            spectral_end: 63,
+            approx_high: 0,
+            approx_low: 0,
+        },
+        JpegScan {
+            components: [1, 0, 0, 0],
+            component_count: 1,
+            spectral_start: 1,
+            spectral_end: 63,
+            approx_high: 0,
+            approx_low: 0,
+        },
+        JpegScan {
+            components: [2, 0, 0, 0],
+            component_count: 1,
+            spectral_start: 1,
+            spectral_end: 63,
+            approx_high: 0,
+            approx_low: 0,
+        },
+    ];
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.scan_script = Some(preview.clone());
+    for optimize in [false, true] {
+        pars.jpeg.optimize = optimize;
+        let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+        assert!(caesium::inspect(&output).unwrap().jpeg.unwrap().progressive);
+        assert_eq!(scan_count(&output), preview.len());
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 2400);
+    }
+
+    pars.jpeg.scan_script = Some(vec![]);
+    assert!(caesium::compress(
+        String::from("tests/samples/uncompressed_드림캐쳐.jpg"),
+        String::from("tests/samples/output/invalid_scan_script.jpg"),
+        &pars
+    )
+    .is_err());
+
+    pars.jpeg.scan_script = Some(vec![JpegScan {
+        component_count: 5,
+        ..preview[0]
+    }]);
+    assert!(caesium::compress_in_memory(in_file, &pars).is_err());
+}
+
+#[test]
+fn source_quality_policy() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.quality = 50;
+    let source = caesium::compress_in_memory(in_file, &pars).unwrap();
+    let source_quality = caesium::estimate_jpeg_quality(&source).unwrap();
+    assert!(source_quality < 90);
+
+    pars.jpeg.quality = source_quality;
+    let at_source_quality = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+
+    pars.jpeg.quality = 90;
+    let ignored = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+    assert!(ignored.len() > at_source_quality.len());
+
+    pars.jpeg.source_quality_policy = JpegSourceQualityPolicy::Cap;
+    let capped = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+    assert_eq!(capped, at_source_quality);
+
+    pars.jpeg.source_quality_policy = JpegSourceQualityPolicy::Lossless;
+    let lossless = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+    assert!(lossless.len() <= source.len());
+    assert_eq!(
+        image::load_from_memory(&source).unwrap().to_rgb8(),
+        image::load_from_memory(&lossless).unwrap().to_rgb8()
+    );
+
+    //Lower target qualities are not affected
+    pars.jpeg.quality = source_quality / 2;
+    let lower = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+    pars.jpeg.source_quality_policy = JpegSourceQualityPolicy::Ignore;
+    assert_eq!(lower, caesium::compress_in_memory(source, &pars).unwrap());
+}
+
+#[test]
+fn recover_corrupted() {
+    let in_file = fs::read("tests/samples/corrupted.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.recover_corrupted = true;
+    for optimize in [false, true] {
+        pars.jpeg.optimize = optimize;
+        let (output, warnings) = caesium::compress_in_memory_with_warnings(in_file.clone(), &pars).unwrap();
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 1016);
+        assert!(warnings.iter().any(|w| w.code == 20118));
+        assert!(warnings.iter().any(|w| w.code == 20117));
+    }
+
+    let output = "tests/samples/output/corrupted.jpg";
+    let warnings =
+        caesium::compress_with_warnings(String::from("tests/samples/corrupted.jpg"), output.to_string(), &pars)
+            .unwrap();
+    assert!(warnings.iter().any(|w| w.code == 20118));
+    let warnings = caesium::compress_to_size_with_warnings(
+        String::from("tests/samples/corrupted.jpg"),
+        output.to_string(),
+        &mut pars,
+        in_file.len() / 2,
+        true,
+    )
+    .unwrap();
+    assert!(warnings.iter().any(|w| w.code == 20118));
+    remove_compressed_test_file(output);
+
+    let (converted, warnings) =
+        caesium::convert_in_memory_with_warnings(in_file, &pars, caesium::SupportedFileTypes::Png).unwrap();
+    assert_eq!(image::load_from_memory(&converted).unwrap().width(), 1016);
+    assert!(warnings.iter().any(|w| w.code == 20118));
+    assert!(warnings.iter().any(|w| w.code == 20117));
+    let output = "tests/samples/output/corrupted.png";
+    let warnings = caesium::convert_with_warnings(
+        String::from("tests/samples/corrupted.jpg"),
+        output.to_string(),
+        &pars,
+        caesium::SupportedFileTypes::Png,
+    )
+    .unwrap();
+    assert!(warnings.iter().any(|w| w.code == 20118));
+    remove_compressed_test_file(output)
+}
+
+#[test]
+fn recover_truncated() {
+    let in_file = fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut pars = CSParameters::new();
+    pars.jpeg.progressive = false;
+    let baseline = caesium::compress_in_memory(in_file, &pars).unwrap();
+    let truncated = baseline[..baseline.len() / 2].to_vec();
+
+    pars.jpeg.recover_corrupted = true;
+    let (output, warnings) = caesium::compress_in_memory_with_warnings(truncated, &pars).unwrap();
+    assert!(!warnings.is_empty());
+    let output = image::load_from_memory(&output).unwrap().to_rgb8();
+    assert_eq!(output.dimensions(), (2400, 1600));
+    let bottom = output.get_pixel(1200, 1590);
+    assert!(bottom.0.iter().all(|&c| c.abs_diff(128) <= 2));
+}
+
+fn find(data: &[u8], pattern: &[u8]) -> Option<usize> {
+    data.windows(pattern.len()).position(|w| w == pattern)
+}
+
+fn ultra_hdr_xmp(gain_map_length: usize) -> Vec<u8> {
+    let mut xmp = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+    xmp.extend(format!(
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description xmlns:Container="http://ns.google.com/photos/1.0/container/" xmlns:Item="http://ns.google.com/photos/1.0/container/item/" xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/" hdrgm:Version="1.0"><Container:Directory><rdf:Seq><rdf:li rdf:parseType="Resource"><Container:Item Item:Semantic="Primary" Item:Mime="image/jpeg"/></rdf:li><rdf:li rdf:parseType="Resource"><Container:Item Item:Semantic="GainMap" Item:Mime="image/jpeg" Item:Length="{gain_map_length}"/></rdf:li></rdf:Seq></Container:Directory></rdf:Description></rdf:RDF></x:xmpmeta>"#
+    ).bytes());
+    xmp
+}
+
+/// Reads the (size, offset) pairs of a little endian MPF index as written by `multi_picture`, with the base of the
+/// offsets.
+fn mp_entries(image: &[u8]) -> (usize, Vec<(usize, usize)>) {
+    let base = find(image, b"MPF\0").unwrap() + 4;
+    let count = u32::from_le_bytes(image[base + 30..base + 34].try_into().unwrap());
+    let entries = (0..count as usize)
+        .map(|i| {
+            let entry = base + 50 + i * 16;
+            let read = |o: usize| u32::from_le_bytes(image[entry + o..entry + o + 4].try_into().unwrap()) as usize;
+            (read(4), read(8))
+        })
+        .collect();
+    (base, entries)
+}
+
+/// Encodes the sample resized to `width`, as a grayscale image for gain maps.
+fn sample_jpeg(width: u32, grayscale: bool) -> img_parts::jpeg::Jpeg {
+    let source = image::load_from_memory(&fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap()).unwrap();
+    let mut image = source.resize(width, width, image::imageops::FilterType::Triangle);
+    if grayscale {
+        image = image.grayscale();
+    }
+    let mut output = vec![];
+    image::codecs::jpeg::JpegEncoder::new_with_quality(&mut output, 98)
+        .encode_image(&image)
+        .unwrap();
+    img_parts::jpeg::Jpeg::from_bytes(output.into()).unwrap()
+}
+
+/// Encodes a gain map at a quarter of the resolution of the primary image of `multi_picture`.
+fn gain_map() -> Vec<u8> {
+    let mut gain_map = sample_jpeg(150, true);
+    let mut gain_map_xmp = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+    gain_map_xmp.extend_from_slice(br#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description xmlns:hdrgm="http://ns.adobe.com/hdr-gain-map/1.0/" hdrgm:Version="1.0" hdrgm:GainMapMax="2.0" hdrgm:HDRCapacityMax="2.0"/></rdf:RDF></x:xmpmeta>"#);
+    gain_map.segments_mut().insert(
+        1,
+        img_parts::jpeg::JpegSegment::new_with_contents(0xE1, gain_map_xmp.into()),
+    );
+    gain_map.encoder().bytes().to_vec()
+}
+
+/// Builds a multi-picture JPEG with a 600x400 primary image holding `xmp`, followed by the `secondary` ones.
+fn multi_picture(xmp: Option<Vec<u8>>, secondary: &[Vec<u8>]) -> Vec<u8> {
+    let count = secondary.len() as u8 + 1;
+    let mut mpf = b"MPF\0II*\0\x08\0\0\0\x03\0".to_vec();
+    mpf.extend([0x00, 0xB0, 0x07, 0x00, 0x04, 0x00, 0x00, 0x00, b'0', b'1', b'0', b'0']);
+    mpf.extend([0x01, 0xB0, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, count, 0x00, 0x00, 0x00]);
+    mpf.extend([
+        0x02,
+        0xB0,
+        0x07,
+        0x00,
+        count * 16,
+        0x00,
+        0x00,
+        0x00,
+        0x32,
+        0x00,
+        0x00,
+        0x00,
+    ]);
+    mpf.extend(vec![0; 4 + count as usize * 16]);
+    let mut primary = sample_jpeg(600, false);
+    let segments = xmp.map(|xmp| img_parts::jpeg::JpegSegment::new_with_contents(0xE1, xmp.into()));
+    primary.segments_mut().splice(
+        1..1,
+        segments
+            .into_iter()
+            .chain([img_parts::jpeg::JpegSegment::new_with_contents(0xE2, mpf.into())]),
+    );
+    let mut image = primary.encoder().bytes().to_vec();
+
+    let (base, _) = mp_entries(&image);
+    let primary_length = image.len();
+    image[base + 50 + 4..base + 50 + 8].copy_from_slice(&(primary_length as u32).to_le_bytes());
+    for (i, secondary) in secondary.iter().enumerate() {
+        let entry = base + 50 + (i + 1) * 16;
+        image[entry + 4..entry + 8].copy_from_slice(&(secondary.len() as u32).to_le_bytes());
+        let offset = (image.len() - base) as u32;
+        image[entry + 8..entry + 12].copy_from_slice(&offset.to_le_bytes());
+        image.extend(secondary);
+    }
+    image
+}
+
+/// Builds an Ultra HDR JPEG from the sample, with a grayscale gain map at a quarter of the resolution.
+fn ultra_hdr() -> Vec<u8> {
+    let gain_map = gain_map();
+    multi_picture(Some(ultra_hdr_xmp(gain_map.len())), &[gain_map])
+}
+
+#[test]
+fn ultra_hdr_gain_map() {
+    let in_file = ultra_hdr();
+    let mut pars = CSParameters::new();
+    pars.jpeg.quality = 60;
+    for keep_metadata in [false, true] {
+        pars.keep_metadata = keep_metadata;
+        let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+        assert!(output.len() < in_file.len());
+
+        let (base, entries) = mp_entries(&output);
+        let (primary_length, _) = entries[0];
+        let (gain_map_length, gain_map_offset) = entries[1];
+        assert_eq!(base + gain_map_offset, primary_length);
+        assert_eq!(primary_length + gain_map_length, output.len());
+        assert_eq!(image::load_from_memory(&output[..primary_length]).unwrap().width(), 600);
+        let gain_map = &output[primary_length..];
+        assert_eq!(image::load_from_memory(gain_map).unwrap().width(), 150);
+        assert!(find(gain_map, b"hdrgm:GainMapMax").is_some());
+        let length = format!("Item:Length=\"{gain_map_length}\"");
+        assert!(find(&output[..primary_length], length.as_bytes()).is_some());
+    }
+
+    pars.jpeg.keep_gain_map = false;
+    pars.keep_metadata = false;
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert!(find(&output, b"MPF\0").is_none());
+    assert!(find(&output, b"hdrgm").is_none());
+    assert!(find(&output[2..], &[0xFF, 0xD8]).is_none());
+    assert_eq!(image::load_from_memory(&output).unwrap().width(), 600);
+
+    //Only the container entries are removed from the XMP
+    pars.keep_metadata = true;
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    assert!(find(&output, b"MPF\0").is_none());
+    assert!(find(&output, b"hdrgm:Version").is_some());
+    assert!(find(&output, b"Container:Directory").is_none());
+    assert!(find(&output[2..], &[0xFF, 0xD8]).is_none());
+
+    let transformed = caesium::jpeg_transform(
+        &in_file,
+        &JpegTransform {
+            transformation: JpegTransformation::Rotate90,
+            crop: None,
+            perfect: false,
+        },
+    )
+    .unwrap();
+    assert!(find(&transformed, b"MPF\0").is_none());
+    assert!(find(&transformed, b"Container:Directory").is_none());
+    assert!(find(&transformed[2..], &[0xFF, 0xD8]).is_none());
+    assert_eq!(image::load_from_memory(&transformed).unwrap().height(), 600);
+
+    //A primary image size not ending at the EOI marker is not trusted
+    let mut broken = in_file;
+    let (base, _) = mp_entries(&broken);
+    broken[base + 50 + 4..base + 50 + 8].copy_from_slice(&100u32.to_le_bytes());
+    let output = caesium::compress_in_memory(broken, &pars).unwrap();
+    assert_eq!(image::load_from_memory(&output).unwrap().width(), 600);
+}
+
+#[test]
+fn multi_picture_previews() {
+    let preview = sample_jpeg(300, false).encoder().bytes().to_vec();
+    let gain_map = gain_map();
+    let in_file = multi_picture(Some(ultra_hdr_xmp(gain_map.len())), &[preview.clone(), gain_map]);
+    let plain = multi_picture(None, &[preview]);
+    let mut pars = CSParameters::new();
+    pars.jpeg.quality = 60;
+
+    //Only the gain map is left without the metadata
+    let output = caesium::compress_in_memory(in_file.clone(), &pars).unwrap();
+    let (base, entries) = mp_entries(&output);
+    assert_eq!(entries.len(), 2);
+    let (primary_length, _) = entries[0];
+    let (gain_map_length, gain_map_offset) = entries[1];
+    assert_eq!(base + gain_map_offset, primary_length);
+    assert_eq!(primary_length + gain_map_length, output.len());
+    assert!(find(&output[primary_length..], b"hdrgm:GainMapMax").is_some());
+
+    let output = caesium::compress_in_memory(plain.clone(), &pars).unwrap();
+    assert!(find(&output, b"MPF\0").is_none());
+    assert!(find(&output[2..], &[0xFF, 0xD8]).is_none());
+    assert_eq!(image::load_from_memory(&output).unwrap().width(), 600);
+
+    pars.keep_metadata = true;
+    for (in_file, count) in [(in_file, 3), (plain, 2)] {
+        let output = caesium::compress_in_memory(in_file, &pars).unwrap();
+        let (base, entries) = mp_entries(&output);
+        assert_eq!(entries.len(), count);
+        let (preview_length, preview_offset) = entries[1];
+        let preview = &output[base + preview_offset..base + preview_offset + preview_length];
+        assert_eq!(image::load_from_memory(preview).unwrap().width(), 300);
+        //The XMP only lists the gain map, which must not get the length of the preview
+        if let Some((gain_map_length, _)) = entries.get(2) {
+            let (primary_length, _) = entries[0];
+            let length = format!("Item:Length=\"{gain_map_length}\"");
+            assert!(find(&output[..primary_length], length.as_bytes()).is_some());
+        }
+    }
+}
+
+#[test]
+fn grayscale() {
+    let source = image::open("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    let mut gray_rgb = vec![];
+    image::DynamicImage::ImageRgb8(source.grayscale().to_rgb8())
+        .write_to(&mut Cursor::new(&mut gray_rgb), image::ImageFormat::Jpeg)
+        .unwrap();
+    assert_eq!(caesium::inspect(&gray_rgb).unwrap().color_model, ColorModel::YCbCr);
+
+    let mut pars = CSParameters::new();
+    let output = caesium::compress_in_memory(gray_rgb.clone(), &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Gray);
+
+    pars.detect_grayscale = false;
+    let output = caesium::compress_in_memory(gray_rgb, &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::YCbCr);
+
+    let color = std::fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap();
+    pars.detect_grayscale = true;
+    let output = caesium::compress_in_memory(color.clone(), &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::YCbCr);
+
+    pars.to_grayscale = true;
+    let output = caesium::compress_in_memory(color, &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Gray);
+
+    //Only exactly neutral pixels are gray by default
+    let mut near_gray = source.grayscale().to_rgb8();
+    near_gray.pixels_mut().for_each(|p| p[2] = p[2].saturating_add(3));
+    let mut near_gray_jpeg = vec![];
+    near_gray
+        .write_to(&mut Cursor::new(&mut near_gray_jpeg), image::ImageFormat::Jpeg)
+        .unwrap();
+    let mut pars = CSParameters::new();
+    let output = caesium::compress_in_memory(near_gray_jpeg.clone(), &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::YCbCr);
+
+    pars.grayscale_tolerance = 4;
+    let output = caesium::compress_in_memory(near_gray_jpeg, &pars).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Gray);
+}
@@ -1,7 +1,7 @@
 use crate::cleanup::remove_compressed_test_file;
 use bytes::Bytes;
-use caesium::parameters::CSParameters;
-use img_parts::png::Png as PartsPng;
+use caesium::parameters::{CSParameters, PNG_COLOR_CHUNKS};
+use img_parts::png::{Png as PartsPng, PngChunk};
 use img_parts::{ImageEXIF, ImageICC};
 use std::collections::HashMap;
 use std::fs;
@@ -136,6 +136,84 @@ fn png_lossless_with_metadata() {
     remove_compressed_test_file(output)
 }
 
+#[test]
+fn png_ancillary_chunks() {
+    let noise = image::RgbImage::from_fn(128, 128, |x, y| {
+        let n = ((y << 16 | x).wrapping_mul(2654435761) >> 13).wrapping_mul(2246822519);
+        image::Rgb([x as u8, y as u8, (n >> 24) as u8])
+    });
+    let mut source = vec![];
+    noise
+        .write_to(&mut std::io::Cursor::new(&mut source), image::ImageFormat::Png)
+        .unwrap();
+    let mut png = PartsPng::from_bytes(Bytes::from(source)).unwrap();
+    let chroma = [31270u32, 32900, 64000, 33000, 30000, 60000, 15000, 6000].map(u32::to_be_bytes);
+    for (i, (kind, contents)) in [
+        (*b"gAMA", Bytes::copy_from_slice(&45455u32.to_be_bytes())),
+        (*b"cHRM", Bytes::from(chroma.concat())),
+        (*b"tEXt", Bytes::from_static(b"Comment\0Caesium")),
+        (*b"sBIT", Bytes::from_static(&[5, 6, 5])),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        png.chunks_mut().insert(1 + i, PngChunk::new(kind, contents));
+    }
+    let mut source = vec![];
+    png.encoder().write_to(&mut source).unwrap();
+
+    let kinds = |png: Vec<u8>| -> Vec<[u8; 4]> {
+        PartsPng::from_bytes(Bytes::from(png))
+            .unwrap()
+            .chunks()
+            .iter()
+            .map(|c| c.kind())
+            .collect()
+    };
+    //The palette of the lossy output has the RGB channels of the source
+    let significant_bits = |png: Vec<u8>| {
+        PartsPng::from_bytes(Bytes::from(png))
+            .unwrap()
+            .chunk_by_type(*b"sBIT")
+            .map(|c| c.contents().to_vec())
+    };
+    let mut pars = CSParameters::new();
+    for optimize in [false, true] {
+        pars.png.optimize = optimize;
+        pars.keep_metadata = false;
+        let stripped = kinds(caesium::compress_in_memory(source.clone(), &pars).unwrap());
+        assert!(stripped.contains(b"gAMA") && stripped.contains(b"cHRM"), "{optimize}");
+        assert!(!stripped.contains(b"tEXt") && !stripped.contains(b"sBIT"), "{optimize}");
+
+        pars.keep_metadata = true;
+        let kept = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+        assert_eq!(significant_bits(kept.clone()), Some(vec![5, 6, 5]), "{optimize}");
+        let kept = kinds(kept);
+        assert!(kept.contains(b"gAMA") && kept.contains(b"tEXt"), "{optimize}");
+
+        pars.keep_metadata = false;
+        pars.png.keep_chunks = vec![];
+        let all_stripped = kinds(caesium::compress_in_memory(source.clone(), &pars).unwrap());
+        assert!(
+            !all_stripped.contains(b"gAMA") && !all_stripped.contains(b"cHRM"),
+            "{optimize}"
+        );
+        pars.png.keep_chunks = PNG_COLOR_CHUNKS.to_vec();
+
+        pars.width = 64;
+        let resized = kinds(caesium::compress_in_memory(source.clone(), &pars).unwrap());
+        assert!(resized.contains(b"gAMA") && resized.contains(b"cHRM"), "{optimize}");
+        assert!(!resized.contains(b"tEXt"), "{optimize}");
+        pars.keep_metadata = true;
+        let resized = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+        assert_eq!(significant_bits(resized.clone()), Some(vec![5, 6, 5]), "{optimize}");
+        let resized = kinds(resized);
+        assert!(resized.contains(b"gAMA") && resized.contains(b"tEXt"), "{optimize}");
+        pars.keep_metadata = false;
+        pars.width = 0;
+    }
+}
+
 fn png_metadata_is_equal(input: &Path, output: &Path) -> bool {
     let in_buf = fs::read(input).unwrap();
     let out_buf = fs::read(output).unwrap();
@@ -175,3 +253,84 @@ fn metadata_is_equal(input: &Path, output: &Path) -> bool {
 
     original_exif_map.eq(&compressed_exif_map)
 }
+
+fn tiff_with_metadata() -> Vec<u8> {
+    use tiff::encoder::{colortype::RGB8, Rational, TiffEncoder};
+    use tiff::tags::{ResolutionUnit, Tag};
+
+    let mut source = std::io::Cursor::new(vec![]);
+    let mut encoder = TiffEncoder::new(&mut source).unwrap();
+    let mut exif = encoder.extra_directory().unwrap();
+    exif.write_tag(Tag::Unknown(DATE_TIME_ORIGINAL_TAG), "2024:01:02 03:04:05")
+        .unwrap();
+    let exif = exif.finish_with_offsets().unwrap().offset;
+
+    let pixels = image::RgbImage::from_fn(32, 16, |x, y| image::Rgb([x as u8 * 8, y as u8 * 16, 100]));
+    let mut page = encoder.new_image::<RGB8>(32, 16).unwrap();
+    page.resolution(ResolutionUnit::Centimeter, Rational { n: 236, d: 2 });
+    let directory = page.encoder();
+    directory.write_tag(Tag::Software, "Scanner 1.0").unwrap();
+    directory.write_tag(Tag::Artist, "Caesium").unwrap();
+    directory.write_tag(Tag::DateTime, "2024:01:02 03:04:05").unwrap();
+    directory.write_tag(Tag::Orientation, 6u16).unwrap();
+    directory
+        .write_tag(Tag::Unknown(700), b"<x:xmpmeta/>".as_slice())
+        .unwrap();
+    directory
+        .write_tag(Tag::Unknown(33723), [0x1c02_0000u32, 0x0004_0000].as_slice())
+        .unwrap();
+    directory.write_tag(Tag::ExifDirectory, exif).unwrap();
+    page.write_data(pixels.as_raw()).unwrap();
+    source.into_inner()
+}
+
+const DATE_TIME_ORIGINAL_TAG: u16 = 36867;
+
+//The values of the metadata tags, then the ones in the EXIF directory
+fn tiff_metadata(data: &[u8]) -> (Vec<Option<String>>, Option<String>) {
+    use tiff::tags::Tag;
+
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(data)).unwrap();
+    let tags = [
+        Tag::Software,
+        Tag::Artist,
+        Tag::DateTime,
+        Tag::Unknown(700),
+        Tag::Unknown(33723),
+        Tag::Orientation,
+        Tag::XResolution,
+        Tag::ResolutionUnit,
+    ]
+    .map(|tag| decoder.find_tag(tag).unwrap().map(|v| format!("{v:?}")));
+    let exif = decoder.find_tag(Tag::ExifDirectory).unwrap().map(|pointer| {
+        let directory = decoder.read_directory(pointer.into_ifd_pointer().unwrap()).unwrap();
+        let date = decoder
+            .read_directory_tags(&directory)
+            .find_tag(Tag::Unknown(DATE_TIME_ORIGINAL_TAG))
+            .unwrap();
+        format!("{date:?}")
+    });
+    (tags.to_vec(), exif)
+}
+
+#[test]
+fn tiff_with_and_without_metadata() {
+    let source = tiff_with_metadata();
+    let (source_tags, source_exif) = tiff_metadata(&source);
+    assert!(source_tags.iter().all(Option::is_some) && source_exif.is_some());
+
+    let mut pars = CSParameters::new();
+    pars.keep_metadata = true;
+    let output = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+    assert_eq!(tiff_metadata(&output), (source_tags.clone(), source_exif));
+
+    pars.width = 16;
+    let output = caesium::compress_in_memory(source.clone(), &pars).unwrap();
+    assert_eq!(tiff_metadata(&output).0, source_tags);
+
+    pars.keep_metadata = false;
+    pars.keep_dpi = false;
+    let (tags, exif) = tiff_metadata(&caesium::compress_in_memory(source, &pars).unwrap());
+    assert!(tags[..6].iter().all(Option::is_none) && exif.is_none());
+    assert_ne!(tags[6..], source_tags[6..]);
+}
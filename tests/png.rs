@@ -1,5 +1,6 @@
 use crate::cleanup::remove_compressed_test_file;
-use caesium::parameters::CSParameters;
+use caesium::info::ColorModel;
+use caesium::parameters::{CSParameters, PngDeflater, PngFilter, PngInterlace};
 use std::{fs::File, sync::Once};
 
 mod cleanup;
@@ -149,3 +150,203 @@ fn downscale_to_size() {
     assert_eq!(image::image_dimensions(output).unwrap(), (150, 150));
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn grayscale() {
+    let source = image::open("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let mut gray_rgb = vec![];
+    image::DynamicImage::ImageRgb8(source.grayscale().to_rgb8())
+        .write_to(&mut std::io::Cursor::new(&mut gray_rgb), image::ImageFormat::Png)
+        .unwrap();
+
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    let output = caesium::compress_in_memory(gray_rgb.clone(), &params).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Gray);
+
+    params.detect_grayscale = false;
+    let output = caesium::compress_in_memory(gray_rgb, &params).unwrap();
+    assert_ne!(caesium::inspect(&output).unwrap().color_model, ColorModel::Gray);
+
+    params.to_grayscale = true;
+    let color = std::fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let output = caesium::compress_in_memory(color.clone(), &params).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::GrayAlpha);
+
+    params.png.optimize = false;
+    let output = image::load_from_memory(&caesium::compress_in_memory(color, &params).unwrap()).unwrap();
+    assert!(output.to_rgb8().pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+}
+
+#[test]
+fn lossy_smaller_than_lossless() {
+    let source = std::fs::read("tests/samples/uncompressed_드림캐쳐.png").unwrap();
+    let mut params = CSParameters::new();
+    let lossy = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(caesium::inspect(&lossy).unwrap().color_model, ColorModel::Indexed);
+
+    params.png.optimize = true;
+    let lossless = caesium::compress_in_memory(source, &params).unwrap();
+    assert!(lossy.len() < lossless.len());
+
+    //Quantization cannot improve an image with two colors
+    let two_colors = image::RgbImage::from_fn(300, 200, |x, _| {
+        if x < 150 {
+            image::Rgb([200, 30, 30])
+        } else {
+            image::Rgb([30, 30, 200])
+        }
+    });
+    let mut flat = vec![];
+    two_colors
+        .write_to(&mut std::io::Cursor::new(&mut flat), image::ImageFormat::Png)
+        .unwrap();
+    let lossless = caesium::compress_in_memory(flat.clone(), &params).unwrap();
+    params.png.optimize = false;
+    let lossy = caesium::compress_in_memory(flat, &params).unwrap();
+    assert!(lossy.len() <= lossless.len());
+    assert_eq!(image::load_from_memory(&lossy).unwrap().to_rgb8(), two_colors);
+}
+
+#[test]
+fn quantization_controls() {
+    //Noise, so that the lossless optimization is never smaller than the palette
+    let noise = image::RgbImage::from_fn(256, 128, |x, y| {
+        let mut n = (y << 16 | x).wrapping_mul(2654435761);
+        n ^= n >> 15;
+        n = n.wrapping_mul(2246822519) >> 24;
+        image::Rgb([x as u8, (y * 2) as u8, n as u8])
+    });
+    let mut source = vec![];
+    noise
+        .write_to(&mut std::io::Cursor::new(&mut source), image::ImageFormat::Png)
+        .unwrap();
+
+    let mut params = CSParameters::new();
+    params.png.max_colors = 16;
+    params.png.dithering_level = 0.0;
+    params.png.speed = 10;
+    params.png.posterization = 2;
+    let compressed = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    let colors: std::collections::HashSet<_> = image::load_from_memory(&compressed)
+        .unwrap()
+        .to_rgb8()
+        .pixels()
+        .copied()
+        .collect();
+    assert!(colors.len() <= 16);
+
+    //Two colors cannot reach the minimum quality, the lossless optimization is used instead
+    params.png.max_colors = 2;
+    params.png.quality = 100;
+    params.png.min_quality = 90;
+    let fallback = caesium::compress_in_memory(source, &params).unwrap();
+    assert_eq!(image::load_from_memory(&fallback).unwrap().to_rgb8(), noise);
+
+    params.png.max_colors = 1;
+    let result = caesium::compress(
+        String::from("tests/samples/uncompressed_드림캐쳐.png"),
+        String::from("tests/samples/output/invalid_max_colors.png"),
+        &params,
+    );
+    assert_eq!(result.unwrap_err().code, 10012);
+}
+
+#[test]
+fn high_bit_depth() {
+    let gradient = image::ImageBuffer::from_fn(128, 64, |x, y| {
+        image::Rgb([x as u16 * 500 + 3, y as u16 * 1000 + 7, 40000u16])
+    });
+    let mut source = vec![];
+    image::DynamicImage::ImageRgb16(gradient.clone())
+        .write_to(&mut std::io::Cursor::new(&mut source), image::ImageFormat::Png)
+        .unwrap();
+    let bit_depth = |png: &[u8]| png[24];
+
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    let (kept, warnings) = caesium::compress_in_memory_with_warnings(source.clone(), &params).unwrap();
+    assert_eq!(bit_depth(&kept), 16);
+    assert!(warnings.is_empty());
+    assert_eq!(image::load_from_memory(&kept).unwrap().to_rgb16(), gradient);
+
+    params.width = 64;
+    let resized = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(bit_depth(&resized), 16);
+
+    params.width = 0;
+    params.png.keep_16_bit = false;
+    let (rounded, warnings) = caesium::compress_in_memory_with_warnings(source.clone(), &params).unwrap();
+    assert_eq!(bit_depth(&rounded), 8);
+    assert_eq!(warnings[0].code, 20217);
+
+    params.png.dither_16_bit = true;
+    let dithered = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    let dithered = image::load_from_memory(&dithered).unwrap().to_rgb8();
+    assert_ne!(image::load_from_memory(&rounded).unwrap().to_rgb8(), dithered);
+    let error: f64 = gradient
+        .as_raw()
+        .iter()
+        .zip(dithered.as_raw())
+        .map(|(original, reduced)| *original as f64 / 257.0 - *reduced as f64)
+        .sum();
+    assert!((error / dithered.as_raw().len() as f64).abs() < 0.1);
+
+    params.png.optimize = false;
+    let (_, warnings) = caesium::compress_in_memory_with_warnings(source, &params).unwrap();
+    assert_eq!(warnings[0].code, 20217);
+}
+
+#[test]
+fn oxipng_options() {
+    let image = image::RgbImage::from_fn(200, 100, |x, y| image::Rgb([x as u8, y as u8, (x ^ y) as u8]));
+    let mut source = vec![];
+    image
+        .write_to(&mut std::io::Cursor::new(&mut source), image::ImageFormat::Png)
+        .unwrap();
+    let interlace = |png: &[u8]| png[28];
+
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    params.png.interlace = PngInterlace::Adam7;
+    params.png.filters = Some(vec![PngFilter::Paeth]);
+    params.png.deflater = Some(PngDeflater::Libdeflater { level: 12 });
+    params.png.timeout = Some(std::time::Duration::from_secs(60));
+    let interlaced = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(interlace(&interlaced), 1);
+    assert_eq!(image::load_from_memory(&interlaced).unwrap().to_rgb8(), image);
+
+    params.png.interlace = PngInterlace::Keep;
+    let kept = caesium::compress_in_memory(interlaced.clone(), &params).unwrap();
+    assert_eq!(interlace(&kept), 1);
+
+    params.png.interlace = PngInterlace::None;
+    let best = caesium::compress_in_memory(interlaced, &params).unwrap();
+    assert_eq!(interlace(&best), 0);
+
+    params.png.deflater = Some(PngDeflater::Libdeflater { level: 0 });
+    let stored = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert!(stored.len() > best.len());
+
+    params.png.deflater = Some(PngDeflater::Zopfli { iterations: 1 });
+    let zopfli = caesium::compress_in_memory(source, &params).unwrap();
+    assert_eq!(image::load_from_memory(&zopfli).unwrap().to_rgb8(), image);
+
+    let mut params = CSParameters::new();
+    params.png.optimize = true;
+    params.png.optimization_level = 0;
+    params.keep_metadata = true;
+    params.png.strip_chunks = Some(vec![*b"iCCP"]);
+    let stripped = caesium::compress_in_memory(std::fs::read("tests/samples/metadata.png").unwrap(), &params).unwrap();
+    let stripped = img_parts::png::Png::from_bytes(stripped.into()).unwrap();
+    assert!(stripped.chunk_by_type(*b"iCCP").is_none());
+    assert!(stripped.chunk_by_type(*b"tIME").is_some());
+
+    params.png.deflater = Some(PngDeflater::Libdeflater { level: 13 });
+    let result = caesium::compress(
+        String::from("tests/samples/metadata.png"),
+        String::from("tests/samples/output/invalid_deflate_level.png"),
+        &params,
+    );
+    assert_eq!(result.unwrap_err().code, 10017);
+}
@@ -1,5 +1,7 @@
 use crate::cleanup::remove_compressed_test_file;
+use caesium::info::ColorModel;
 use caesium::parameters::{CSParameters, TiffCompression, TiffDeflateLevel};
+use caesium::SupportedFileTypes;
 use std::{fs::File, sync::Once};
 mod cleanup;
 
@@ -175,3 +177,235 @@ fn prevent_panic() {
     )
     .is_err());
 }
+
+#[test]
+fn grayscale() {
+    let source = image::open("tests/samples/rgb8.tif").unwrap();
+    let mut gray_rgb = vec![];
+    image::DynamicImage::ImageRgb8(source.grayscale().to_rgb8())
+        .write_to(&mut std::io::Cursor::new(&mut gray_rgb), image::ImageFormat::Tiff)
+        .unwrap();
+
+    let mut params = CSParameters::new();
+    let output = caesium::compress_in_memory(gray_rgb.clone(), &params).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Gray);
+    assert!(
+        output.len()
+            < caesium::compress_in_memory(std::fs::read("tests/samples/rgb8.tif").unwrap(), &params)
+                .unwrap()
+                .len()
+    );
+
+    params.detect_grayscale = false;
+    let output = caesium::compress_in_memory(gray_rgb, &params).unwrap();
+    assert_eq!(caesium::inspect(&output).unwrap().color_model, ColorModel::Rgb);
+
+    params.to_grayscale = true;
+    let output = caesium::compress_in_memory(std::fs::read("tests/samples/rgba8.tif").unwrap(), &params).unwrap();
+    let output = image::load_from_memory(&output).unwrap();
+    assert!(output.to_rgba8().pixels().all(|p| p[0] == p[1] && p[1] == p[2]));
+}
+
+fn single_page_tiff<C: tiff::encoder::colortype::ColorType>(data: &[C::Inner], alpha: bool) -> Vec<u8>
+where
+    [C::Inner]: tiff::encoder::TiffValue,
+{
+    let mut output = std::io::Cursor::new(vec![]);
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut output).unwrap();
+    let mut image = encoder.new_image::<C>(16, 8).unwrap();
+    if alpha {
+        image
+            .extra_samples(&[tiff::tags::ExtraSamples::UnassociatedAlpha])
+            .unwrap();
+    }
+    image.write_data(data).unwrap();
+    output.into_inner()
+}
+
+//The color type and the samples, which have no common type to compare with
+fn decode_single_page(data: &[u8]) -> (tiff::ColorType, String) {
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(data)).unwrap();
+    (
+        decoder.colortype().unwrap(),
+        format!("{:?}", decoder.read_image().unwrap()),
+    )
+}
+
+#[test]
+fn color_types() {
+    use tiff::encoder::colortype::{Gray16, Gray8, RGB32Float, CMYK8, RGB16, RGBA16};
+
+    let samples = |channels: usize| (0..16 * 8 * channels).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+    let samples16 = |channels: usize| {
+        samples(channels)
+            .iter()
+            .map(|&v| v as u16 * 257 + 3)
+            .collect::<Vec<u16>>()
+    };
+    let samples32f = samples(3).iter().map(|&v| v as f32 / 255.0).collect::<Vec<f32>>();
+    let sources = [
+        single_page_tiff::<Gray8>(&samples(1), false),
+        single_page_tiff::<Gray16>(&samples16(1), false),
+        single_page_tiff::<Gray8>(&samples(2), true),
+        single_page_tiff::<Gray16>(&samples16(2), true),
+        single_page_tiff::<RGB16>(&samples16(3), false),
+        single_page_tiff::<RGBA16>(&samples16(4), false),
+        single_page_tiff::<RGB32Float>(&samples32f, false),
+        single_page_tiff::<CMYK8>(&samples(4), false),
+    ];
+
+    let mut params = CSParameters::new();
+    params.tiff.algorithm = TiffCompression::Deflate;
+    for source in sources {
+        let output = caesium::compress_in_memory(source.clone(), &params).unwrap();
+        assert_eq!(decode_single_page(&output), decode_single_page(&source));
+    }
+}
+
+//Three pages: a 64x48 red RGB one, a 32x32 gray one and a 64x48 blue RGB one
+fn multi_page_tiff() -> Vec<u8> {
+    let mut output = std::io::Cursor::new(vec![]);
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut output).unwrap();
+    let red = image::RgbImage::from_fn(64, 48, |x, _| image::Rgb([200, x as u8, 0]));
+    encoder
+        .write_image::<tiff::encoder::colortype::RGB8>(64, 48, red.as_raw())
+        .unwrap();
+    let gray = image::GrayImage::from_fn(32, 32, |x, y| image::Luma([(x * y) as u8]));
+    encoder
+        .write_image::<tiff::encoder::colortype::Gray8>(32, 32, gray.as_raw())
+        .unwrap();
+    let blue = image::RgbImage::from_fn(64, 48, |_, y| image::Rgb([0, y as u8, 200]));
+    encoder
+        .write_image::<tiff::encoder::colortype::RGB8>(64, 48, blue.as_raw())
+        .unwrap();
+    output.into_inner()
+}
+
+fn page_dimensions(data: &[u8]) -> Vec<(u32, u32)> {
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(data)).unwrap();
+    let mut dimensions = vec![decoder.dimensions().unwrap()];
+    while decoder.more_images() {
+        decoder.next_image().unwrap();
+        dimensions.push(decoder.dimensions().unwrap());
+    }
+    dimensions
+}
+
+#[test]
+fn multi_page() {
+    let source = multi_page_tiff();
+    let mut params = CSParameters::new();
+    let output = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(page_dimensions(&output), vec![(64, 48), (32, 32), (64, 48)]);
+    let first_page = image::load_from_memory(&output).unwrap().to_rgb8();
+    assert_eq!(first_page.get_pixel(10, 0), &image::Rgb([200, 10, 0]));
+
+    params.tiff.pages = Some((1, u32::MAX));
+    let output = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(page_dimensions(&output), vec![(32, 32), (64, 48)]);
+
+    params.tiff.pages = Some((1, 1));
+    params.width = 16;
+    let output = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(page_dimensions(&output), vec![(16, 16)]);
+
+    params.tiff.pages = Some((3, 4));
+    assert_eq!(caesium::compress_in_memory(source, &params).err().unwrap().code, 20508);
+
+    params.tiff.pages = Some((1, 0));
+    let output = "tests/samples/output/pages.tif";
+    let result = caesium::compress(String::from("tests/samples/rgb8.tif"), String::from(output), &params);
+    assert_eq!(result.err().unwrap().code, 10028);
+}
+
+//Two 16x16 gray pages, scanned at 300 and 150 dpi
+fn mixed_resolution_tiff() -> Vec<u8> {
+    let mut output = std::io::Cursor::new(vec![]);
+    let mut encoder = tiff::encoder::TiffEncoder::new(&mut output).unwrap();
+    for dpi in [300, 150] {
+        let mut page = encoder.new_image::<tiff::encoder::colortype::Gray8>(16, 16).unwrap();
+        page.resolution(
+            tiff::tags::ResolutionUnit::Inch,
+            tiff::encoder::Rational { n: dpi, d: 1 },
+        );
+        page.write_data(&[128; 16 * 16]).unwrap();
+    }
+    output.into_inner()
+}
+
+fn page_resolutions(data: &[u8]) -> Vec<u32> {
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(data)).unwrap();
+    let mut resolutions = vec![];
+    loop {
+        match decoder.get_tag(tiff::tags::Tag::XResolution).unwrap() {
+            tiff::decoder::ifd::Value::Rational(n, d) => resolutions.push(n / d),
+            value => panic!("Resolution is not a rational ({value:?})"),
+        }
+        if !decoder.more_images() {
+            return resolutions;
+        }
+        decoder.next_image().unwrap();
+    }
+}
+
+#[test]
+fn multi_page_resolution() {
+    let source = mixed_resolution_tiff();
+    let mut params = CSParameters::new();
+    let output = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(page_resolutions(&output), vec![300, 150]);
+
+    params.tiff.pages = Some((1, 1));
+    let output = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert_eq!(page_resolutions(&output), vec![150]);
+
+    params.tiff.pages = None;
+    params.dpi = Some(caesium::parameters::Dpi { x: 600, y: 600 });
+    let output = caesium::compress_in_memory(source, &params).unwrap();
+    assert_eq!(page_resolutions(&output), vec![600, 600]);
+}
+
+#[test]
+fn split_pages() {
+    let source = multi_page_tiff();
+    let params = CSParameters::new();
+    let pages = caesium::split_pages_in_memory(source.clone(), &params, SupportedFileTypes::Tiff).unwrap();
+    assert_eq!(
+        pages.iter().map(|p| page_dimensions(p)).collect::<Vec<_>>(),
+        vec![vec![(64, 48)], vec![(32, 32)], vec![(64, 48)]]
+    );
+
+    let pages = caesium::split_pages_in_memory(source, &params, SupportedFileTypes::Png).unwrap();
+    assert_eq!(pages.len(), 3);
+    assert!(pages.iter().all(|p| infer::get(p).unwrap().mime_type() == "image/png"));
+    assert_eq!(image::load_from_memory(&pages[1]).unwrap().width(), 32);
+
+    let result = caesium::split_pages_in_memory(
+        std::fs::read("tests/samples/uncompressed_드림캐쳐.jpg").unwrap(),
+        &params,
+        SupportedFileTypes::Png,
+    );
+    assert_eq!(result.err().unwrap().code, 10414);
+}
+
+#[test]
+fn pages_to_animation() {
+    let source = multi_page_tiff();
+    let params = CSParameters::new();
+    let animation =
+        caesium::convert_pages_to_animation_in_memory(source.clone(), &params, SupportedFileTypes::WebP, 500).unwrap();
+    let frames = webp::AnimDecoder::new(&animation).decode().unwrap();
+    assert_eq!(
+        frames.into_iter().map(|f| f.get_time_ms()).collect::<Vec<_>>(),
+        vec![500, 1000, 1500]
+    );
+    assert_eq!(frames.get_frame(0).unwrap().width(), 64);
+
+    let animation =
+        caesium::convert_pages_to_animation_in_memory(source.clone(), &params, SupportedFileTypes::Gif, 500).unwrap();
+    let decoder = image::codecs::gif::GifDecoder::new(std::io::Cursor::new(animation)).unwrap();
+    assert_eq!(image::AnimationDecoder::into_frames(decoder).count(), 3);
+
+    let result = caesium::convert_pages_to_animation_in_memory(source, &params, SupportedFileTypes::Jpeg, 500);
+    assert_eq!(result.err().unwrap().code, 10415);
+}
@@ -1,5 +1,5 @@
 use crate::cleanup::remove_compressed_test_file;
-use caesium::parameters::CSParameters;
+use caesium::parameters::{CSParameters, WebPAlphaFiltering, WebPPreset};
 use std::{fs::File, sync::Once};
 
 mod cleanup;
@@ -156,3 +156,129 @@ fn compress_animated_80() {
     assert_eq!(infer::get_from_path(output).unwrap().unwrap().mime_type(), "image/webp");
     remove_compressed_test_file(output)
 }
+
+#[test]
+fn encoder_options() {
+    //Fully transparent pixels still holding colors
+    let image = image::RgbaImage::from_fn(64, 64, |x, y| {
+        let alpha = if x < 32 { 0 } else { 255 };
+        image::Rgba([(x * 4) as u8, (y * 4) as u8, 200, alpha])
+    });
+    let mut config = webp::WebPConfig::new().unwrap();
+    config.lossless = 1;
+    config.exact = 1;
+    let source = webp::Encoder::from_rgba(image.as_raw(), 64, 64)
+        .encode_advanced(&config)
+        .unwrap()
+        .to_vec();
+
+    let mut params = CSParameters::new();
+    params.webp.lossless = true;
+    params.webp.method = 6;
+    params.webp.exact = true;
+    let exact = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    let decoded = webp::Decoder::new(&exact).decode().unwrap();
+    assert_eq!(&*decoded, image.as_raw().as_slice());
+
+    params.webp.exact = false;
+    params.webp.near_lossless = 0;
+    let near_lossless = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    let decoded = webp::Decoder::new(&near_lossless).decode().unwrap();
+    assert_ne!(&*decoded, image.as_raw().as_slice());
+
+    params.webp.lossless = false;
+    params.webp.near_lossless = 100;
+    params.webp.preset = WebPPreset::Drawing;
+    params.webp.sns_strength = Some(0);
+    params.webp.filter_strength = Some(0);
+    params.webp.filter_sharpness = Some(7);
+    params.webp.segments = 1;
+    params.webp.pass = 10;
+    params.webp.alpha_quality = 50;
+    params.webp.alpha_filtering = WebPAlphaFiltering::Best;
+    params.webp.use_sharp_yuv = true;
+    params.webp.target_psnr = 40.0;
+    let lossy = caesium::compress_in_memory(source, &params).unwrap();
+    assert_eq!(infer::get(&lossy).unwrap().mime_type(), "image/webp");
+
+    params.webp.method = 7;
+    let result = caesium::compress(
+        String::from("tests/samples/uncompressed_家.webp"),
+        String::from("tests/samples/output/invalid_method.webp"),
+        &params,
+    );
+    assert_eq!(result.unwrap_err().code, 10019);
+}
+
+//Offset, size and duration of the ANMF chunks
+fn animation_frames(data: &[u8]) -> Vec<(u32, u32, u32, u32, u32)> {
+    let mut frames = vec![];
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let length = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if &data[offset..offset + 4] == b"ANMF" {
+            let chunk = &data[offset + 8..];
+            let read = |i: usize| u32::from_le_bytes([chunk[i], chunk[i + 1], chunk[i + 2], 0]);
+            frames.push((read(0) * 2, read(3) * 2, read(6) + 1, read(9) + 1, read(12)));
+        }
+        offset += 8 + length + (length & 1);
+    }
+    frames
+}
+
+#[test]
+fn animation() {
+    let source = std::fs::read("tests/samples/uncompressed_animated.webp").unwrap();
+    let source_frames = webp::AnimDecoder::new(&source).decode().unwrap();
+    let timestamps = |anim: &webp::DecodeAnimImage| anim.into_iter().map(|f| f.get_time_ms()).collect::<Vec<_>>();
+
+    let mut params = CSParameters::new();
+    let compressed = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    let frames = webp::AnimDecoder::new(&compressed).decode().unwrap();
+    assert_eq!(frames.len(), source_frames.len());
+    assert_eq!(timestamps(&frames), timestamps(&source_frames));
+    assert_eq!(frames.loop_count, source_frames.loop_count);
+    assert_eq!(frames.bg_color, source_frames.bg_color);
+    let chunks = animation_frames(&compressed);
+    assert_eq!(
+        chunks.iter().map(|c| c.4).collect::<Vec<_>>(),
+        animation_frames(&source).iter().map(|c| c.4).collect::<Vec<_>>()
+    );
+    //Only the changed part of the canvas is stored
+    assert!(chunks.iter().skip(1).all(|c| c.2 < 380 || c.3 < 400));
+
+    params.webp.kmax = Some(1);
+    let compressed = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    assert!(animation_frames(&compressed)
+        .iter()
+        .all(|c| (c.0, c.1, c.2, c.3) == (0, 0, 380, 400)));
+
+    params.webp.kmax = None;
+    params.webp.lossless = true;
+    let compressed = caesium::compress_in_memory(source.clone(), &params).unwrap();
+    let frames = webp::AnimDecoder::new(&compressed).decode().unwrap();
+    assert!(source_frames
+        .into_iter()
+        .zip(frames.into_iter())
+        .all(|(s, f)| s.get_image() == f.get_image()));
+
+    params.webp.allow_mixed = true;
+    params.width = 100;
+    let compressed = caesium::compress_in_memory(source, &params).unwrap();
+    let frames = webp::AnimDecoder::new(&compressed).decode().unwrap();
+    //Frames left identical by the downscale are merged
+    assert_eq!(timestamps(&frames).last(), timestamps(&source_frames).last());
+    let image = image::load_from_memory(&compressed).unwrap();
+    assert_eq!((image.width(), image.height()), (100, 105));
+}
+
+#[test]
+fn lossless_downscale() {
+    let source = std::fs::read("tests/samples/uncompressed_家.webp").unwrap();
+    let mut params = CSParameters::new();
+    params.webp.lossless = true;
+    params.width = 100;
+    let compressed = caesium::compress_in_memory(source, &params).unwrap();
+    assert_eq!(&compressed[12..16], b"VP8L");
+    assert_eq!(image::load_from_memory(&compressed).unwrap().width(), 100);
+}